@@ -0,0 +1,56 @@
+//! Regression benchmark for the growth of the program break.
+//!
+//! Free blocks which are only slightly larger than a request have to be reused
+//! instead of extending the data segment.
+#![feature(test)]
+
+extern crate test;
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+
+use collam::alloc::Collam;
+use test::Bencher;
+
+static COLLAM: Collam = Collam::new();
+
+/// Number of blocks freed before the benchmark, every other block stays allocated.
+const BLOCKS: usize = 64;
+
+#[bench]
+fn bench_reuse_slightly_larger_blocks(b: &mut Bencher) {
+    let mut options = COLLAM.options();
+    // Small requests must be served by the free list
+    options.slab = false;
+    COLLAM.configure(options);
+
+    unsafe {
+        let large = Layout::from_size_align_unchecked(96, 16);
+        let small = Layout::from_size_align_unchecked(80, 16);
+        let mut blocks = [null_mut(); BLOCKS];
+        for block in blocks.iter_mut() {
+            *block = COLLAM.alloc(large);
+            assert!(!block.is_null());
+        }
+        for block in blocks.iter().step_by(2) {
+            COLLAM.dealloc(*block, large);
+        }
+
+        let mut ptrs = [null_mut(); BLOCKS / 2];
+        b.iter(|| {
+            // The harness may move the break between iterations
+            let brk = libc::sbrk(0);
+            for ptr in ptrs.iter_mut() {
+                *ptr = COLLAM.alloc(small);
+            }
+            assert_eq!(libc::sbrk(0), brk, "free blocks have not been reused");
+            for ptr in ptrs.iter() {
+                COLLAM.dealloc(*ptr, small);
+            }
+        });
+
+        for block in blocks.iter().skip(1).step_by(2) {
+            COLLAM.dealloc(*block, large);
+        }
+    }
+}
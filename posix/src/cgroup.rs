@@ -0,0 +1,73 @@
+use collam::alloc::options;
+
+/// Mount point of the unified cgroup hierarchy.
+const CGROUP_ROOT: &[u8] = b"/sys/fs/cgroup";
+/// Maximum length of a path below `CGROUP_ROOT` including the NUL terminator.
+const PATH_MAX_LEN: usize = 512;
+
+/// Memory limits of the cgroup v2 the process belongs to, `None` if unlimited.
+pub struct MemoryLimits {
+    /// Hard limit from `memory.max`.
+    pub max: Option<usize>,
+    /// Throttling limit from `memory.high`.
+    pub high: Option<usize>,
+}
+
+/// Reads the memory limits of the current cgroup without allocating.
+/// Returns `None` if the process is not part of a cgroup v2 hierarchy.
+pub unsafe fn memory_limits() -> Option<MemoryLimits> {
+    // Hybrid setups list the v1 controllers as well
+    let mut buf = [0_u8; 4096];
+    let content = read_file(b"/proc/self/cgroup\0", &mut buf)?;
+    // The entry of the unified hierarchy has the form `0::<path>`
+    let group = content
+        .split(|c| *c == b'\n')
+        .find(|line| line.starts_with(b"0::"))
+        .map(|line| &line[3..])?;
+
+    let mut path = [0_u8; PATH_MAX_LEN];
+    let len = CGROUP_ROOT.len() + group.len();
+    // Leave room for the file name
+    if len + 16 > PATH_MAX_LEN {
+        return None;
+    }
+    path[..CGROUP_ROOT.len()].copy_from_slice(CGROUP_ROOT);
+    path[CGROUP_ROOT.len()..len].copy_from_slice(group);
+    Some(MemoryLimits {
+        max: read_limit(&mut path, len, b"/memory.max\0"),
+        high: read_limit(&mut path, len, b"/memory.high\0"),
+    })
+}
+
+/// Reads a limit file of the cgroup directory in `path[..len]`, `max` means unlimited.
+unsafe fn read_limit(path: &mut [u8; PATH_MAX_LEN], len: usize, name: &[u8]) -> Option<usize> {
+    path[len..len + name.len()].copy_from_slice(name);
+    let mut buf = [0_u8; 32];
+    match read_file(&path[..len + name.len()], &mut buf)? {
+        b"max" => None,
+        value => options::parse_size(value),
+    }
+}
+
+/// Reads a small file into `buf`, `path` must be NUL terminated.
+/// Returns its content without trailing newline.
+unsafe fn read_file<'a>(path: &[u8], buf: &'a mut [u8]) -> Option<&'a [u8]> {
+    debug_assert_eq!(path.last(), Some(&0));
+    let fd = libc::open(
+        path.as_ptr().cast::<libc::c_char>(),
+        libc::O_RDONLY | libc::O_CLOEXEC,
+    );
+    if fd < 0 {
+        return None;
+    }
+    let len = libc::read(fd, buf.as_mut_ptr().cast::<libc::c_void>(), buf.len());
+    libc::close(fd);
+    if len <= 0 {
+        return None;
+    }
+    let mut content = &buf[..len as usize];
+    if let Some((b'\n', rest)) = content.split_last() {
+        content = rest;
+    }
+    Some(content)
+}
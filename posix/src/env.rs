@@ -0,0 +1,15 @@
+use core::slice;
+
+/// Returns the value of the given environment variable without allocating.
+/// `name` must be NUL terminated.
+pub unsafe fn getenv(name: &[u8]) -> Option<&'static [u8]> {
+    debug_assert_eq!(name.last(), Some(&0));
+    let value = libc::getenv(name.as_ptr().cast::<libc::c_char>());
+    if value.is_null() {
+        return None;
+    }
+    Some(slice::from_raw_parts(
+        value.cast::<u8>(),
+        libc::strlen(value),
+    ))
+}
@@ -7,62 +7,152 @@
 extern crate collam;
 
 use core::alloc::{GlobalAlloc, Layout};
-use core::intrinsics::{abort, unlikely};
-use core::ptr::{null_mut, Unique};
-use core::{ffi::c_void, panic};
+use core::intrinsics::likely;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use core::{ffi::c_void, mem};
 
-use collam::alloc::{block::BlockPtr, Collam};
+use collam::alloc::Collam;
+use collam::trace::TraceOp;
 use collam::MIN_ALIGN;
 
+mod cgroup;
+mod env;
+mod log;
+mod options;
+mod profile;
+mod stats;
+mod trace;
+
 static COLLAM: Collam = Collam::new();
 
+const INIT_PENDING: u8 = 0;
+const INIT_RUNNING: u8 = 1;
+const INIT_DONE: u8 = 2;
+
+static INIT_STATE: AtomicU8 = AtomicU8::new(INIT_PENDING);
+
+/// Reads the runtime configuration once on the first call into the library.
+/// Calls made while initialization is running are served with the default configuration.
+#[inline]
+fn init() {
+    if likely(INIT_STATE.load(Ordering::Acquire) == INIT_DONE) {
+        return;
+    }
+    if INIT_STATE
+        .compare_exchange(
+            INIT_PENDING,
+            INIT_RUNNING,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+    {
+        // SAFETY: we are the only thread running the initialization
+        unsafe {
+            let config = options::Config::load(COLLAM.options());
+            COLLAM.configure(config.options);
+            log::init(&config);
+            trace::init(&config);
+            profile::init(&COLLAM, &config);
+            stats::init(&config);
+            let registered =
+                libc::pthread_atfork(Some(prepare_fork), Some(release_fork), Some(release_fork));
+            if registered != 0 {
+                error!(Alloc, "unable to register fork handlers");
+            }
+        }
+        INIT_STATE.store(INIT_DONE, Ordering::Release);
+    }
+}
+
+/// Acquires all locks before `fork()`, so the child doesn't inherit a lock
+/// which is held by another thread and would never be released.
+unsafe extern "C" fn prepare_fork() {
+    COLLAM.lock_all();
+}
+
+/// Releases the locks in the parent and in the child after `fork()`.
+unsafe extern "C" fn release_fork() {
+    COLLAM.unlock_all();
+}
+
+/// Runs when the library is unloaded or the process exits.
+extern "C" fn fini() {
+    profile::dump();
+    stats::dump(&COLLAM);
+    if COLLAM.options().redzone != 0 {
+        COLLAM.check_heap();
+    }
+}
+
+#[used]
+#[link_section = ".fini_array"]
+static FINI: extern "C" fn() = fini;
+
 #[no_mangle]
 pub unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    init();
     let layout = Layout::from_size_align_unchecked(size, MIN_ALIGN);
-    COLLAM.alloc(layout).cast::<c_void>()
+    let ptr = COLLAM.alloc(layout).cast::<c_void>();
+    trace::record(TraceOp::Malloc, size, ptr, null_mut());
+    ptr
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn calloc(nobj: usize, size: usize) -> *mut c_void {
+    init();
     let total_size = match nobj.checked_mul(size) {
         Some(x) => x,
         None => {
-            eprintln!(
-                "integer overflow detected for calloc(nobj={}, size={})",
-                nobj, size
+            error!(
+                Alloc,
+                "integer overflow detected for calloc(nobj={}, size={})", nobj, size
             );
             return null_mut();
         }
     };
     let layout = Layout::from_size_align_unchecked(total_size, MIN_ALIGN);
-    COLLAM.alloc_zeroed(layout).cast::<c_void>()
+    let ptr = COLLAM.alloc_zeroed(layout).cast::<c_void>();
+    trace::record(TraceOp::Calloc, total_size, ptr, null_mut());
+    ptr
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn realloc(p: *mut c_void, size: usize) -> *mut c_void {
-    if p.is_null() {
+    init();
+    // The old memory may be reused by another thread before the call returns
+    let seq = trace::next_seq();
+    let new_ptr = if p.is_null() {
         // If ptr is NULL, then the call is equivalent to malloc(size), for all values of size.
         let layout = Layout::from_size_align_unchecked(size, MIN_ALIGN);
-        return COLLAM.alloc(layout).cast::<c_void>();
-    }
-
-    let p = p.cast::<u8>();
-    let layout = Layout::from_size_align_unchecked(0, MIN_ALIGN);
-
-    if size == 0 {
+        COLLAM.alloc(layout).cast::<c_void>()
+    } else if size == 0 {
         // If size is equal to zero, and ptr is not NULL,
         // then the call is equivalent to free(ptr).
-        COLLAM.dealloc(p, layout);
+        let layout = Layout::from_size_align_unchecked(0, MIN_ALIGN);
+        COLLAM.dealloc(p.cast::<u8>(), layout);
         null_mut()
     } else {
-        COLLAM.realloc(p, layout, size).cast::<c_void>()
-    }
+        let layout = Layout::from_size_align_unchecked(0, MIN_ALIGN);
+        COLLAM
+            .realloc(p.cast::<u8>(), layout, size)
+            .cast::<c_void>()
+    };
+    trace::record_seq(seq, TraceOp::Realloc, size, new_ptr, p);
+    new_ptr
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+    init();
+    // Recorded before the memory can be reused by another thread
+    trace::record(TraceOp::Free, 0, ptr, null_mut());
     let layout = Layout::from_size_align_unchecked(0, MIN_ALIGN);
-    COLLAM.dealloc(ptr.cast::<u8>(), layout)
+    COLLAM.dealloc(ptr.cast::<u8>(), layout);
 }
 
 #[no_mangle]
@@ -70,38 +160,63 @@ pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> usize {
     if ptr.is_null() {
         return 0;
     }
+    COLLAM.usable_size(ptr.cast::<u8>())
+}
 
-    // Its safe to use Unique_unchecked since we already checked for null pointers.
-    let block = match BlockPtr::from_mem_region(Unique::new_unchecked(ptr.cast::<u8>())) {
-        Some(b) => b,
-        None => return 0,
-    };
-    if unlikely(!block.as_ref().verify()) {
-        eprintln!(
-            "malloc_usable_size(): Unable to verify {} at {:p}",
-            block.as_ref(),
-            block
-        );
-        return 0;
+/// Verifies the heap and returns the number of corrupted blocks, details are logged as errors.
+#[no_mangle]
+pub extern "C" fn collam_check_heap() -> usize {
+    COLLAM.check_heap()
+}
+
+/// Out-of-memory handler of the C API, returns non-zero to retry the allocation.
+pub type CollamOomHandler = extern "C" fn(size: usize) -> libc::c_int;
+
+/// Handler registered with `collam_set_oom_handler`, 0 if there is none.
+static OOM_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+fn load_oom_handler() -> Option<CollamOomHandler> {
+    match OOM_HANDLER.load(Ordering::Acquire) {
+        0 => None,
+        // SAFETY: only `CollamOomHandler` pointers are stored
+        h => Some(unsafe { mem::transmute::<usize, CollamOomHandler>(h) }),
     }
-    block.size()
+}
+
+/// Forwards to the registered C handler.
+fn oom_handler(size: usize) -> bool {
+    load_oom_handler().map_or(false, |handler| handler(size) != 0)
+}
+
+/// Registers a handler which is called with the requested size before an allocation fails,
+/// NULL removes it. Returns the previously registered handler.
+#[no_mangle]
+pub extern "C" fn collam_set_oom_handler(
+    handler: Option<CollamOomHandler>,
+) -> Option<CollamOomHandler> {
+    init();
+    let previous = load_oom_handler();
+    OOM_HANDLER.store(handler.map_or(0, |h| h as usize), Ordering::Release);
+    COLLAM.set_oom_handler(handler.map(|_| oom_handler as fn(usize) -> bool));
+    previous
 }
 
 // TODO: implement me
 #[no_mangle]
 pub extern "C" fn mallopt(param: i32, value: i32) -> i32 {
-    eprintln!(
-        "[mallopt] not implemented! (param={}, value={})",
-        param, value
+    warn!(
+        Alloc,
+        "mallopt() not implemented! (param={}, value={})", param, value
     );
     1
 }
 
 #[cfg(not(test))]
 #[panic_handler]
-fn panic(info: &panic::PanicInfo) -> ! {
-    eprintln!("[libcollam.so]: panic occurred: {:?}", info);
-    abort()
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    error!(Alloc, "panic occurred: {:?}", info);
+    core::intrinsics::abort()
 }
 
 #[cfg(not(test))]
@@ -0,0 +1,28 @@
+use collam::log;
+
+use crate::options::{Config, Output};
+
+/// Applies the log filter and output of the given configuration.
+///
+/// # Safety
+///
+/// Must be called only once, before any other initialization which may log.
+pub unsafe fn init(config: &Config) {
+    match config.log_output {
+        Some(Output::Fd(fd)) => log::set_output(fd),
+        Some(Output::File(path)) => {
+            let fd = libc::open(
+                path.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_APPEND | libc::O_CLOEXEC,
+                0o644,
+            );
+            if fd >= 0 {
+                log::set_output(fd);
+            } else {
+                error!(Alloc, "unable to open log file, using stderr");
+            }
+        }
+        None => {}
+    }
+    log::set_filter(&config.log_filter);
+}
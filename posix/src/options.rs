@@ -0,0 +1,201 @@
+use core::str;
+
+use collam::alloc::options::{self, OptionError, Options};
+use collam::log::{self, Filter, Level, Target};
+
+use crate::{cgroup, env};
+
+/// Maximum length of a path option including the NUL terminator.
+const PATH_MAX_LEN: usize = 256;
+
+/// Legacy environment variables and the option they set, applied before `COLLAM_OPTIONS`.
+const ENV_OPTIONS: [(&[u8], &[u8]); 8] = [
+    (b"COLLAM_LOG\0", b"log"),
+    (b"COLLAM_LOG_OUTPUT\0", b"log_output"),
+    (b"COLLAM_STATS\0", b"stats"),
+    (b"COLLAM_TRACE\0", b"trace"),
+    (b"COLLAM_TRACE_RECORDS\0", b"trace_records"),
+    (b"COLLAM_PROFILE\0", b"profile"),
+    (b"COLLAM_PROFILE_OUTPUT\0", b"profile_output"),
+    (b"COLLAM_PROFILE_SIGNAL\0", b"profile_signal"),
+];
+
+/// NUL terminated path stored without allocating.
+#[derive(Copy, Clone)]
+pub struct Path {
+    buf: [u8; PATH_MAX_LEN],
+}
+
+impl Path {
+    pub const EMPTY: Path = Path {
+        buf: [0; PATH_MAX_LEN],
+    };
+
+    /// Returns `None` if the path is empty, too long or contains a NUL byte.
+    fn from_bytes(path: &[u8]) -> Option<Self> {
+        if path.is_empty() || path.len() >= PATH_MAX_LEN || path.contains(&0) {
+            return None;
+        }
+        let mut buf = [0; PATH_MAX_LEN];
+        buf[..path.len()].copy_from_slice(path);
+        Some(Self { buf })
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.buf[0] == 0
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> *const libc::c_char {
+        self.buf.as_ptr().cast::<libc::c_char>()
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Copy, Clone)]
+pub enum Output {
+    Fd(libc::c_int),
+    File(Path),
+}
+
+/// Configuration of the library, read once at initialization.
+pub struct Config {
+    pub options: Options,
+    pub log_filter: Filter,
+    pub log_output: Option<Output>,
+    pub stats: bool,
+    pub trace: Option<Path>,
+    pub trace_records: usize,
+    pub profile: usize,
+    pub profile_output: Option<Path>,
+    pub profile_signal: Option<libc::c_int>,
+}
+
+impl Config {
+    /// Reads the legacy `COLLAM_*` variables and then `COLLAM_OPTIONS`,
+    /// which takes precedence. Invalid options are reported and ignored.
+    ///
+    /// # Safety
+    ///
+    /// Must not be called concurrently with functions modifying the environment.
+    pub unsafe fn load(collam_options: Options) -> Self {
+        let mut config = Self {
+            options: collam_options,
+            log_filter: log::filter(),
+            log_output: None,
+            stats: false,
+            trace: None,
+            trace_records: 1 << 20,
+            profile: 0,
+            profile_output: None,
+            profile_signal: None,
+        };
+        for (name, key) in ENV_OPTIONS.iter() {
+            if let Some(value) = env::getenv(name) {
+                config.apply(key, value);
+            }
+        }
+        if let Some(spec) = env::getenv(b"COLLAM_OPTIONS\0") {
+            for (key, value) in options::pairs(spec) {
+                config.apply(key, value);
+            }
+        }
+        config
+    }
+
+    fn apply(&mut self, key: &[u8], value: &[u8]) {
+        let name = str::from_utf8(key).unwrap_or("?");
+        match self.set(key, value) {
+            Ok(()) => {}
+            Err(OptionError::UnknownKey) => error!(Alloc, "unknown option {}", name),
+            Err(OptionError::InvalidValue) => error!(Alloc, "invalid value for option {}", name),
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), OptionError> {
+        if key == b"limit" && value == b"cgroup" {
+            self.set_cgroup_limits();
+            return Ok(());
+        }
+        match self.options.set(key, value) {
+            Err(OptionError::UnknownKey) => {}
+            result => return result,
+        }
+        match key {
+            b"arenas" => {
+                if options::parse_size(value).ok_or(OptionError::InvalidValue)? != 1 {
+                    warn!(Alloc, "only a single arena is supported, ignoring arenas");
+                }
+            }
+            b"log" => {
+                self.log_filter = self
+                    .log_filter
+                    .parse(value)
+                    .ok_or(OptionError::InvalidValue)?
+            }
+            b"log_output" => {
+                self.log_output = match options::parse_size(value) {
+                    Some(fd) => Some(Output::Fd(fd as libc::c_int)),
+                    None => optional_path(value)?.map(Output::File),
+                }
+            }
+            b"stats" => self.stats = options::parse_bool(value).ok_or(OptionError::InvalidValue)?,
+            b"trace" => self.trace = optional_path(value)?,
+            b"trace_records" => {
+                self.trace_records = options::parse_size(value)
+                    .filter(|r| *r > 0)
+                    .ok_or(OptionError::InvalidValue)?
+            }
+            b"profile" => {
+                self.profile = options::parse_size(value).ok_or(OptionError::InvalidValue)?
+            }
+            b"profile_output" => self.profile_output = optional_path(value)?,
+            b"profile_signal" => {
+                self.profile_signal = Some(
+                    options::parse_size(value)
+                        .filter(|s| *s > 0 && *s < 65)
+                        .ok_or(OptionError::InvalidValue)? as libc::c_int,
+                )
+            }
+            _ if key.starts_with(b"log_") => {
+                let target = Target::from_bytes(&key[4..]).ok_or(OptionError::UnknownKey)?;
+                let level = Level::from_bytes(value).ok_or(OptionError::InvalidValue)?;
+                self.log_filter.set(target, level);
+            }
+            _ => return Err(OptionError::UnknownKey),
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Uses `memory.max` of the cgroup as limit and `memory.high` as soft limit.
+    fn set_cgroup_limits(&mut self) {
+        // SAFETY: only reads files into stack buffers
+        let limits = match unsafe { cgroup::memory_limits() } {
+            Some(l) => l,
+            None => {
+                warn!(Alloc, "no cgroup v2 found, ignoring limit=cgroup");
+                return;
+            }
+        };
+        if let Some(max) = limits.max {
+            self.options.limit = max;
+        }
+        if let Some(high) = limits.high {
+            self.options.soft_limit = high;
+        }
+    }
+}
+
+/// Parses a path option, an empty value disables the output.
+#[inline]
+fn optional_path(value: &[u8]) -> Result<Option<Path>, OptionError> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    Path::from_bytes(value)
+        .map(Some)
+        .ok_or(OptionError::InvalidValue)
+}
@@ -0,0 +1,58 @@
+use collam::alloc::Collam;
+use collam::profile::Profiler;
+
+use crate::options::{Config, Path};
+
+static PROFILER: Profiler = Profiler::new();
+/// Output path, stderr is used if empty. Only written by `init`.
+static mut OUTPUT: Path = Path::EMPTY;
+
+/// Enables the sampling profiler if the `profile` option is set to a sampling interval in bytes.
+/// Samples are written to `profile_output` (default: stderr) at exit
+/// and whenever the signal number given in `profile_signal` is received.
+///
+/// # Safety
+///
+/// Must be called only once.
+pub unsafe fn init(collam: &'static Collam, config: &Config) {
+    if config.profile == 0 {
+        return;
+    }
+    if let Some(path) = config.profile_output {
+        OUTPUT = path;
+    }
+    if let Some(signal) = config.profile_signal {
+        libc::signal(signal, on_signal as libc::sighandler_t);
+    }
+    PROFILER.set_interval(config.profile);
+    collam.set_profiler(Some(&PROFILER));
+}
+
+/// Writes all live samples to the configured output if profiling is enabled.
+pub fn dump() {
+    if PROFILER.interval() == 0 {
+        return;
+    }
+    // SAFETY: `OUTPUT` is not modified after initialization
+    let path = unsafe { &OUTPUT };
+    if path.is_empty() {
+        PROFILER.dump(libc::STDERR_FILENO);
+        return;
+    }
+    unsafe {
+        let fd = libc::open(
+            path.as_ptr(),
+            libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+            0o644,
+        );
+        if fd < 0 {
+            return;
+        }
+        PROFILER.dump(fd);
+        libc::close(fd);
+    }
+}
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    dump();
+}
@@ -0,0 +1,24 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use collam::alloc::Collam;
+use collam::log::{self, Level, Target};
+
+use crate::options::Config;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the statistics dump at exit if the `stats` option is set.
+pub fn init(config: &Config) {
+    ENABLED.store(config.stats, Ordering::Relaxed);
+}
+
+/// Writes heap and fragmentation statistics to the log output if enabled, regardless of the log filter.
+pub fn dump(collam: &Collam) {
+    if ENABLED.load(Ordering::Relaxed) {
+        log::write(
+            Target::Alloc,
+            Level::Info,
+            format_args!("heap statistics\n{}", collam.stats()),
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use core::ffi::c_void;
+use core::intrinsics::likely;
+use core::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+use collam::trace::{TraceHeader, TraceOp, TraceRecord, TRACE_HEADER_SIZE, TRACE_RECORD_SIZE};
+
+use crate::options::Config;
+
+static TRACE_FD: AtomicI32 = AtomicI32::new(-1);
+static TRACE_SEQ: AtomicU64 = AtomicU64::new(0);
+static TRACE_CAPACITY: AtomicU64 = AtomicU64::new(0);
+
+/// Opens the file given by the `trace` option and writes the file header.
+/// Tracing stays disabled if the option is not set or the file can't be created.
+///
+/// # Safety
+///
+/// Must be called only once before any record is written.
+pub unsafe fn init(config: &Config) {
+    let path = match config.trace {
+        Some(p) => p,
+        None => return,
+    };
+    let capacity = config.trace_records as u64;
+
+    let fd = libc::open(
+        path.as_ptr(),
+        libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_CLOEXEC,
+        0o644,
+    );
+    if fd < 0 {
+        error!(Alloc, "unable to open trace file, tracing disabled");
+        return;
+    }
+
+    let header = TraceHeader { capacity }.to_bytes();
+    if !write_at(fd, &header, 0) {
+        libc::close(fd);
+        return;
+    }
+    TRACE_CAPACITY.store(capacity, Ordering::Relaxed);
+    TRACE_FD.store(fd, Ordering::Release);
+}
+
+/// Appends a record for the given allocator call to the trace file if tracing is enabled.
+#[inline]
+pub fn record(op: TraceOp, size: usize, ptr: *mut c_void, old_ptr: *mut c_void) {
+    record_seq(next_seq(), op, size, ptr, old_ptr);
+}
+
+/// Takes the sequence number of the next record, 0 if tracing is disabled.
+/// Calls which release memory take it beforehand, so their record is ordered before
+/// the record of any allocation which reuses the memory.
+#[inline]
+pub fn next_seq() -> u64 {
+    if likely(TRACE_FD.load(Ordering::Acquire) < 0) {
+        return 0;
+    }
+    TRACE_SEQ.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// Writes the record with a sequence number taken by `next_seq`.
+pub fn record_seq(seq: u64, op: TraceOp, size: usize, ptr: *mut c_void, old_ptr: *mut c_void) {
+    if likely(seq == 0) {
+        return;
+    }
+    let record = TraceRecord {
+        seq,
+        timestamp: timestamp(),
+        thread_id: thread_id(),
+        op,
+        size: size as u64,
+        ptr: ptr as u64,
+        old_ptr: old_ptr as u64,
+    };
+    let offset = record.file_offset(TRACE_CAPACITY.load(Ordering::Relaxed));
+    debug_assert!(offset >= TRACE_HEADER_SIZE as u64);
+    // pwrite(2) doesn't touch the shared file offset, so concurrent writers need no lock
+    write_at(TRACE_FD.load(Ordering::Acquire), &record.to_bytes(), offset);
+}
+
+/// Writes the whole buffer at the given file offset.
+fn write_at(fd: libc::c_int, buf: &[u8], offset: u64) -> bool {
+    debug_assert!(buf.len() <= TRACE_RECORD_SIZE.max(TRACE_HEADER_SIZE));
+    let written = unsafe {
+        libc::pwrite(
+            fd,
+            buf.as_ptr().cast::<libc::c_void>(),
+            buf.len(),
+            offset as libc::off_t,
+        )
+    };
+    written == buf.len() as isize
+}
+
+/// Returns a monotonic timestamp in nanoseconds.
+#[inline]
+fn timestamp() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Returns the kernel thread id of the caller.
+#[inline]
+fn thread_id() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
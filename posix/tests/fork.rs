@@ -0,0 +1,107 @@
+//! Runs this test binary again with the library preloaded, so all allocations of the harness
+//! and the test go through it, and forks while other threads allocate.
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Set in the environment of the preloaded process.
+const PRELOADED: &str = "COLLAM_TEST_PRELOADED";
+const THREADS: usize = 4;
+const FORKS: usize = 50;
+
+/// Builds the library, which isn't done by `cargo test` for a `cdylib`,
+/// and returns its path.
+fn library() -> PathBuf {
+    let exe = env::current_exe().expect("unable to locate test binary");
+    // Test binaries are placed in `<target>/<profile>/deps`
+    let target = exe
+        .ancestors()
+        .nth(3)
+        .expect("unable to locate target directory");
+    let status = Command::new(env!("CARGO"))
+        .args(&["build", "--lib", "--manifest-path"])
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"))
+        .arg("--target-dir")
+        .arg(target)
+        .status()
+        .expect("unable to run cargo");
+    assert!(status.success(), "unable to build library");
+    target.join("debug").join("libcollam.so")
+}
+
+/// Forks while the other threads allocate, the child allocates before it exits.
+/// Returns the number of children which exited cleanly before the first failure.
+fn fork_while_allocating() -> usize {
+    let stop = Arc::new(AtomicBool::new(false));
+    let threads: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut blocks = Vec::new();
+                while !stop.load(Ordering::Relaxed) {
+                    // Mix slab and heap sized allocations
+                    blocks.push(vec![i as u8; 16 << (blocks.len() % 10)]);
+                    if blocks.len() == 64 {
+                        blocks.clear();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut clean = 0;
+    for _ in 0..FORKS {
+        unsafe {
+            match libc::fork() {
+                -1 => panic!("fork failed"),
+                0 => {
+                    // Inherited locks would block forever, let the parent notice
+                    libc::alarm(10);
+                    let ptr = libc::malloc(1024);
+                    let small = libc::malloc(32);
+                    let code = if ptr.is_null() || small.is_null() {
+                        1
+                    } else {
+                        0
+                    };
+                    libc::free(small);
+                    libc::free(ptr);
+                    libc::_exit(code);
+                }
+                pid => {
+                    let mut status = 0;
+                    assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                    if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+                        break;
+                    }
+                    clean += 1;
+                }
+            }
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().expect("allocating thread panicked");
+    }
+    clean
+}
+
+#[test]
+fn test_fork_while_allocating() {
+    if env::var_os(PRELOADED).is_some() {
+        assert_eq!(fork_while_allocating(), FORKS);
+        return;
+    }
+    let library = library();
+    let status = Command::new(env::current_exe().expect("unable to locate test binary"))
+        .args(&["--exact", "test_fork_while_allocating", "--nocapture"])
+        .env("LD_PRELOAD", &library)
+        .env(PRELOADED, "1")
+        .status()
+        .expect("unable to run preloaded test");
+    assert!(status.success());
+}
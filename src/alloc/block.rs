@@ -1,19 +1,52 @@
-use core::{fmt, intrinsics, mem, ptr::Unique};
-
-use libc_print::libc_eprintln;
+use core::{cmp, fmt, intrinsics, mem, ptr::Unique};
 
 use crate::{util, MIN_ALIGN};
 
-/// The required block size to store the bare minimum of metadata (size + magic values).
-pub const BLOCK_META_SIZE: usize = util::min_align_unchecked(mem::align_of::<usize>() * 2);
+/// The size of the block header, a single word packing size, flags and tag.
+/// Blocks start at `BLOCK_META_SIZE` bytes past a `MIN_ALIGN` boundary
+/// and span a multiple of `MIN_ALIGN` bytes, so memory regions are always aligned.
+pub const BLOCK_META_SIZE: usize = mem::size_of::<usize>();
 /// The minimum region size to save intrusive data structures if not allocated by the user.
 pub const BLOCK_MIN_REGION_SIZE: usize =
-    util::min_align_unchecked(mem::align_of::<Option<BlockPtr>>() * 2);
+    util::min_align_unchecked(BLOCK_META_SIZE + mem::size_of::<Option<BlockPtr>>() * 2)
+        - BLOCK_META_SIZE;
 /// Defines the minimum remaining size of a block to consider splitting it.
-pub const BLOCK_SPLIT_MIN_SIZE: usize =
-    util::min_align_unchecked(BLOCK_META_SIZE + BLOCK_MIN_REGION_SIZE + MIN_ALIGN);
-
-const BLOCK_MAGIC_FREE: u16 = 0xDEAD;
+pub const BLOCK_SPLIT_MIN_SIZE: usize = BLOCK_MIN_REGION_SIZE + MIN_ALIGN;
+
+/// The block has been handed out.
+const BLOCK_IN_USE: usize = 0b01;
+/// The preceding block has been handed out or doesn't exist.
+const BLOCK_PREV_IN_USE: usize = 0b10;
+/// The memory region handed out to the user is surrounded by redzones.
+const BLOCK_REDZONE: usize = 0b100;
+/// Mask of all flag bits, block sizes are a multiple of `MIN_ALIGN`.
+const BLOCK_FLAGS: usize = MIN_ALIGN - 1;
+/// Check tag stored in the most significant byte of every header.
+#[cfg(target_pointer_width = "64")]
+const BLOCK_TAG: usize = 0xC5 << 56;
+#[cfg(target_pointer_width = "64")]
+const BLOCK_TAG_MASK: usize = 0xFF << 56;
+/// 32-bit targets need all bits for the size and rely on the flag bits only.
+#[cfg(not(target_pointer_width = "64"))]
+const BLOCK_TAG: usize = 0;
+#[cfg(not(target_pointer_width = "64"))]
+const BLOCK_TAG_MASK: usize = 0;
+const BLOCK_SIZE_MASK: usize = !BLOCK_FLAGS & !BLOCK_TAG_MASK;
+
+/// Returns the region size of the smallest block which can hold `size` bytes.
+#[inline]
+pub fn region_size(size: usize) -> Option<usize> {
+    let block_size = util::pad_min_align(size.checked_add(BLOCK_META_SIZE)?)
+        .ok()?
+        .size();
+    if block_size > BLOCK_SIZE_MASK {
+        return None;
+    }
+    Some(cmp::max(
+        block_size - BLOCK_META_SIZE,
+        BLOCK_MIN_REGION_SIZE,
+    ))
+}
 
 /// Represents a mutable non-null Pointer to a `Block`.
 #[repr(C)]
@@ -21,15 +54,27 @@ const BLOCK_MAGIC_FREE: u16 = 0xDEAD;
 pub struct BlockPtr(Unique<Block>);
 
 impl BlockPtr {
-    /// Creates a `Block` instance at the given raw pointer for the specified size.
+    /// Creates a free `Block` instance at the given raw pointer for the specified region size.
+    /// The preceding block is considered in use.
     #[must_use]
     pub fn new(ptr: Unique<u8>, size: usize) -> Self {
-        debug_assert_eq!(size, util::pad_min_align(size).unwrap().size());
+        debug_assert_eq!((BLOCK_META_SIZE + size) % MIN_ALIGN, 0);
         let ptr = ptr.cast::<Block>();
         unsafe { *ptr.as_ptr() = Block::new(size) };
         Self(ptr)
     }
 
+    /// Writes a fencepost at the given raw pointer, an empty block in use which marks
+    /// the end of a memory source. Its header is the only part which must be accessible.
+    pub fn fencepost(ptr: Unique<u8>, prev_in_use: bool) -> Self {
+        let mut block = ptr.cast::<Block>();
+        // SAFETY: the caller provides at least `BLOCK_META_SIZE` bytes
+        unsafe { block.as_mut().header = Block::pack(0, BLOCK_IN_USE) };
+        let mut block = Self(block);
+        block.as_mut().set_prev_in_use(prev_in_use);
+        block
+    }
+
     /// Returns an existing `BlockPtr` instance from the given memory region raw pointer
     #[must_use]
     pub fn from_mem_region(ptr: Unique<u8>) -> Option<Self> {
@@ -72,13 +117,25 @@ impl BlockPtr {
     /// Returns the allocatable size available for the user
     #[inline]
     pub fn size(self) -> usize {
-        self.as_ref().size
+        self.block_size().saturating_sub(BLOCK_META_SIZE)
     }
 
     /// Returns the raw size in memory for this block.
     #[inline]
     pub fn block_size(self) -> usize {
-        BLOCK_META_SIZE + self.size()
+        self.as_ref().header & BLOCK_SIZE_MASK
+    }
+
+    /// Marks the block as used or free and updates the prev-in-use flag of the following block.
+    ///
+    /// # Safety
+    ///
+    /// The following block or a fencepost must exist.
+    #[inline]
+    pub unsafe fn mark(mut self, in_use: bool) {
+        self.as_mut().set_in_use(in_use);
+        let mut next = self.next_potential_block().cast::<Block>();
+        next.as_mut().set_prev_in_use(in_use);
     }
 
     /// Tries to merge self with the next block, if available.
@@ -92,15 +149,22 @@ impl BlockPtr {
             }
         }
 
-        dprintln!("[merge]: {} at {:p}", self.as_ref(), self.0);
-        dprintln!("       & {} at {:p}", next.as_ref(), next);
+        trace!(
+            Heap,
+            "merge {} at {:p} & {} at {:p}",
+            self.as_ref(),
+            self.0,
+            next.as_ref(),
+            next
+        );
         // Update related links
         self.as_mut().next = next.as_ref().next;
         if let Some(mut n) = self.as_ref().next {
             n.as_mut().prev = Some(self);
         }
         // Update to final size
-        self.as_mut().size += BLOCK_META_SIZE + next.size();
+        let size = self.size() + next.block_size();
+        self.as_mut().set_size(size);
 
         // Overwrite block meta data for old block to detect double free
         // SAFETY: passed pointer can't be null
@@ -108,39 +172,37 @@ impl BlockPtr {
             intrinsics::volatile_set_memory(next.cast::<u8>().as_ptr(), 0, BLOCK_META_SIZE);
         }
 
-        dprintln!("      -> {} at {:p}", self.as_ref(), self.0);
+        trace!(Heap, "merged -> {} at {:p}", self.as_ref(), self.0);
         Some(self)
     }
 
     /// Shrinks the block in-place to have the exact memory size as specified (excluding metadata).
     /// Returns a newly created `BlockPtr` with the remaining size or `None` if split is not possible.
     pub fn shrink(&mut self, size: usize) -> Option<BlockPtr> {
-        dprintln!("[split]: {} at {:p}", self.as_ref(), self.0);
-        debug_assert_eq!(
-            size,
-            util::pad_min_align(size).expect("unable to align").size()
-        );
+        debug_assert_eq!((BLOCK_META_SIZE + size) % MIN_ALIGN, 0);
         // Check if its possible to split the block with the requested size
         let rem_block_size = self.size().checked_sub(size + BLOCK_META_SIZE)?;
 
         if rem_block_size < BLOCK_SPLIT_MIN_SIZE {
-            dprintln!("      -> None");
             return None;
         }
 
         // Update size for old block
-        self.as_mut().size = size;
+        self.as_mut().set_size(size);
 
         // Create block with remaining size
         // SAFETY: we know `self.mem_region()` can't be null and size is within bounds
         let new_block_ptr = unsafe { Unique::new_unchecked(self.mem_region().as_ptr().add(size)) };
-        let new_block = BlockPtr::new(new_block_ptr, rem_block_size);
-
-        dprintln!("      -> {} at {:p}", self.as_ref(), self.0);
-        dprintln!("      -> {} at {:p}", new_block.as_ref(), new_block);
-        dprintln!(
-            "         distance is {} bytes",
-            new_block.as_ptr() as usize - (self.as_ptr() as usize + self.block_size())
+        let mut new_block = BlockPtr::new(new_block_ptr, rem_block_size);
+        new_block.as_mut().set_prev_in_use(self.as_ref().in_use());
+
+        trace!(
+            Heap,
+            "split -> {} at {:p} & {} at {:p}",
+            self.as_ref(),
+            self.0,
+            new_block.as_ref(),
+            new_block
         );
         debug_assert_eq!(
             new_block.as_ptr() as usize - (self.as_ptr() as usize + self.block_size()),
@@ -187,9 +249,9 @@ impl fmt::Debug for BlockPtr {
 
 #[repr(C)]
 pub struct Block {
-    // Required metadata
-    size: usize,
-    magic: u16,
+    // Required metadata: block size including the header,
+    // flags in the low bits and the check tag in the most significant byte.
+    header: usize,
     // Memory region starts here. All following members will be
     // overwritten and are unusable if block has been allocated by a user.
     pub next: Option<BlockPtr>,
@@ -200,13 +262,66 @@ impl Block {
     #[must_use]
     pub const fn new(size: usize) -> Self {
         Self {
-            size,
+            header: Self::pack(BLOCK_META_SIZE + size, BLOCK_PREV_IN_USE),
             next: None,
             prev: None,
-            magic: BLOCK_MAGIC_FREE,
         }
     }
 
+    #[inline]
+    const fn pack(block_size: usize, flags: usize) -> usize {
+        BLOCK_TAG | block_size | flags
+    }
+
+    #[inline]
+    fn set_flag(&mut self, flag: usize, value: bool) {
+        if value {
+            self.header |= flag;
+        } else {
+            self.header &= !flag;
+        }
+    }
+
+    /// Overwrites the region size, flags are kept.
+    #[inline]
+    pub(crate) fn set_size(&mut self, size: usize) {
+        debug_assert_eq!((BLOCK_META_SIZE + size) % MIN_ALIGN, 0);
+        self.header = (self.header & !BLOCK_SIZE_MASK) | (BLOCK_META_SIZE + size);
+    }
+
+    /// Returns `true` if the block has been handed out.
+    #[inline]
+    pub fn in_use(&self) -> bool {
+        self.header & BLOCK_IN_USE != 0
+    }
+
+    #[inline]
+    pub fn set_in_use(&mut self, in_use: bool) {
+        self.set_flag(BLOCK_IN_USE, in_use);
+    }
+
+    /// Returns `true` if the preceding block has been handed out or doesn't exist.
+    #[inline]
+    pub fn prev_in_use(&self) -> bool {
+        self.header & BLOCK_PREV_IN_USE != 0
+    }
+
+    #[inline]
+    pub fn set_prev_in_use(&mut self, prev_in_use: bool) {
+        self.set_flag(BLOCK_PREV_IN_USE, prev_in_use);
+    }
+
+    /// Returns `true` if the memory region is laid out with redzones.
+    #[inline]
+    pub fn has_redzone(&self) -> bool {
+        self.header & BLOCK_REDZONE != 0
+    }
+
+    #[inline]
+    pub fn set_redzone(&mut self, redzone: bool) {
+        self.set_flag(BLOCK_REDZONE, redzone);
+    }
+
     #[inline]
     pub fn unlink(&mut self) {
         self.next = None;
@@ -217,23 +332,21 @@ impl Block {
     /// Returns `true` if block metadata is intact, `false` otherwise.
     #[inline]
     pub fn verify(&self) -> bool {
-        self.magic == BLOCK_MAGIC_FREE
+        self.header & BLOCK_TAG_MASK == BLOCK_TAG
+            && self.header & BLOCK_FLAGS & !(BLOCK_IN_USE | BLOCK_PREV_IN_USE | BLOCK_REDZONE) == 0
     }
 }
 
 impl fmt::Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        /*
-        TODO: fix formatter for self.prev and self.next
-        write!(
-            f,
-            "Block(size={}, prev={:?}, next={:?}, magic=0x{:X}, meta_size={})",
-            self.size, self.prev, self.next, self.magic, BLOCK_META_SIZE,
-        )*/
         write!(
             f,
-            "Block(size={}, magic=0x{:X}, meta_size={})",
-            self.size, self.magic, BLOCK_META_SIZE,
+            "Block(size={}, in_use={}, prev_in_use={}, header=0x{:X}, meta_size={})",
+            (self.header & BLOCK_SIZE_MASK).saturating_sub(BLOCK_META_SIZE),
+            self.in_use(),
+            self.prev_in_use(),
+            self.header,
+            BLOCK_META_SIZE,
         )
     }
 }
@@ -241,7 +354,21 @@ impl fmt::Display for Block {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use core::ffi::c_void;
+    use std::vec::Vec;
+
+    /// Buffer holding a block of the given region size at a properly aligned address.
+    struct Buffer(Vec<u128>);
+
+    impl Buffer {
+        fn new(size: usize) -> Self {
+            Self(std::vec![0; (BLOCK_META_SIZE * 2 + size) / 16 + 1])
+        }
+
+        fn ptr(&mut self) -> Unique<u8> {
+            // SAFETY: the buffer is aligned to 16 bytes and large enough
+            unsafe { Unique::new_unchecked(self.0.as_mut_ptr().cast::<u8>().add(BLOCK_META_SIZE)) }
+        }
+    }
 
     fn assert_block(block: BlockPtr, size: usize) {
         assert_eq!(block.size(), size, "block size doesn't match");
@@ -255,34 +382,80 @@ mod tests {
         assert!(block.as_ref().prev.is_none(), "prev is not None");
     }
 
+    #[test]
+    fn test_region_size() {
+        let word = mem::size_of::<usize>();
+        assert_eq!(BLOCK_META_SIZE, word);
+        // The region holds both links and the block ends on a `MIN_ALIGN` boundary
+        assert!(BLOCK_MIN_REGION_SIZE >= 2 * word);
+        assert_eq!((BLOCK_META_SIZE + BLOCK_MIN_REGION_SIZE) % MIN_ALIGN, 0);
+        assert_eq!(region_size(1), Some(BLOCK_MIN_REGION_SIZE));
+        assert_eq!(
+            region_size(BLOCK_MIN_REGION_SIZE),
+            Some(BLOCK_MIN_REGION_SIZE)
+        );
+        assert_eq!(
+            region_size(BLOCK_MIN_REGION_SIZE + 1),
+            Some(BLOCK_MIN_REGION_SIZE + MIN_ALIGN)
+        );
+        // Large blocks stay representable next to the tag
+        assert_eq!(region_size(1 << 30), Some((1 << 30) + MIN_ALIGN - word));
+        assert_eq!(region_size(usize::max_value()), None);
+    }
+
     #[test]
     fn test_block_new() {
-        let alloc_size = 64;
-        let ptr = unsafe {
-            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
-                .expect("unable to allocate memory")
-                .cast::<u8>()
-        };
-        assert_block(BlockPtr::new(ptr, alloc_size), alloc_size);
-        unsafe { libc::free(ptr.cast::<c_void>().as_ptr()) };
+        let alloc_size = 56;
+        let mut buf = Buffer::new(alloc_size);
+        let block = BlockPtr::new(buf.ptr(), alloc_size);
+        assert_block(block, alloc_size);
+        assert!(!block.as_ref().in_use());
+        assert!(block.as_ref().prev_in_use());
+        assert_eq!(block.mem_region().as_ptr() as usize % MIN_ALIGN, 0);
+    }
+
+    #[test]
+    fn test_block_flags() {
+        let alloc_size = 120;
+        let mut buf = Buffer::new(alloc_size);
+        let mut block = BlockPtr::new(buf.ptr(), alloc_size);
+        let fencepost = BlockPtr::fencepost(unsafe { block.next_potential_block() }, false);
+        assert!(fencepost.as_ref().verify());
+        assert!(fencepost.as_ref().in_use());
+        assert_eq!(fencepost.block_size(), 0);
+
+        unsafe { block.mark(true) };
+        assert!(block.as_ref().in_use());
+        assert!(fencepost.as_ref().prev_in_use());
+        block.as_mut().set_prev_in_use(false);
+        assert_block(block, alloc_size);
+        assert!(!block.as_ref().prev_in_use());
+
+        block.as_mut().set_redzone(true);
+        assert!(block.as_ref().verify());
+        assert!(block.as_ref().has_redzone());
+        assert_block(block, alloc_size);
+        block.as_mut().set_redzone(false);
+        assert!(!block.as_ref().has_redzone());
+
+        unsafe { block.mark(false) };
+        assert!(!block.as_ref().in_use());
+        assert!(!fencepost.as_ref().prev_in_use());
     }
 
     #[test]
     fn test_block_shrink_with_remaining() {
-        let block1_size = 4096;
-        let ptr = unsafe {
-            Unique::new(libc::malloc(BLOCK_META_SIZE + block1_size))
-                .expect("unable to allocate memory")
-                .cast::<u8>()
-        };
+        let block1_size = 4088;
+        let mut buf = Buffer::new(block1_size);
+        let ptr = buf.ptr();
         let mut block1 = BlockPtr::new(ptr, block1_size);
         assert_block(block1, block1_size);
         let total_size = block1.block_size();
         assert_eq!(ptr.as_ptr(), block1.as_ptr().cast::<u8>());
 
-        // Shrink block1 to 256 bytes
-        let mut block2 = block1.shrink(256).expect("split block failed");
-        assert_block(block1, 256);
+        // Shrink block1 to 248 bytes
+        let mut block2 = block1.shrink(248).expect("split block failed");
+        assert_block(block1, 248);
         unsafe {
             assert_eq!(
                 block1.next_potential_block().as_ptr(),
@@ -290,10 +463,11 @@ mod tests {
             );
         }
         assert_block(block2, total_size - block1.block_size() - BLOCK_META_SIZE);
+        assert!(!block2.as_ref().prev_in_use());
 
-        // Shrink block2 to 256 bytes
-        let block3 = block2.shrink(256).expect("split block failed");
-        assert_block(block2, 256);
+        // Shrink block2 to 248 bytes
+        let block3 = block2.shrink(248).expect("split block failed");
+        assert_block(block2, 248);
         unsafe {
             assert_eq!(
                 block2.next_potential_block().as_ptr(),
@@ -304,77 +478,60 @@ mod tests {
             block3,
             total_size - block1.block_size() - block2.block_size() - BLOCK_META_SIZE,
         );
-        unsafe { libc::free(ptr.cast::<c_void>().as_ptr()) };
     }
 
     #[test]
     fn test_block_shrink_no_remaining() {
-        let alloc_size = 256;
-        let ptr = unsafe {
-            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
-                .expect("unable to allocate memory")
-                .cast::<u8>()
-        };
+        let alloc_size = 248;
+        let mut buf = Buffer::new(alloc_size);
+        let ptr = buf.ptr();
         let mut block = BlockPtr::new(ptr, alloc_size);
-        let remaining = block.shrink(240);
+        let remaining = block.shrink(232);
 
         // Assert correctness of initial block
         assert_eq!(ptr.as_ptr(), block.as_ptr().cast::<u8>());
-        assert_block(block, 256);
+        assert_block(block, 248);
 
         // There should be no remaining block
-        // since 240 will be aligned to 256 and no space is left.
+        // since only 16 bytes are left.
         assert!(remaining.is_none());
-        unsafe { libc::free(ptr.cast::<c_void>().as_ptr()) };
     }
 
     #[test]
     fn test_block_verify_ok() {
-        let alloc_size = 256;
-        let ptr = unsafe {
-            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
-                .expect("unable to allocate memory")
-                .cast::<u8>()
-        };
-        let block = BlockPtr::new(ptr, alloc_size);
+        let alloc_size = 248;
+        let mut buf = Buffer::new(alloc_size);
+        let block = BlockPtr::new(buf.ptr(), alloc_size);
         assert!(block.as_ref().verify());
-        unsafe { libc::free(ptr.cast::<c_void>().as_ptr()) };
     }
 
     #[test]
     fn test_block_verify_invalid() {
-        let alloc_size = 256;
-        let ptr = unsafe {
-            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
-                .expect("unable to allocate memory")
-                .cast::<u8>()
-        };
-        let mut block = BlockPtr::new(ptr, alloc_size);
-        block.as_mut().magic = 0x1234;
-        assert_eq!(block.as_ref().verify(), false);
-
-        unsafe { libc::free(ptr.cast::<c_void>().as_ptr()) };
+        let alloc_size = 248;
+        let mut buf = Buffer::new(alloc_size);
+        let mut block = BlockPtr::new(buf.ptr(), alloc_size);
+        // 32-bit targets have no tag
+        block.as_mut().header ^= BLOCK_TAG_MASK;
+        assert_eq!(block.as_ref().verify(), BLOCK_TAG_MASK == 0);
+        // Unknown flags are rejected, unless the bit is part of the size
+        block.as_mut().header = Block::pack(256, 0b1000);
+        assert_eq!(block.as_ref().verify(), BLOCK_FLAGS < 0b1000);
     }
 
     #[test]
     fn test_block_mem_region_ok() {
-        let alloc_size = 64;
-        let ptr = unsafe {
-            Unique::new(libc::malloc(BLOCK_META_SIZE + alloc_size))
-                .expect("unable to allocate memory")
-                .cast::<u8>()
-        };
-        let block = BlockPtr::new(ptr, alloc_size);
+        let alloc_size = 56;
+        let mut buf = Buffer::new(alloc_size);
+        let block = BlockPtr::new(buf.ptr(), alloc_size);
         let mem = block.mem_region();
         assert!(mem.as_ptr() > block.as_ptr().cast::<u8>());
         let block2 = BlockPtr::from_mem_region(mem).expect("unable to create from mem region");
         assert_eq!(block, block2);
-        unsafe { libc::free(ptr.cast::<c_void>().as_ptr()) };
     }
 
     #[test]
     fn test_block_mem_region_err() {
-        let region = unsafe { Unique::new_unchecked(16 as *mut u8) };
+        let region = unsafe { Unique::new_unchecked(BLOCK_META_SIZE as *mut u8) };
         assert_eq!(BlockPtr::from_mem_region(region), None);
     }
 }
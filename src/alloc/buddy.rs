@@ -0,0 +1,382 @@
+//! Binary buddy system operating on regions obtained from a `MemorySource`.
+//!
+//! Every block spans a power of two bytes including its header, so its buddy is found
+//! by flipping a single bit of the offset within the region. Free blocks are kept in
+//! one unordered list per order, splitting and merging takes at most `BUDDY_ORDERS` steps.
+//! Only the in-use flag of the headers is maintained, the prev-in-use flag has no meaning here.
+use core::{cmp, intrinsics, ptr::Unique};
+
+use crate::alloc::block::{BlockPtr, BLOCK_META_SIZE, BLOCK_MIN_REGION_SIZE};
+use crate::sources::MemorySource;
+
+/// Order of the smallest block, large enough for the header and the list links.
+const BUDDY_MIN_ORDER: u32 = (BLOCK_META_SIZE + BLOCK_MIN_REGION_SIZE).trailing_zeros();
+/// Order of a region requested from the memory source.
+pub const BUDDY_MAX_ORDER: u32 = 22;
+/// Size of a region requested from the memory source.
+pub const BUDDY_REGION_SIZE: usize = 1 << BUDDY_MAX_ORDER;
+/// Largest size which can be served, larger allocations have to be handled elsewhere.
+pub const BUDDY_MAX_SIZE: usize = BUDDY_REGION_SIZE - BLOCK_META_SIZE;
+/// Maximum number of regions, limits the memory managed to 1 GiB.
+const BUDDY_MAX_REGIONS: usize = 256;
+const BUDDY_ORDERS: usize = (BUDDY_MAX_ORDER - BUDDY_MIN_ORDER + 1) as usize;
+
+/// Returns the order of the smallest block which fits `size` bytes and the block header.
+#[inline]
+fn order_of(size: usize) -> u32 {
+    let block_size = (BLOCK_META_SIZE + size).next_power_of_two();
+    cmp::max(block_size.trailing_zeros(), BUDDY_MIN_ORDER)
+}
+
+pub struct BuddyAllocator {
+    /// Heads of the free lists, indexed by `order - BUDDY_MIN_ORDER`.
+    free: [Option<BlockPtr>; BUDDY_ORDERS],
+    /// Start addresses of all regions in ascending order.
+    regions: [usize; BUDDY_MAX_REGIONS],
+    region_count: usize,
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            free: [None; BUDDY_ORDERS],
+            regions: [0; BUDDY_MAX_REGIONS],
+            region_count: 0,
+        }
+    }
+
+    /// Returns the number of bytes obtained from the memory source.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.region_count * BUDDY_REGION_SIZE
+    }
+
+    /// Returns `true` if `size` bytes can be served without requesting a new region.
+    pub fn has_free(&self, size: usize) -> bool {
+        size <= BUDDY_MAX_SIZE && (order_of(size)..=BUDDY_MAX_ORDER).any(|o| self.head(o).is_some())
+    }
+
+    /// Returns the start of the region containing `addr`.
+    fn region_of(&self, addr: usize) -> Option<usize> {
+        let regions = &self.regions[..self.region_count];
+        let i = match regions.binary_search(&addr) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        Some(regions[i]).filter(|base| addr < base + BUDDY_REGION_SIZE)
+    }
+
+    /// Returns `true` if the given `BlockPtr` lies within a region of this allocator.
+    #[inline]
+    pub fn contains(&self, block: BlockPtr) -> bool {
+        self.region_of(block.as_ptr() as usize).is_some()
+    }
+
+    /// Returns a used `BlockPtr` of at least the given size,
+    /// a new region is requested from `source` if no free block is large enough.
+    ///
+    /// # Safety
+    ///
+    /// Function is not thread safe.
+    pub unsafe fn alloc(
+        &mut self,
+        source: &mut impl MemorySource,
+        size: usize,
+    ) -> Option<BlockPtr> {
+        if size > BUDDY_MAX_SIZE {
+            return None;
+        }
+        let order = order_of(size);
+        let mut current = match (order..=BUDDY_MAX_ORDER).find(|o| self.head(*o).is_some()) {
+            Some(o) => o,
+            None => {
+                self.grow(source)?;
+                BUDDY_MAX_ORDER
+            }
+        };
+        let block = self.pop(current)?;
+        let addr = block.as_ptr() as usize;
+        while current > order {
+            current -= 1;
+            self.push(Self::create(addr + (1 << current), current));
+        }
+        let mut block = Self::create(addr, order);
+        block.as_mut().set_in_use(true);
+        trace!(Heap, "buddy alloc {} at {:p}", block.as_ref(), block);
+        Some(block)
+    }
+
+    /// Takes back a `BlockPtr` handed out by `alloc` and merges it with its free buddies.
+    /// Regions which are free entirely are returned to `source` if `trim` is set.
+    /// Returns `Err` if the block is not a valid used block, e.g. on double free.
+    ///
+    /// # Safety
+    ///
+    /// Function is not thread safe.
+    pub unsafe fn dealloc(
+        &mut self,
+        source: &mut impl MemorySource,
+        block: BlockPtr,
+        trim: bool,
+    ) -> Result<(), ()> {
+        let mut addr = block.as_ptr() as usize;
+        let base = self.region_of(addr).ok_or(())?;
+        let block_size = block.block_size();
+        if !block.as_ref().verify()
+            || !block.as_ref().in_use()
+            || !block_size.is_power_of_two()
+            || block_size < 1 << BUDDY_MIN_ORDER
+            || block_size > BUDDY_REGION_SIZE
+            || (addr - base) % block_size != 0
+        {
+            return Err(());
+        }
+
+        let mut order = block_size.trailing_zeros();
+        while order < BUDDY_MAX_ORDER {
+            let buddy = Self::block_at(base + ((addr - base) ^ (1 << order)));
+            if !buddy.as_ref().verify()
+                || buddy.as_ref().in_use()
+                || buddy.block_size() != 1 << order
+            {
+                break;
+            }
+            self.remove(buddy);
+            addr = cmp::min(addr, buddy.as_ptr() as usize);
+            // Overwrite the header of the absorbed block to detect double free
+            intrinsics::volatile_set_memory((addr + (1 << order)) as *mut u8, 0, BLOCK_META_SIZE);
+            order += 1;
+        }
+
+        let block = Self::create(addr, order);
+        if order == BUDDY_MAX_ORDER && trim && source.release(block) {
+            trace!(Heap, "buddy released region at {:#x}", addr);
+            self.remove_region(addr);
+            return Ok(());
+        }
+        self.push(block);
+        Ok(())
+    }
+
+    /// Shrinks a used `BlockPtr` in-place to the smallest order fitting `size`
+    /// and puts the upper halves back into the free lists.
+    pub fn shrink(&mut self, block: &mut BlockPtr, size: usize) {
+        let addr = block.as_ptr() as usize;
+        let mut order = block.block_size().trailing_zeros();
+        let new_order = order_of(size);
+        if new_order >= order {
+            return;
+        }
+        while order > new_order {
+            order -= 1;
+            // The buddy of the upper half is the lower half, which is still in use
+            self.push(Self::create(addr + (1 << order), order));
+        }
+        block.as_mut().set_size((1 << order) - BLOCK_META_SIZE);
+    }
+
+    /// Returns an iterator over all free blocks.
+    pub fn iter(&self) -> impl Iterator<Item = BlockPtr> + '_ {
+        self.free.iter().flat_map(|head| {
+            let mut next = *head;
+            core::iter::from_fn(move || {
+                let block = next?;
+                next = block.as_ref().next;
+                Some(block)
+            })
+        })
+    }
+
+    /// Requests a new region from `source` and adds it as a free block of the highest order.
+    unsafe fn grow(&mut self, source: &mut impl MemorySource) -> Option<()> {
+        if self.region_count == BUDDY_MAX_REGIONS {
+            return None;
+        }
+        let region = source.request(BUDDY_MAX_SIZE)?;
+        debug_assert_eq!(region.block_size(), BUDDY_REGION_SIZE);
+        let base = region.as_ptr() as usize;
+        let i = match self.regions[..self.region_count].binary_search(&base) {
+            Ok(i) | Err(i) => i,
+        };
+        self.regions.copy_within(i..self.region_count, i + 1);
+        self.regions[i] = base;
+        self.region_count += 1;
+        trace!(Heap, "buddy added region at {:#x}", base);
+        self.push(Self::create(base, BUDDY_MAX_ORDER));
+        Some(())
+    }
+
+    fn remove_region(&mut self, base: usize) {
+        if let Ok(i) = self.regions[..self.region_count].binary_search(&base) {
+            self.regions.copy_within(i + 1..self.region_count, i);
+            self.region_count -= 1;
+        }
+    }
+
+    /// Writes a free block header of the given order at `addr`.
+    #[inline]
+    fn create(addr: usize, order: u32) -> BlockPtr {
+        // SAFETY: addresses are always within a region and never null
+        let ptr = unsafe { Unique::new_unchecked(addr as *mut u8) };
+        BlockPtr::new(ptr, (1 << order) - BLOCK_META_SIZE)
+    }
+
+    /// Returns the existing block header at `addr`.
+    #[inline]
+    fn block_at(addr: usize) -> BlockPtr {
+        // SAFETY: addresses are always within a region and never null
+        let ptr = unsafe { Unique::new_unchecked((addr + BLOCK_META_SIZE) as *mut u8) };
+        BlockPtr::from_mem_region(ptr).expect("block header at null")
+    }
+
+    #[inline]
+    fn head(&self, order: u32) -> Option<BlockPtr> {
+        self.free[(order - BUDDY_MIN_ORDER) as usize]
+    }
+
+    fn push(&mut self, mut block: BlockPtr) {
+        let head = &mut self.free[(block.block_size().trailing_zeros() - BUDDY_MIN_ORDER) as usize];
+        block.as_mut().prev = None;
+        block.as_mut().next = *head;
+        if let Some(mut next) = *head {
+            next.as_mut().prev = Some(block);
+        }
+        *head = Some(block);
+    }
+
+    fn pop(&mut self, order: u32) -> Option<BlockPtr> {
+        let block = self.head(order)?;
+        self.remove(block);
+        Some(block)
+    }
+
+    fn remove(&mut self, mut block: BlockPtr) {
+        let head = &mut self.free[(block.block_size().trailing_zeros() - BUDDY_MIN_ORDER) as usize];
+        match block.as_ref().prev {
+            Some(mut prev) => prev.as_mut().next = block.as_ref().next,
+            None => *head = block.as_ref().next,
+        }
+        if let Some(mut next) = block.as_ref().next {
+            next.as_mut().prev = block.as_ref().prev;
+        }
+        block.as_mut().unlink();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::MmapSource;
+
+    fn free_blocks(buddy: &BuddyAllocator) -> usize {
+        buddy.iter().count()
+    }
+
+    #[test]
+    fn test_order_of() {
+        assert_eq!(order_of(0), BUDDY_MIN_ORDER);
+        assert_eq!(order_of(4096 - BLOCK_META_SIZE), 12);
+        assert_eq!(order_of(4096), 13);
+        assert_eq!(order_of(BUDDY_MAX_SIZE), BUDDY_MAX_ORDER);
+    }
+
+    #[test]
+    fn test_buddy_split_and_merge() {
+        unsafe {
+            let mut source = MmapSource::new(2 * BUDDY_REGION_SIZE);
+            let mut buddy = BuddyAllocator::new();
+            let block = buddy.alloc(&mut source, 4000).expect("unable to allocate");
+            assert_eq!(block.block_size(), 4096);
+            assert!(block.as_ref().verify());
+            assert!(buddy.contains(block));
+            assert_eq!(buddy.size(), BUDDY_REGION_SIZE);
+            // One free upper half for each order between 4 KiB and the region size
+            assert_eq!(free_blocks(&buddy), (BUDDY_MAX_ORDER - 12) as usize);
+
+            // The buddy of the first block is handed out next
+            let block2 = buddy.alloc(&mut source, 4000).expect("unable to allocate");
+            assert_eq!(block2.as_ptr() as usize, block.as_ptr() as usize + 4096);
+            assert_eq!(free_blocks(&buddy), (BUDDY_MAX_ORDER - 13) as usize);
+
+            assert_eq!(buddy.dealloc(&mut source, block, false), Ok(()));
+            assert_eq!(buddy.dealloc(&mut source, block2, false), Ok(()));
+            assert_eq!(free_blocks(&buddy), 1);
+            assert_eq!(
+                buddy.head(BUDDY_MAX_ORDER).map(|b| b.block_size()),
+                Some(BUDDY_REGION_SIZE)
+            );
+            source.destroy();
+        }
+    }
+
+    #[test]
+    fn test_buddy_release_region() {
+        unsafe {
+            let mut source = MmapSource::new(2 * BUDDY_REGION_SIZE);
+            let mut buddy = BuddyAllocator::new();
+            let block = buddy.alloc(&mut source, 100).expect("unable to allocate");
+            assert_eq!(block.block_size(), 128);
+            assert_eq!(buddy.dealloc(&mut source, block, true), Ok(()));
+            assert_eq!(buddy.size(), 0);
+            assert_eq!(free_blocks(&buddy), 0);
+            assert!(!buddy.contains(block));
+            source.destroy();
+        }
+    }
+
+    #[test]
+    fn test_buddy_double_free() {
+        unsafe {
+            let mut source = MmapSource::new(2 * BUDDY_REGION_SIZE);
+            let mut buddy = BuddyAllocator::new();
+            let block = buddy.alloc(&mut source, 100).expect("unable to allocate");
+            let block2 = buddy.alloc(&mut source, 100).expect("unable to allocate");
+            assert_eq!(buddy.dealloc(&mut source, block2, false), Ok(()));
+            assert_eq!(buddy.dealloc(&mut source, block2, false), Err(()));
+            assert_eq!(buddy.dealloc(&mut source, block, false), Ok(()));
+            // block2 has been merged into block
+            assert_eq!(buddy.dealloc(&mut source, block2, false), Err(()));
+            assert_eq!(free_blocks(&buddy), 1);
+            source.destroy();
+        }
+    }
+
+    #[test]
+    fn test_buddy_shrink() {
+        unsafe {
+            let mut source = MmapSource::new(2 * BUDDY_REGION_SIZE);
+            let mut buddy = BuddyAllocator::new();
+            let mut block = buddy.alloc(&mut source, 60000).expect("unable to allocate");
+            assert_eq!(block.block_size(), 65536);
+            let free = free_blocks(&buddy);
+            block.mem_region().as_ptr().write_bytes(1, 1000);
+            buddy.shrink(&mut block, 1000);
+            assert_eq!(block.block_size(), 1024);
+            assert!(block.as_ref().verify());
+            assert_eq!(*block.mem_region().as_ptr(), 1);
+            assert_eq!(free_blocks(&buddy), free + 6);
+
+            assert_eq!(buddy.dealloc(&mut source, block, false), Ok(()));
+            assert_eq!(free_blocks(&buddy), 1);
+            source.destroy();
+        }
+    }
+
+    #[test]
+    fn test_buddy_exhausted() {
+        unsafe {
+            // Leaves room for a single region and its fencepost
+            let mut source = MmapSource::new(BUDDY_REGION_SIZE + 1);
+            let mut buddy = BuddyAllocator::new();
+            assert!(buddy.alloc(&mut source, BUDDY_MAX_SIZE + 1).is_none());
+            let block = buddy
+                .alloc(&mut source, BUDDY_MAX_SIZE)
+                .expect("unable to allocate");
+            assert!(buddy.alloc(&mut source, 16).is_none());
+            assert_eq!(buddy.dealloc(&mut source, block, false), Ok(()));
+            assert!(buddy.alloc(&mut source, 16).is_some());
+            source.destroy();
+        }
+    }
+}
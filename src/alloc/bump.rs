@@ -0,0 +1,494 @@
+//! Bump allocation for short lived data.
+//!
+//! A `BumpArena` hands out memory by advancing a pointer through chunks obtained from a
+//! `MemorySource`. Individual frees are ignored, all memory is reclaimed at once
+//! with `reset` or partially with `rollback` to a previously taken `Checkpoint`.
+//! Chunks are kept for reuse until the arena is dropped.
+use core::alloc::{
+    AllocErr, AllocInit, AllocRef, GlobalAlloc, Layout, MemoryBlock, ReallocPlacement,
+};
+use core::ptr::{self, null_mut, NonNull, Unique};
+use core::{cmp, mem};
+
+use spin::Mutex;
+
+use crate::alloc::block::BlockPtr;
+use crate::sources::{MemorySource, MmapSource};
+use crate::util;
+
+/// Default usable size of a chunk.
+pub const BUMP_DEFAULT_CHUNK_SIZE: usize = 64 << 10;
+
+/// Header at the start of every chunk.
+struct Chunk {
+    /// Next chunk in allocation order.
+    next: Option<Unique<Chunk>>,
+    /// End address of the usable memory.
+    end: usize,
+}
+
+const CHUNK_HEADER_SIZE: usize = util::min_align_unchecked(mem::size_of::<Chunk>());
+
+impl Chunk {
+    #[inline]
+    fn start(chunk: Unique<Chunk>) -> usize {
+        chunk.as_ptr() as usize + CHUNK_HEADER_SIZE
+    }
+}
+
+/// Position within a `BumpArena` which can be restored with `BumpArena::rollback`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Address of the current chunk, 0 if no chunk has been requested yet.
+    chunk: usize,
+    pos: usize,
+    allocated: usize,
+}
+
+struct Inner<S: MemorySource> {
+    source: S,
+    chunk_size: usize,
+    first: Option<Unique<Chunk>>,
+    current: Option<Unique<Chunk>>,
+    /// Next free address in the current chunk.
+    pos: usize,
+    /// Bytes handed out since the last reset.
+    allocated: usize,
+    /// Start address of the most recent allocation, allows to resize it in-place.
+    last: usize,
+}
+
+impl<S: MemorySource> Inner<S> {
+    /// Tries to allocate from the current chunk.
+    #[inline]
+    fn bump(&mut self, layout: Layout) -> Option<*mut u8> {
+        let chunk = self.current?;
+        let start = self.pos.checked_add(layout.align() - 1)? & !(layout.align() - 1);
+        let end = start.checked_add(layout.size())?;
+        // SAFETY: chunk points to a valid chunk header
+        if end > unsafe { chunk.as_ref().end } {
+            return None;
+        }
+        self.pos = end;
+        self.allocated += layout.size();
+        self.last = start;
+        Some(start as *mut u8)
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        loop {
+            if let Some(ptr) = self.bump(layout) {
+                return ptr;
+            }
+            // Reuse chunks retained by `reset` and `rollback` if they are large enough
+            let next = self.current.and_then(|c| unsafe { c.as_ref().next });
+            match next {
+                Some(next) if Self::fits(next, layout) => {
+                    self.current = Some(next);
+                    self.pos = Chunk::start(next);
+                }
+                _ => {
+                    if !self.add_chunk(layout) {
+                        return null_mut();
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn fits(chunk: Unique<Chunk>, layout: Layout) -> bool {
+        let start = Chunk::start(chunk);
+        let aligned = (start + layout.align() - 1) & !(layout.align() - 1);
+        // SAFETY: chunk points to a valid chunk header
+        aligned
+            .checked_add(layout.size())
+            .map_or(false, |end| end <= unsafe { chunk.as_ref().end })
+    }
+
+    /// Requests a chunk which fits `layout` and inserts it after the current one.
+    fn add_chunk(&mut self, layout: Layout) -> bool {
+        let size = match layout
+            .size()
+            .checked_add(layout.align() + CHUNK_HEADER_SIZE)
+            .and_then(|s| util::pad_min_align(cmp::max(s, self.chunk_size)).ok())
+        {
+            Some(l) => l.size(),
+            None => return false,
+        };
+        // SAFETY: access is synchronized by the mutex of `BumpArena`
+        let block = match unsafe { self.source.request(size) } {
+            Some(b) => b,
+            None => return false,
+        };
+        let region = block.mem_region();
+        let chunk: Unique<Chunk> = region.cast();
+        unsafe {
+            chunk.as_ptr().write(Chunk {
+                next: self.current.and_then(|c| c.as_ref().next),
+                end: region.as_ptr() as usize + block.size(),
+            });
+            match self.current {
+                Some(mut current) => current.as_mut().next = Some(chunk),
+                None => self.first = Some(chunk),
+            }
+        }
+        trace!(
+            Heap,
+            "new bump chunk at {:p} ({} bytes)",
+            chunk.as_ptr(),
+            size
+        );
+        self.current = Some(chunk);
+        self.pos = Chunk::start(chunk);
+        true
+    }
+
+    /// Resizes the most recent allocation in-place, returns `false` if not possible.
+    fn resize_in_place(&mut self, ptr: *mut u8, old_size: usize, new_size: usize) -> bool {
+        let chunk = match self.current {
+            Some(c) => c,
+            None => return false,
+        };
+        if ptr as usize != self.last {
+            return new_size <= old_size;
+        }
+        let end = match self.last.checked_add(new_size) {
+            Some(e) => e,
+            None => return false,
+        };
+        // SAFETY: chunk points to a valid chunk header
+        if end > unsafe { chunk.as_ref().end } {
+            return false;
+        }
+        self.pos = end;
+        self.allocated = self.allocated - old_size + new_size;
+        true
+    }
+
+    fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.current = match checkpoint.chunk {
+            0 => self.first,
+            c => Unique::new(c as *mut Chunk),
+        };
+        self.pos = match (checkpoint.chunk, self.current) {
+            (0, Some(first)) => Chunk::start(first),
+            _ => checkpoint.pos,
+        };
+        self.allocated = checkpoint.allocated;
+        self.last = 0;
+    }
+}
+
+/// Allocator which hands out memory by bumping a pointer and frees it all at once.
+pub struct BumpArena<S: MemorySource = MmapSource> {
+    inner: Mutex<Inner<S>>,
+}
+
+impl BumpArena<MmapSource> {
+    /// Creates an arena backed by a private memory region of at most `capacity` bytes.
+    #[must_use]
+    pub const fn new(capacity: usize) -> Self {
+        Self::with_source(MmapSource::new(capacity), BUMP_DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl<S: MemorySource> BumpArena<S> {
+    /// Creates an arena which requests chunks of at least `chunk_size` bytes from `source`.
+    /// Memory is only given back to `source` when the arena is dropped.
+    #[must_use]
+    pub const fn with_source(source: S, chunk_size: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                source,
+                chunk_size,
+                first: None,
+                current: None,
+                pos: 0,
+                allocated: 0,
+                last: 0,
+            }),
+        }
+    }
+
+    /// Returns the number of bytes handed out since the last reset.
+    pub fn allocated(&self) -> usize {
+        self.inner.lock().allocated
+    }
+
+    /// Returns the current position, allocations made afterwards can be freed with `rollback`.
+    pub fn checkpoint(&self) -> Checkpoint {
+        let inner = self.inner.lock();
+        Checkpoint {
+            chunk: inner.current.map_or(0, |c| c.as_ptr() as usize),
+            pos: inner.pos,
+            allocated: inner.allocated,
+        }
+    }
+
+    /// Frees all allocations made after `checkpoint` was taken.
+    ///
+    /// # Safety
+    ///
+    /// Memory allocated after `checkpoint` must not be used anymore and
+    /// `checkpoint` must have been taken from this arena after the last `reset`.
+    pub unsafe fn rollback(&self, checkpoint: Checkpoint) {
+        self.inner.lock().rewind(checkpoint);
+    }
+
+    /// Frees all allocations, the chunks are kept for reuse.
+    ///
+    /// # Safety
+    ///
+    /// Memory allocated from this arena must not be used anymore.
+    pub unsafe fn reset(&self) {
+        self.inner.lock().rewind(Checkpoint {
+            chunk: 0,
+            pos: 0,
+            allocated: 0,
+        });
+    }
+}
+
+impl<S: MemorySource> Drop for BumpArena<S> {
+    /// Returns all chunks to the memory source.
+    /// Sources like `DataSegment` only take back the block at their end,
+    /// so the chunk list is walked until no more chunks can be released.
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        unsafe {
+            loop {
+                let mut released = false;
+                let mut prev: Option<Unique<Chunk>> = None;
+                let mut next = inner.first;
+                while let Some(chunk) = next {
+                    next = chunk.as_ref().next;
+                    let region = Unique::new_unchecked(chunk.as_ptr().cast::<u8>());
+                    let block = match BlockPtr::from_mem_region(region) {
+                        Some(b) => b,
+                        None => continue,
+                    };
+                    if inner.source.release(block) {
+                        match prev {
+                            Some(mut p) => p.as_mut().next = next,
+                            None => inner.first = next,
+                        }
+                        released = true;
+                    } else {
+                        prev = Some(chunk);
+                    }
+                }
+                if !released {
+                    break;
+                }
+            }
+            inner.source.destroy();
+        }
+    }
+}
+
+unsafe impl<S: MemorySource> GlobalAlloc for BumpArena<S> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() == 0 {
+            return null_mut();
+        }
+        self.inner.lock().alloc(layout)
+    }
+
+    /// Individual frees are ignored.
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let mut inner = self.inner.lock();
+        if inner.resize_in_place(ptr, layout.size(), new_size) {
+            return ptr;
+        }
+        let new_ptr = inner.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+        }
+        new_ptr
+    }
+}
+
+unsafe impl<S: MemorySource> AllocRef for &BumpArena<S> {
+    fn alloc(&mut self, layout: Layout, init: AllocInit) -> Result<MemoryBlock, AllocErr> {
+        let ptr = if layout.size() == 0 {
+            layout.align() as *mut u8
+        } else {
+            self.inner.lock().alloc(layout)
+        };
+        let memory = MemoryBlock {
+            ptr: NonNull::new(ptr).ok_or(AllocErr)?,
+            size: layout.size(),
+        };
+        // SAFETY: memory denotes a valid block of memory
+        unsafe { init.init(memory) };
+        Ok(memory)
+    }
+
+    /// Individual frees are ignored.
+    unsafe fn dealloc(&mut self, _ptr: NonNull<u8>, _layout: Layout) {}
+
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+        placement: ReallocPlacement,
+        init: AllocInit,
+    ) -> Result<MemoryBlock, AllocErr> {
+        debug_assert!(new_size >= layout.size());
+        let memory = if self
+            .inner
+            .lock()
+            .resize_in_place(ptr.as_ptr(), layout.size(), new_size)
+        {
+            MemoryBlock {
+                ptr,
+                size: new_size,
+            }
+        } else if placement == ReallocPlacement::MayMove {
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            let memory = AllocRef::alloc(self, new_layout, AllocInit::Uninitialized)?;
+            ptr::copy_nonoverlapping(ptr.as_ptr(), memory.ptr.as_ptr(), layout.size());
+            memory
+        } else {
+            return Err(AllocErr);
+        };
+        init.init_offset(memory, layout.size());
+        Ok(memory)
+    }
+
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+        _placement: ReallocPlacement,
+    ) -> Result<MemoryBlock, AllocErr> {
+        debug_assert!(new_size <= layout.size());
+        self.inner
+            .lock()
+            .resize_in_place(ptr.as_ptr(), layout.size(), new_size);
+        Ok(MemoryBlock {
+            ptr,
+            size: new_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).expect("unable to create layout")
+    }
+
+    #[test]
+    fn test_bump_alloc() {
+        unsafe {
+            let arena = BumpArena::new(1 << 20);
+            let ptr1 = arena.alloc(layout(10, 1));
+            let ptr2 = arena.alloc(layout(8, 8));
+            let ptr3 = arena.alloc(layout(1, 64));
+            assert!(!ptr1.is_null() && !ptr2.is_null() && !ptr3.is_null());
+            assert_eq!(ptr2 as usize % 8, 0);
+            assert_eq!(ptr3 as usize % 64, 0);
+            assert_eq!(ptr2 as usize - ptr1 as usize, 16);
+            assert_eq!(arena.allocated(), 19);
+            // Frees don't return memory
+            arena.dealloc(ptr3, layout(1, 64));
+            assert_eq!(arena.allocated(), 19);
+        }
+    }
+
+    #[test]
+    fn test_bump_chunks() {
+        unsafe {
+            let arena = BumpArena::with_source(MmapSource::new(1 << 20), 4096);
+            let first = arena.alloc(layout(4000, 16));
+            // Doesn't fit into the first chunk anymore
+            let second = arena.alloc(layout(4000, 16));
+            // Larger than a chunk
+            let large = arena.alloc(layout(10000, 16));
+            assert!(!first.is_null() && !second.is_null() && !large.is_null());
+            large.write_bytes(1, 10000);
+
+            arena.reset();
+            assert_eq!(arena.allocated(), 0);
+            // Retained chunks are reused in order
+            assert_eq!(arena.alloc(layout(4000, 16)), first);
+            assert_eq!(arena.alloc(layout(4000, 16)), second);
+            assert_eq!(arena.alloc(layout(10000, 16)), large);
+        }
+    }
+
+    #[test]
+    fn test_bump_checkpoint() {
+        unsafe {
+            let arena = BumpArena::with_source(MmapSource::new(1 << 20), 4096);
+            let start = arena.checkpoint();
+            let ptr = arena.alloc(layout(100, 16));
+            let checkpoint = arena.checkpoint();
+            let ptr2 = arena.alloc(layout(3000, 16));
+            arena.alloc(layout(3000, 16));
+            assert_eq!(arena.allocated(), 6100);
+
+            arena.rollback(checkpoint);
+            assert_eq!(arena.allocated(), 100);
+            assert_eq!(arena.alloc(layout(3000, 16)), ptr2);
+
+            arena.rollback(start);
+            assert_eq!(arena.allocated(), 0);
+            assert_eq!(arena.alloc(layout(100, 16)), ptr);
+        }
+    }
+
+    #[test]
+    fn test_bump_realloc() {
+        unsafe {
+            let arena = BumpArena::new(1 << 20);
+            let ptr = arena.alloc(layout(16, 8));
+            ptr.write_bytes(7, 16);
+            // The most recent allocation grows in-place
+            assert_eq!(arena.realloc(ptr, layout(16, 8), 64), ptr);
+            let other = arena.alloc(layout(16, 8));
+            let moved = arena.realloc(ptr, layout(64, 8), 128);
+            assert_ne!(moved, ptr);
+            assert!(moved as usize > other as usize);
+            assert_eq!(*moved.add(15), 7);
+        }
+    }
+
+    #[test]
+    fn test_bump_alloc_ref() {
+        unsafe {
+            let arena = BumpArena::new(1 << 20);
+            let mut a = &arena;
+            let memory = AllocRef::alloc(&mut a, layout(32, 8), AllocInit::Zeroed)
+                .expect("unable to allocate");
+            assert_eq!(memory.size, 32);
+            memory.ptr.as_ptr().write_bytes(1, 32);
+            let grown = a
+                .grow(
+                    memory.ptr,
+                    layout(32, 8),
+                    64,
+                    ReallocPlacement::InPlace,
+                    AllocInit::Zeroed,
+                )
+                .expect("unable to grow in place");
+            assert_eq!(grown.ptr, memory.ptr);
+            assert_eq!(*grown.ptr.as_ptr().add(31), 1);
+            assert_eq!(*grown.ptr.as_ptr().add(32), 0);
+            let shrunk = a
+                .shrink(grown.ptr, layout(64, 8), 16, ReallocPlacement::InPlace)
+                .expect("unable to shrink");
+            assert_eq!(shrunk.size, 16);
+            assert_eq!(arena.allocated(), 16);
+            AllocRef::dealloc(&mut a, shrunk.ptr, layout(16, 8));
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Placement policies choosing the free block used for an allocation.
+use crate::alloc::block::BlockPtr;
+use crate::util::Rng;
+
+/// Chooses a free block for an allocation.
+pub trait FitPolicy {
+    /// Returns a suitable block out of `free` for `size` bytes or `None` if there is none.
+    /// Free blocks are passed in ascending address order.
+    fn find<I: Iterator<Item = BlockPtr>>(&mut self, free: I, size: usize) -> Option<BlockPtr>;
+
+    /// Returns `true` if `find` picks the smallest suitable block with the lowest address.
+    /// Large blocks are then looked up in a size index instead of calling `find`.
+    fn best_fit(&self) -> bool {
+        false
+    }
+}
+
+/// Returns `true` if `block` can hold `size` bytes.
+/// Space which is too small to be split off stays part of the block.
+#[inline]
+pub fn fits(block: BlockPtr, size: usize) -> bool {
+    block.size() >= size
+}
+
+/// Uses the first suitable block at the lowest address.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FirstFit;
+
+impl FitPolicy for FirstFit {
+    fn find<I: Iterator<Item = BlockPtr>>(&mut self, mut free: I, size: usize) -> Option<BlockPtr> {
+        free.find(|b| fits(*b, size))
+    }
+}
+
+/// Continues the search at the address of the previously chosen block
+/// and wraps around to the lowest address.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NextFit {
+    /// Address where the next search starts.
+    rover: usize,
+}
+
+impl NextFit {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { rover: 0 }
+    }
+}
+
+impl FitPolicy for NextFit {
+    fn find<I: Iterator<Item = BlockPtr>>(&mut self, free: I, size: usize) -> Option<BlockPtr> {
+        let mut wrapped = None;
+        for block in free.filter(|b| fits(*b, size)) {
+            if block.as_ptr() as usize >= self.rover {
+                self.rover = block.as_ptr() as usize;
+                return Some(block);
+            }
+            wrapped = wrapped.or(Some(block));
+        }
+        let block = wrapped?;
+        self.rover = block.as_ptr() as usize;
+        Some(block)
+    }
+}
+
+/// Uses the smallest suitable block, ties are resolved by the lowest address.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BestFit;
+
+impl FitPolicy for BestFit {
+    fn best_fit(&self) -> bool {
+        true
+    }
+
+    fn find<I: Iterator<Item = BlockPtr>>(&mut self, free: I, size: usize) -> Option<BlockPtr> {
+        let mut best: Option<BlockPtr> = None;
+        for block in free.filter(|b| fits(*b, size)) {
+            if block.size() == size {
+                return Some(block);
+            }
+            if best.map_or(true, |b| block.size() < b.size()) {
+                best = Some(block);
+            }
+        }
+        best
+    }
+}
+
+/// Uses the first block wasting at most `1 / 2^shift` of the requested size,
+/// falls back to best fit if there is no such block.
+#[derive(Copy, Clone, Debug)]
+pub struct GoodFit {
+    shift: u32,
+}
+
+impl GoodFit {
+    /// Creates a policy accepting blocks up to `size + (size >> shift)` bytes.
+    #[must_use]
+    pub const fn new(shift: u32) -> Self {
+        Self { shift }
+    }
+}
+
+impl Default for GoodFit {
+    /// Accepts 12.5% of waste.
+    fn default() -> Self {
+        Self::new(3)
+    }
+}
+
+impl FitPolicy for GoodFit {
+    fn find<I: Iterator<Item = BlockPtr>>(&mut self, free: I, size: usize) -> Option<BlockPtr> {
+        let limit = size.saturating_add(size >> self.shift);
+        let mut best: Option<BlockPtr> = None;
+        for block in free.filter(|b| fits(*b, size)) {
+            if block.size() <= limit {
+                return Some(block);
+            }
+            if best.map_or(true, |b| block.size() < b.size()) {
+                best = Some(block);
+            }
+        }
+        best
+    }
+}
+
+/// Uses the largest block, which leaves the largest remainder after splitting.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WorstFit;
+
+impl FitPolicy for WorstFit {
+    fn find<I: Iterator<Item = BlockPtr>>(&mut self, free: I, size: usize) -> Option<BlockPtr> {
+        let mut worst: Option<BlockPtr> = None;
+        for block in free.filter(|b| fits(*b, size)) {
+            if worst.map_or(true, |b| block.size() > b.size()) {
+                worst = Some(block);
+            }
+        }
+        worst
+    }
+}
+
+/// Number of suitable blocks `RandomFit` chooses from.
+const RANDOM_FIT_CANDIDATES: usize = 8;
+
+/// Uses one of the first suitable blocks at random, which makes the placement
+/// of allocations hard to predict.
+#[derive(Copy, Clone, Debug)]
+pub struct RandomFit {
+    rng: Rng,
+}
+
+impl RandomFit {
+    #[must_use]
+    pub const fn new(rng: Rng) -> Self {
+        Self { rng }
+    }
+}
+
+impl FitPolicy for RandomFit {
+    fn find<I: Iterator<Item = BlockPtr>>(&mut self, free: I, size: usize) -> Option<BlockPtr> {
+        // Reservoir sampling, each candidate is chosen with equal probability
+        let mut chosen = None;
+        let candidates = free.filter(|b| fits(*b, size)).take(RANDOM_FIT_CANDIDATES);
+        for (i, block) in candidates.enumerate() {
+            if self.rng.below(i + 1) == 0 {
+                chosen = Some(block);
+            }
+        }
+        chosen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::block::BLOCK_META_SIZE;
+    use core::ptr::Unique;
+    use std::vec::Vec;
+
+    /// Creates adjacent blocks spanning the given sizes including their header in a buffer.
+    fn blocks(buf: &mut [u128], sizes: &[usize]) -> Vec<BlockPtr> {
+        let mut offset = BLOCK_META_SIZE;
+        let mut blocks = Vec::new();
+        for size in sizes {
+            let ptr = unsafe { Unique::new_unchecked(buf.as_mut_ptr().cast::<u8>().add(offset)) };
+            blocks.push(BlockPtr::new(ptr, size - BLOCK_META_SIZE));
+            offset += size;
+        }
+        blocks
+    }
+
+    /// Returns the index of the block chosen by `policy`.
+    fn find(policy: &mut impl FitPolicy, free: &[BlockPtr], size: usize) -> Option<usize> {
+        let block = policy.find(free.iter().copied(), size)?;
+        free.iter().position(|b| *b == block)
+    }
+
+    #[test]
+    fn test_policies() {
+        let mut buf = [0_u128; 256];
+        let free = blocks(&mut buf, &[64, 512, 176, 1024, 160, 128]);
+        assert_eq!(find(&mut FirstFit, &free, 96), Some(1));
+        assert_eq!(find(&mut BestFit, &free, 96), Some(5));
+        assert_eq!(find(&mut BestFit, &free, 120), Some(5));
+        assert_eq!(find(&mut WorstFit, &free, 96), Some(3));
+        assert_eq!(find(&mut GoodFit::new(0), &free, 96), Some(2));
+        // No block is good enough, falls back to best fit
+        assert_eq!(find(&mut GoodFit::new(6), &free, 96), Some(5));
+        assert_eq!(find(&mut FirstFit, &free, 2048), None);
+    }
+
+    #[test]
+    fn test_next_fit() {
+        let mut buf = [0_u128; 256];
+        let free = blocks(&mut buf, &[256, 64, 256, 256]);
+        let mut policy = NextFit::new();
+        assert_eq!(policy.find(free.iter().copied(), 128), Some(free[0]));
+        // The chosen block has been removed, the search continues behind it
+        assert_eq!(policy.find(free[1..].iter().copied(), 128), Some(free[2]));
+        assert_eq!(policy.find(free.iter().copied(), 128), Some(free[2]));
+        assert_eq!(policy.find(free[..2].iter().copied(), 128), Some(free[0]));
+    }
+
+    #[test]
+    fn test_random_fit() {
+        let mut buf = [0_u128; 256];
+        let free = blocks(&mut buf, &[64, 256, 64, 256, 256, 64]);
+        let mut policy = RandomFit::new(Rng::new(42));
+        let mut chosen = [0; 6];
+        for _ in 0..100 {
+            chosen[find(&mut policy, &free, 128).expect("no block found")] += 1;
+        }
+        assert_eq!(chosen[0] + chosen[2] + chosen[5], 0);
+        assert!(chosen[1] > 0 && chosen[3] > 0 && chosen[4] > 0);
+        assert_eq!(find(&mut policy, &free, 512), None);
+    }
+}
@@ -0,0 +1,377 @@
+//! Guard page allocator for debugging, similar to Electric Fence.
+//!
+//! Every allocation gets its own pages next to an inaccessible guard page, so out of bounds
+//! accesses fault immediately. Freed pages are made inaccessible and never reused, which turns
+//! use-after-free into a fault as well. The size of each allocation is stored out-of-band
+//! in a table with one entry per page, so invalid pointers and double frees are detected
+//! without touching protected memory.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use spin::Mutex;
+
+use crate::alloc::options::GuardMode;
+use crate::sources::PAGE_SIZE;
+use crate::util;
+
+/// Reserved address space for guarded allocations, freed pages are never reused.
+#[cfg(target_pointer_width = "64")]
+pub const GUARD_REGION_SIZE: usize = 16 << 30;
+/// Reserved address space for guarded allocations, freed pages are never reused.
+#[cfg(not(target_pointer_width = "64"))]
+pub const GUARD_REGION_SIZE: usize = 256 << 20;
+
+/// Marks table entries of allocations in `GuardMode::Underflow`.
+const ENTRY_UNDERFLOW: usize = 1;
+
+struct GuardState {
+    /// Start of the reserved pages, 0 if not yet reserved.
+    base: usize,
+    /// Table with one entry per page, holding `size << 1 | underflow`
+    /// for the page containing the start of an allocation, 0 otherwise.
+    table: usize,
+    /// Index of the next page which has never been used.
+    next_page: usize,
+    allocations: usize,
+    /// Set once the exhausted region has been reported.
+    exhausted: bool,
+}
+
+impl GuardState {
+    #[inline]
+    fn pages() -> usize {
+        GUARD_REGION_SIZE / *PAGE_SIZE
+    }
+
+    #[inline]
+    fn entry(&mut self, page: usize) -> &mut usize {
+        debug_assert!(page < Self::pages());
+        // SAFETY: the table spans all pages of the reserved region
+        unsafe { &mut *(self.table as *mut usize).add(page) }
+    }
+
+    #[inline]
+    fn page_addr(&self, page: usize) -> usize {
+        self.base + page * *PAGE_SIZE
+    }
+
+    unsafe fn reserve(&mut self) -> Option<usize> {
+        let table = libc::mmap(
+            null_mut(),
+            Self::pages() * core::mem::size_of::<usize>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if table == libc::MAP_FAILED {
+            error!(Heap, "unable to reserve guard page table");
+            return None;
+        }
+        let base = libc::mmap(
+            null_mut(),
+            GUARD_REGION_SIZE,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if base == libc::MAP_FAILED {
+            error!(Heap, "unable to reserve guard page region");
+            libc::munmap(table, Self::pages() * core::mem::size_of::<usize>());
+            return None;
+        }
+        trace!(Heap, "reserved guard page region at {:p}", base);
+        self.table = table as usize;
+        self.base = base as usize;
+        Some(self.base)
+    }
+
+    /// Returns the number of data pages of an allocation, `None` on overflow.
+    #[inline]
+    fn data_pages(size: usize) -> Option<usize> {
+        let size = util::pad_to_align(size, *PAGE_SIZE).ok()?.size();
+        Some(size / *PAGE_SIZE)
+    }
+
+    unsafe fn alloc(
+        &mut self,
+        size: usize,
+        mode: GuardMode,
+        reserve: impl FnOnce(usize) -> bool,
+    ) -> *mut u8 {
+        // The size is stored shifted by one bit in the table
+        if size > usize::max_value() >> 1 {
+            return null_mut();
+        }
+        if self.base == 0 && self.reserve().is_none() {
+            return null_mut();
+        }
+        let pages = match Self::data_pages(size) {
+            Some(p) => p,
+            None => return null_mut(),
+        };
+        let end_page = self
+            .next_page
+            .checked_add(pages)
+            .and_then(|p| p.checked_add(1));
+        if end_page.map_or(true, |p| p > Self::pages()) {
+            if !self.exhausted {
+                warn!(
+                    Heap,
+                    "guard page region exhausted, allocations are no longer guarded"
+                );
+                self.exhausted = true;
+            }
+            return null_mut();
+        }
+        let (data, ptr, entry) = match mode {
+            GuardMode::Underflow => {
+                let data = self.next_page + 1;
+                (data, self.page_addr(data), size << 1 | ENTRY_UNDERFLOW)
+            }
+            _ => {
+                let data = self.next_page;
+                let end = self.page_addr(data + pages);
+                (data, end - util::min_align_unchecked(size), size << 1)
+            }
+        };
+        let addr = self.page_addr(data) as *mut libc::c_void;
+        let len = pages * *PAGE_SIZE;
+        if libc::mprotect(addr, len, libc::PROT_READ | libc::PROT_WRITE) != 0 {
+            error!(Heap, "unable to map {} guarded pages", pages);
+            return null_mut();
+        }
+        if !reserve(len) {
+            libc::mprotect(addr, len, libc::PROT_NONE);
+            return null_mut();
+        }
+        self.next_page += pages + 1;
+        *self.entry(data) = entry;
+        self.allocations += 1;
+        ptr as *mut u8
+    }
+
+    /// Returns the first data page, the size and the number of data pages of an allocation,
+    /// `None` for invalid pointers.
+    fn locate(&mut self, ptr: *mut u8) -> Option<(usize, usize, usize)> {
+        let offset = (ptr as usize).checked_sub(self.base)?;
+        let page = offset / *PAGE_SIZE;
+        if page >= self.next_page {
+            return None;
+        }
+        let entry = *self.entry(page);
+        let size = entry >> 1;
+        if size == 0 {
+            return None;
+        }
+        let pages = Self::data_pages(size)?;
+        let expected = if entry & ENTRY_UNDERFLOW != 0 {
+            self.page_addr(page)
+        } else {
+            self.page_addr(page + pages) - util::min_align_unchecked(size)
+        };
+        if expected != ptr as usize {
+            return None;
+        }
+        Some((page, size, pages))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, release: impl FnOnce(usize)) -> bool {
+        let (page, _, pages) = match self.locate(ptr) {
+            Some(l) => l,
+            None => return false,
+        };
+        *self.entry(page) = 0;
+        self.allocations -= 1;
+        let addr = self.page_addr(page) as *mut libc::c_void;
+        let len = pages * *PAGE_SIZE;
+        libc::mprotect(addr, len, libc::PROT_NONE);
+        libc::madvise(addr, len, libc::MADV_DONTNEED);
+        release(len);
+        true
+    }
+}
+
+/// Allocator placing every allocation on its own pages next to a guard page.
+pub struct Guard {
+    /// Copy of `GuardState::base`, allows to check ownership without locking.
+    base: AtomicUsize,
+    /// Placement of new allocations as `GuardMode` discriminant, read without locking.
+    mode: AtomicUsize,
+    state: Mutex<GuardState>,
+}
+
+impl Guard {
+    pub const fn new() -> Self {
+        Self {
+            base: AtomicUsize::new(0),
+            mode: AtomicUsize::new(GuardMode::Disabled as usize),
+            state: Mutex::new(GuardState {
+                base: 0,
+                table: 0,
+                next_page: 0,
+                allocations: 0,
+                exhausted: false,
+            }),
+        }
+    }
+
+    /// Returns the placement of new allocations.
+    #[inline]
+    pub fn mode(&self) -> GuardMode {
+        match self.mode.load(Ordering::Relaxed) {
+            m if m == GuardMode::Overflow as usize => GuardMode::Overflow,
+            m if m == GuardMode::Underflow as usize => GuardMode::Underflow,
+            _ => GuardMode::Disabled,
+        }
+    }
+
+    pub fn set_mode(&self, mode: GuardMode) {
+        self.mode.store(mode as usize, Ordering::Relaxed);
+    }
+
+    /// Returns `true` if `ptr` points into the guard page region.
+    #[inline]
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let base = self.base.load(Ordering::Acquire);
+        base != 0 && (ptr as usize).wrapping_sub(base) < GUARD_REGION_SIZE
+    }
+
+    /// Returns zeroed memory of at least `size` bytes placed as specified by `mode`,
+    /// or null if the region is exhausted.
+    /// The data pages are charged with `reserve`, the allocation fails if it returns `false`.
+    pub fn alloc(
+        &self,
+        size: usize,
+        mode: GuardMode,
+        reserve: impl FnOnce(usize) -> bool,
+    ) -> *mut u8 {
+        debug_assert!(size > 0 && mode != GuardMode::Disabled);
+        let mut state = self.state.lock();
+        // SAFETY: we know it is thread safe, because we're locking the mutex
+        let ptr = unsafe { state.alloc(size, mode, reserve) };
+        self.base.store(state.base, Ordering::Release);
+        ptr
+    }
+
+    /// Makes the pages of an allocation inaccessible and passes their size to `release`.
+    /// Returns `false` for invalid pointers and double frees.
+    pub fn dealloc(&self, ptr: *mut u8, release: impl FnOnce(usize)) -> bool {
+        debug_assert!(self.contains(ptr));
+        // SAFETY: we know it is thread safe, because we're locking the mutex
+        unsafe { self.state.lock().dealloc(ptr, release) }
+    }
+
+    /// Returns the usable size of the allocation at `ptr`, 0 for invalid pointers.
+    pub fn usable_size(&self, ptr: *mut u8) -> usize {
+        self.state
+            .lock()
+            .locate(ptr)
+            .map_or(0, |(_, size, _)| util::min_align_unchecked(size))
+    }
+
+    /// Returns the number of live allocations.
+    pub fn allocations(&self) -> usize {
+        self.state.lock().allocations
+    }
+
+    /// Holds the lock until `unlock` is called, e.g. while forking.
+    pub fn lock(&self) {
+        util::hold(&self.state);
+    }
+
+    /// Releases the lock acquired by `lock`.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called after `lock`.
+    pub unsafe fn unlock(&self) {
+        self.state.force_unlock();
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let state = self.state.lock();
+        if state.base != 0 {
+            // SAFETY: allocations can't be used after the owning allocator has been dropped
+            unsafe {
+                libc::munmap(state.base as *mut libc::c_void, GUARD_REGION_SIZE);
+                libc::munmap(
+                    state.table as *mut libc::c_void,
+                    GuardState::pages() * core::mem::size_of::<usize>(),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard_mode() {
+        let guard = Guard::new();
+        assert_eq!(guard.mode(), GuardMode::Disabled);
+        guard.set_mode(GuardMode::Underflow);
+        assert_eq!(guard.mode(), GuardMode::Underflow);
+    }
+
+    #[test]
+    fn test_guard_overflow() {
+        let guard = Guard::new();
+        let ptr = guard.alloc(100, GuardMode::Overflow, |_| true);
+        assert!(!ptr.is_null());
+        assert!(guard.contains(ptr));
+        assert_eq!(ptr as usize % crate::MIN_ALIGN, 0);
+        // The memory region ends at a page boundary
+        assert_eq!((ptr as usize + 112) % *PAGE_SIZE, 0);
+        assert_eq!(guard.usable_size(ptr), 112);
+        unsafe { ptr.write_bytes(1, 112) };
+        assert_eq!(guard.allocations(), 1);
+
+        assert_eq!(guard.usable_size(unsafe { ptr.add(16) }), 0);
+        assert!(guard.dealloc(ptr, |_| ()));
+        // Double free is detected without touching the protected pages
+        assert!(!guard.dealloc(ptr, |_| ()));
+        assert_eq!(guard.usable_size(ptr), 0);
+        assert_eq!(guard.allocations(), 0);
+    }
+
+    #[test]
+    fn test_guard_underflow() {
+        let guard = Guard::new();
+        let ptr = guard.alloc(5000, GuardMode::Underflow, |_| true);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % *PAGE_SIZE, 0);
+        let slice = unsafe { core::slice::from_raw_parts(ptr, 5008) };
+        assert!(slice.iter().all(|b| *b == 0));
+        let ptr2 = guard.alloc(16, GuardMode::Overflow, |_| true);
+        // Pages are never reused
+        assert!(guard.dealloc(ptr, |_| ()));
+        let ptr3 = guard.alloc(5000, GuardMode::Underflow, |_| true);
+        assert!(ptr3 > ptr2);
+        assert!(guard.dealloc(ptr2, |_| ()));
+        assert!(guard.dealloc(ptr3, |_| ()));
+    }
+
+    #[test]
+    fn test_guard_overflowing_size() {
+        let guard = Guard::new();
+        for size in &[
+            usize::max_value(),
+            usize::max_value() >> 1,
+            GUARD_REGION_SIZE,
+        ] {
+            assert!(guard.alloc(*size, GuardMode::Overflow, |_| true).is_null());
+            assert!(guard.alloc(*size, GuardMode::Underflow, |_| true).is_null());
+        }
+        assert_eq!(guard.allocations(), 0);
+        // The region is still usable
+        let ptr = guard.alloc(100, GuardMode::Overflow, |_| true);
+        assert!(!ptr.is_null());
+        assert!(guard.dealloc(ptr, |_| ()));
+    }
+}
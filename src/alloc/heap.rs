@@ -1,72 +1,731 @@
-use libc_print::libc_eprintln;
+use core::{cmp, intrinsics, mem};
 
-use crate::alloc::block::BlockPtr;
+use crate::alloc::block::{BlockPtr, BLOCK_META_SIZE};
+use crate::alloc::buddy::{BuddyAllocator, BUDDY_REGION_SIZE};
+use crate::alloc::fit::{FirstFit, FitPolicy, RandomFit};
 use crate::alloc::list::IntrusiveList;
+use crate::alloc::options::{Backend, Options};
+use crate::alloc::quarantine::Quarantine;
+use crate::alloc::stats::{self, HeapStats};
 use crate::sources::{DataSegment, MemorySource};
+use crate::util::{self, Rng};
+use crate::MIN_ALIGN;
 
-pub struct Heap {
+/// Upper bound of the random gap in front of the first block if `options.randomize` is set.
+const RANDOM_BASE_OFFSET: usize = 64 << 10;
+
+pub struct Heap<S: MemorySource, F: FitPolicy> {
     pub list: IntrusiveList,
-    source: DataSegment,
+    /// Freed blocks which are not yet reusable.
+    quarantine: Quarantine,
+    /// Chooses the block popped from `list`.
+    policy: F,
+    /// Replaces `policy` if `options.randomize` is set.
+    random: Option<RandomFit>,
+    buddy: BuddyAllocator,
+    pub options: Options,
+    source: S,
+    /// Bytes currently obtained from `source` for `list`.
+    size: usize,
+    /// Bytes committed outside of `source`, i.e. slab pages and guarded allocations.
+    external: usize,
+    peak_size: usize,
+    /// Number of blocks handed out to the user.
+    used_blocks: usize,
+    /// Usable bytes of all blocks handed out to the user.
+    used_bytes: usize,
+    /// Set while more than `options.soft_limit` bytes are obtained from `source`.
+    pressure: bool,
+    /// Set if a request has been refused once because the soft limit has been crossed.
+    soft_limit_hit: bool,
 }
 
-impl Heap {
+impl Heap<DataSegment, FirstFit> {
     pub const fn new() -> Self {
+        Self::with_policy(DataSegment::new(), FirstFit)
+    }
+}
+
+impl<S: MemorySource, F: FitPolicy> Heap<S, F> {
+    pub const fn with_policy(source: S, policy: F) -> Self {
         Self {
             list: IntrusiveList::new(),
-            source: DataSegment,
+            quarantine: Quarantine::new(),
+            policy,
+            random: None,
+            buddy: BuddyAllocator::new(),
+            options: Options::new(),
+            source,
+            size: 0,
+            external: 0,
+            peak_size: 0,
+            used_blocks: 0,
+            used_bytes: 0,
+            pressure: false,
+            soft_limit_hit: false,
+        }
+    }
+
+    /// Replaces the runtime configuration, quarantined blocks are released beforehand.
+    /// Enabling `randomize` before the first request also randomizes the heap base.
+    ///
+    /// # Safety
+    ///
+    /// Function is not thread safe.
+    pub unsafe fn configure(&mut self, options: Options) {
+        while let Some(block) = self.quarantine.pop() {
+            self.evict(block);
+        }
+        self.options = options;
+        self.list.set_poison(options.poison);
+        if !options.randomize {
+            self.random = None;
+        } else if self.random.is_none() {
+            let mut rng = Rng::from_entropy();
+            let offset = rng.below(RANDOM_BASE_OFFSET / MIN_ALIGN) * MIN_ALIGN;
+            self.source.set_base_offset(offset);
+            self.random = Some(RandomFit::new(rng));
+        }
+    }
+
+    /// Returns a `BlockPtr` of at least the given size to be handed out to the user.
+    /// Excess space is split off and released if possible.
+    ///
+    /// # Safety
+    ///
+    /// Function is not thread safe.
+    pub unsafe fn alloc(&mut self, size: usize) -> Option<BlockPtr> {
+        self.alloc_fresh(size).map(|(block, _)| block)
+    }
+
+    /// Like `alloc`, additionally returns `true` if the block has just been obtained
+    /// from a memory source which hands out zeroed memory, so clearing it can be skipped.
+    ///
+    /// # Safety
+    ///
+    /// Function is not thread safe.
+    pub unsafe fn alloc_fresh(&mut self, size: usize) -> Option<(BlockPtr, bool)> {
+        let block = match self.options.backend {
+            Backend::Buddy => self.alloc_buddy(size),
+            Backend::List => None,
+        };
+        let (block, fresh) = match block {
+            Some(b) => (b, false),
+            // Let the caller handle the soft limit before falling back to the list
+            None if self.soft_limit_hit => return None,
+            None => {
+                let (mut block, fresh) = self.request(size)?;
+                if let Some(rem_block) = self.split(&mut block, size) {
+                    // Requested blocks are poisoned already
+                    self.insert(rem_block, true);
+                }
+                block.mark(true);
+                (block, fresh)
+            }
+        };
+        self.used_blocks += 1;
+        self.used_bytes += block.size();
+        Some((block, fresh))
+    }
+
+    /// Takes back a `BlockPtr` which has been handed out by `alloc`.
+    ///
+    /// # Safety
+    ///
+    /// Function is not thread safe.
+    pub unsafe fn dealloc(&mut self, block: BlockPtr) {
+        if self.buddy.contains(block) {
+            self.dealloc_buddy(block);
+            return;
+        }
+        if !self.source.contains(block) {
+            error!(Heap, "{:p} has not been allocated by this heap", block);
+            return;
+        }
+        if !block.as_ref().in_use() {
+            error!(Heap, "double free detected for {:p}", block);
+            if self.options.abort_on_corruption {
+                intrinsics::abort();
+            }
+            return;
+        }
+        let size = block.size();
+        if self.retire(block) {
+            self.used_blocks -= 1;
+            self.used_bytes -= size;
+        }
+    }
+
+    /// Puts a freed block into quarantine if enabled, otherwise releases it.
+    /// The oldest blocks are released once the quarantine exceeds its limits.
+    unsafe fn retire(&mut self, block: BlockPtr) -> bool {
+        let (max_bytes, max_blocks) =
+            (self.options.quarantine_size, self.options.quarantine_blocks);
+        if block.size() > max_bytes || max_blocks == 0 {
+            return self.release(block);
+        }
+        block.mark(false);
+        self.list.fill_poison(block);
+        self.quarantine.push(block);
+        while self.quarantine.bytes > max_bytes || self.quarantine.blocks > max_blocks {
+            match self.quarantine.pop() {
+                Some(evicted) => self.evict(evicted),
+                None => break,
+            }
+        }
+        true
+    }
+
+    /// Releases a block leaving the quarantine after verifying its poison.
+    unsafe fn evict(&mut self, block: BlockPtr) {
+        trace!(Heap, "evict {} at {:p}", block.as_ref(), block);
+        let intact = self.list.check_poison(block, block.size());
+        if !intact && self.options.abort_on_corruption {
+            intrinsics::abort();
+        }
+        // Corrupted blocks are poisoned again to report them only once
+        self.insert(block, intact);
+    }
+
+    /// Shrinks a `BlockPtr` which has been handed out by `alloc` in-place
+    /// and releases the remaining space if possible.
+    ///
+    /// # Safety
+    ///
+    /// Function is not thread safe.
+    pub unsafe fn shrink(&mut self, block: &mut BlockPtr, size: usize) {
+        let old_size = block.size();
+        if self.buddy.contains(*block) {
+            self.buddy.shrink(block, size);
+            self.used_bytes -= old_size - block.size();
+            return;
         }
+        if let Some(rem_block) = self.split(block, size) {
+            self.used_bytes -= old_size - block.size();
+            self.release(rem_block);
+        }
+    }
+
+    /// Allocates a block from the buddy allocator,
+    /// returns `None` if the size is too large or its regions are exhausted.
+    unsafe fn alloc_buddy(&mut self, size: usize) -> Option<BlockPtr> {
+        if !self.buddy.has_free(size) && !self.reserve(BUDDY_REGION_SIZE) {
+            return None;
+        }
+        let block = self.buddy.alloc(&mut self.source, size)?;
+        self.peak_size = cmp::max(self.peak_size, self.total_size());
+        Some(block)
+    }
+
+    unsafe fn dealloc_buddy(&mut self, block: BlockPtr) {
+        let size = block.size();
+        let trim = BUDDY_REGION_SIZE >= self.options.trim_threshold;
+        if self.buddy.dealloc(&mut self.source, block, trim).is_err() {
+            error!(
+                Heap,
+                "double free or invalid block detected for {:p}", block
+            );
+            if self.options.abort_on_corruption {
+                intrinsics::abort();
+            }
+            return;
+        }
+        self.used_blocks -= 1;
+        self.used_bytes -= size;
+    }
+
+    /// Splits off the space exceeding `size` if the remainder is at least `options.split_min_size`.
+    #[inline]
+    fn split(&self, block: &mut BlockPtr, size: usize) -> Option<BlockPtr> {
+        let rem_size = block.size().checked_sub(size + BLOCK_META_SIZE)?;
+        if rem_size < self.options.split_min_size {
+            return None;
+        }
+        block.shrink(size)
     }
 
     /// Requests and returns a suitable empty `BlockPtr` for the given size.
-    /// This can be either a reused empty block or a new one requested from kernel.
+    /// This can be either a reused empty block or a new one requested from kernel,
+    /// the latter is flagged with `true` if the memory source hands out zeroed memory.
+    ///
+    /// # Safety
+    ///
+    /// Function is not thread safe.
+    pub unsafe fn request(&mut self, size: usize) -> Option<(BlockPtr, bool)> {
+        match self.reuse(size) {
+            Some(block) => Some((block, false)),
+            None => self.grow(size),
+        }
+    }
+
+    /// Pops a suitable block from the free list.
+    /// A detected write-after-free aborts if `options.abort_on_corruption` is set.
+    unsafe fn reuse(&mut self, size: usize) -> Option<BlockPtr> {
+        let errors = self.list.poison_errors;
+        let block = match self.random.as_mut() {
+            Some(random) => self.list.pop(size, random)?,
+            None => self.list.pop(size, &mut self.policy)?,
+        };
+        trace!(Heap, "pop {} at {:p}", block.as_ref(), block);
+        if self.list.poison_errors != errors && self.options.abort_on_corruption {
+            intrinsics::abort();
+        }
+        Some(block)
+    }
+
+    /// Requests a new block from the memory source, flagged with `true` if it is zeroed.
+    /// The block is poisoned if poisoning is enabled.
+    unsafe fn grow(&mut self, size: usize) -> Option<(BlockPtr, bool)> {
+        let granularity = self.source.granularity();
+        let request = util::pad_to_align(size.checked_add(BLOCK_META_SIZE)?, granularity).ok()?;
+        if !self.reserve(request.size()) {
+            return None;
+        }
+        let block = self.source.request(size)?;
+        self.size += block.block_size();
+        self.peak_size = cmp::max(self.peak_size, self.total_size());
+        if self.list.poison() != 0 {
+            self.list.fill_poison(block);
+            return Some((block, false));
+        }
+        Some((block, self.source.zeroed()))
+    }
+
+    /// Returns the number of bytes obtained from the memory source and committed externally.
+    #[inline]
+    fn total_size(&self) -> usize {
+        self.size + self.buddy.size() + self.external
+    }
+
+    /// Returns `true` if `size` more bytes may be obtained from the memory source.
+    /// Crossing `options.soft_limit` trims the heap and refuses the request once,
+    /// which gives the caller the chance to run the out-of-memory handler before retrying.
+    unsafe fn reserve(&mut self, size: usize) -> bool {
+        let total = self.total_size().saturating_add(size);
+        if self.options.limit != 0 && total > self.options.limit {
+            info!(Heap, "limit of {} bytes reached", self.options.limit);
+            return false;
+        }
+        if self.options.soft_limit == 0 || total <= self.options.soft_limit {
+            self.pressure = false;
+            return true;
+        }
+        if self.pressure {
+            return true;
+        }
+        info!(
+            Heap,
+            "soft limit of {} bytes reached", self.options.soft_limit
+        );
+        self.pressure = true;
+        self.soft_limit_hit = true;
+        self.trim();
+        false
+    }
+
+    /// Charges `size` bytes committed outside of the memory source against the limits.
+    /// Returns `false` if they may not be committed, see `reserve`.
+    ///
+    /// # Safety
+    ///
+    /// Function is not thread safe.
+    pub unsafe fn reserve_external(&mut self, size: usize) -> bool {
+        if !self.reserve(size) {
+            return false;
+        }
+        self.external += size;
+        self.peak_size = cmp::max(self.peak_size, self.total_size());
+        true
+    }
+
+    /// Gives back `size` bytes charged by `reserve_external`.
+    pub fn release_external(&mut self, size: usize) {
+        debug_assert!(size <= self.external);
+        self.external -= size;
+    }
+
+    /// Returns `true` if a request has been refused by the soft limit
+    /// and the flag hasn't been taken yet.
+    #[inline]
+    pub fn soft_limit_hit(&self) -> bool {
+        self.soft_limit_hit
+    }
+
+    /// Returns `true` if the last failed request has been refused by the soft limit
+    /// and resets the flag. Retrying the request then exceeds the soft limit.
+    pub fn take_soft_limit_hit(&mut self) -> bool {
+        mem::take(&mut self.soft_limit_hit)
+    }
+
+    /// Releases quarantined blocks and returns the free block at the end of the heap
+    /// to the memory source, regardless of `options.trim_threshold`.
+    /// Returns the number of bytes given back.
     ///
     /// # Safety
     ///
     /// Function is not thread safe.
-    pub unsafe fn request(&mut self, size: usize) -> Option<BlockPtr> {
-        if let Some(block) = self.list.pop(size) {
-            dprintln!("[pop]: {} at {:p}", block.as_ref(), block);
-            return Some(block);
+    pub unsafe fn trim(&mut self) -> usize {
+        let size = self.size;
+        while let Some(block) = self.quarantine.pop() {
+            self.evict(block);
+        }
+        if let Some(block) = self.list.pop_tail() {
+            let block_size = block.block_size();
+            if self.source.release(block) {
+                self.size -= block_size;
+            } else {
+                // The block has just been removed, so reinserting it can't fail
+                let _ = self.list.insert(block);
+            }
         }
-        self.source.request(size)
+        trace!(Heap, "trimmed {} bytes", size - self.size);
+        size - self.size
     }
 
     /// Releases a given `BlockPtr` back to the allocator or kernel.
+    /// Returns `false` if a double free has been detected.
     ///
     /// # Safety
     ///
     /// Function is not thread safe.
-    pub unsafe fn release(&mut self, block: BlockPtr) {
+    pub unsafe fn release(&mut self, block: BlockPtr) -> bool {
+        self.insert(block, false)
+    }
+
+    /// Like `release`, `poisoned` skips filling blocks which are known to be poisoned.
+    unsafe fn insert(&mut self, block: BlockPtr, poisoned: bool) -> bool {
         #[cfg(feature = "debug")]
         self.list.debug();
 
-        if self.source.release(block) {
-            return;
+        block.mark(false);
+        let block_size = block.block_size();
+        if block.size() >= self.options.trim_threshold && self.source.release(block) {
+            self.size -= block_size;
+            return true;
+        }
+        if !poisoned {
+            self.list.fill_poison(block);
         }
 
-        dprintln!("[insert]: {} at {:p}", block.as_ref(), block);
+        trace!(Heap, "insert {} at {:p}", block.as_ref(), block);
         if self.list.insert(block).is_err() {
-            eprintln!("double free detected for ptr {:?}", block.mem_region());
+            error!(
+                Heap,
+                "double free detected for ptr {:?}",
+                block.mem_region()
+            );
+            if self.options.abort_on_corruption {
+                intrinsics::abort();
+            }
+            return false;
         }
+        true
+    }
+
+    /// Releases all memory of the memory source at once.
+    ///
+    /// # Safety
+    ///
+    /// All blocks handed out by this heap become invalid.
+    pub unsafe fn destroy(&mut self) {
+        self.list = IntrusiveList::new();
+        self.list.set_poison(self.options.poison);
+        self.quarantine = Quarantine::new();
+        self.buddy = BuddyAllocator::new();
+        self.source.destroy();
+        self.size = 0;
+        self.used_blocks = 0;
+        self.used_bytes = 0;
+        self.pressure = false;
+    }
+
+    /// Returns a snapshot of the current heap state.
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            heap_size: self.total_size(),
+            peak_heap_size: self.peak_size,
+            huge_page_bytes: self.source.huge_page_bytes(),
+            used_blocks: self.used_blocks,
+            used_bytes: self.used_bytes,
+            write_after_free: self.list.poison_errors,
+            quarantine_blocks: self.quarantine.blocks,
+            quarantine_bytes: self.quarantine.bytes,
+            ..HeapStats::default()
+        };
+        for block in self.list.iter().chain(self.buddy.iter()) {
+            stats.free_blocks += 1;
+            stats.free_bytes += block.size();
+            stats.free_histogram[stats::histogram_bucket(block.size())] += 1;
+            stats.largest_free_block = cmp::max(stats.largest_free_block, block.size());
+        }
+        stats
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sources::{HugePages, MmapSource, HUGE_PAGE_SIZE};
     use core::ffi::c_void;
     use libc::sbrk;
+    use std::vec::Vec;
 
     #[test]
     fn test_request_block() {
         unsafe {
             let mut heap = Heap::new();
-            let block = heap.request(256).expect("unable to request block");
-            let brk = block.next_potential_block().as_ptr();
+            let block = heap.request(256).expect("unable to request block").0;
+            // The block is followed by a fencepost
+            let brk = block.next_potential_block().as_ptr().add(BLOCK_META_SIZE);
             assert_eq!(brk.cast::<c_void>(), sbrk(0));
         }
     }
 
+    #[test]
+    fn test_stats() {
+        unsafe {
+            let mut heap = Heap::new();
+            assert_eq!(heap.stats(), HeapStats::default());
+
+            // The remaining block ends at the program break and is returned to the kernel
+            let block = heap.alloc(56).expect("unable to allocate block");
+            let stats = heap.stats();
+            assert_eq!(stats.heap_size, block.block_size());
+            assert!(stats.peak_heap_size > stats.heap_size);
+            assert_eq!(stats.used_blocks, 1);
+            assert_eq!(stats.used_bytes, 56);
+            assert_eq!(stats.free_blocks, 0);
+
+            heap.dealloc(block);
+            let stats = heap.stats();
+            assert_eq!(stats.heap_size, 0);
+            assert_eq!(stats.used_blocks, 0);
+            assert_eq!(stats.used_bytes, 0);
+        }
+    }
+
+    #[test]
+    fn test_trim_threshold() {
+        unsafe {
+            let mut heap = Heap::new();
+            heap.options.trim_threshold = usize::max_value();
+            let block = heap.alloc(56).expect("unable to allocate block");
+            // The remaining block is kept in the free list instead of being returned to the kernel
+            let stats = heap.stats();
+            assert_eq!(stats.free_blocks, 1);
+            assert_eq!(stats.heap_size, stats.peak_heap_size);
+
+            // Both blocks are merged again
+            heap.dealloc(block);
+            let stats = heap.stats();
+            assert_eq!(stats.free_blocks, 1);
+            assert_eq!(stats.free_bytes, stats.heap_size - BLOCK_META_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_in_use_flags() {
+        unsafe {
+            let mut heap = Heap::with_policy(MmapSource::new(1 << 20), FirstFit);
+            heap.options.trim_threshold = usize::max_value();
+            let block = heap.alloc(56).expect("unable to allocate block");
+            let block2 = heap.alloc(56).expect("unable to allocate block");
+            assert!(block.as_ref().in_use());
+            assert!(block2.as_ref().prev_in_use());
+
+            heap.dealloc(block);
+            assert!(!block.as_ref().in_use());
+            assert!(!block2.as_ref().prev_in_use());
+            // The second free is detected by the in-use flag and ignored
+            heap.dealloc(block);
+            assert_eq!(heap.stats().used_blocks, 1);
+            heap.destroy();
+        }
+    }
+
+    #[test]
+    fn test_quarantine() {
+        unsafe {
+            let mut heap = Heap::with_policy(MmapSource::new(1 << 20), FirstFit);
+            let mut options = heap.options;
+            options.trim_threshold = usize::max_value();
+            options.quarantine_size = 1024;
+            options.quarantine_blocks = 2;
+            options.poison = 0xA5;
+            heap.configure(options);
+
+            let blocks: Vec<_> = (0..4)
+                .map(|_| heap.alloc(248).expect("unable to allocate block"))
+                .collect();
+            heap.dealloc(blocks[0]);
+            heap.dealloc(blocks[1]);
+            let stats = heap.stats();
+            assert_eq!((stats.quarantine_blocks, stats.quarantine_bytes), (2, 496));
+            assert_ne!(heap.alloc(248), Some(blocks[0]));
+            // Quarantined blocks are still detected as double free
+            heap.dealloc(blocks[1]);
+            assert_eq!(heap.stats().quarantine_blocks, 2);
+
+            // A write to a quarantined block is detected once it is evicted
+            *blocks[0].mem_region().as_ptr().add(200) = 0;
+            heap.dealloc(blocks[2]);
+            let stats = heap.stats();
+            assert_eq!(stats.quarantine_blocks, 2);
+            assert_eq!(stats.write_after_free, 1);
+
+            // Reconfiguring flushes the quarantine
+            heap.configure(options);
+            assert_eq!(heap.stats().quarantine_blocks, 0);
+            assert_eq!(heap.alloc(248), Some(blocks[0]));
+            heap.destroy();
+        }
+    }
+
+    #[test]
+    fn test_randomize() {
+        unsafe {
+            let mut heap = Heap::with_policy(MmapSource::new(1 << 20), FirstFit);
+            let mut options = heap.options;
+            options.trim_threshold = usize::max_value();
+            options.randomize = true;
+            heap.configure(options);
+
+            // Free every other block, so they can't be merged
+            let blocks: Vec<_> = (0..32)
+                .map(|_| heap.alloc(248).expect("unable to allocate block"))
+                .collect();
+            for block in blocks.iter().step_by(2) {
+                heap.dealloc(*block);
+            }
+            let mut chosen = Vec::new();
+            for _ in 0..32 {
+                let block = heap.alloc(248).expect("unable to allocate block");
+                assert!(blocks.contains(&block));
+                if !chosen.contains(&block) {
+                    chosen.push(block);
+                }
+                heap.dealloc(block);
+            }
+            assert!(chosen.len() > 1);
+
+            // Disabling it restores the configured policy
+            options.randomize = false;
+            heap.configure(options);
+            assert_eq!(heap.alloc(248), Some(blocks[0]));
+            heap.destroy();
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        unsafe {
+            let mut heap = Heap::with_policy(MmapSource::new(1 << 20), FirstFit);
+            let mut options = heap.options;
+            options.trim_threshold = usize::max_value();
+            options.limit = 256 << 10;
+            options.soft_limit = 128 << 10;
+            heap.configure(options);
+
+            const SIZE: usize = (100 << 10) - BLOCK_META_SIZE;
+            let block1 = heap.alloc(SIZE).expect("unable to allocate block");
+            // Crossing the soft limit fails once
+            assert_eq!(heap.alloc(SIZE), None);
+            assert!(heap.take_soft_limit_hit());
+            assert!(!heap.take_soft_limit_hit());
+            let block2 = heap.alloc(SIZE).expect("unable to allocate block");
+            assert!(heap.stats().heap_size > options.soft_limit);
+            // The hard limit can't be exceeded
+            assert_eq!(heap.alloc(SIZE), None);
+            assert!(!heap.take_soft_limit_hit());
+            assert!(heap.stats().heap_size <= options.limit);
+
+            // The free block at the end is trimmed regardless of the trim threshold
+            heap.dealloc(block2);
+            let heap_size = heap.stats().heap_size;
+            assert!(heap.trim() > 0);
+            assert!(heap.stats().heap_size < heap_size);
+            assert!(heap.stats().free_blocks <= 1);
+            heap.dealloc(block1);
+            heap.destroy();
+        }
+    }
+
+    #[test]
+    fn test_limits_huge_pages() {
+        unsafe {
+            let source = MmapSource::with_huge_pages(4 * HUGE_PAGE_SIZE, HugePages::Transparent);
+            let mut heap = Heap::with_policy(source, FirstFit);
+            let mut options = heap.options;
+            const SIZE: usize = (1 << 10) - BLOCK_META_SIZE;
+            // Blocks are requested in units of huge pages
+            options.limit = HUGE_PAGE_SIZE / 2;
+            heap.configure(options);
+            assert_eq!(heap.alloc(SIZE), None);
+            assert_eq!(heap.stats().heap_size, 0);
+
+            options.limit = HUGE_PAGE_SIZE;
+            heap.configure(options);
+            let block = heap.alloc(SIZE).expect("unable to allocate block");
+            assert_eq!(heap.stats().heap_size, HUGE_PAGE_SIZE);
+            assert_eq!(heap.alloc(HUGE_PAGE_SIZE - BLOCK_META_SIZE), None);
+            assert_eq!(heap.stats().heap_size, HUGE_PAGE_SIZE);
+            heap.dealloc(block);
+            heap.destroy();
+        }
+    }
+
+    #[test]
+    fn test_alloc_fresh() {
+        unsafe {
+            let mut heap = Heap::with_policy(MmapSource::new(1 << 20), FirstFit);
+            heap.options.trim_threshold = usize::max_value();
+            let (block, fresh) = heap.alloc_fresh(248).expect("unable to allocate block");
+            assert!(fresh);
+            block.mem_region().as_ptr().write_bytes(0xFF, block.size());
+            heap.dealloc(block);
+
+            // The block is reused from the free list and may contain stale data
+            let (block2, fresh) = heap.alloc_fresh(248).expect("unable to allocate block");
+            assert_eq!(block2, block);
+            assert!(!fresh);
+            heap.destroy();
+        }
+    }
+
+    #[test]
+    fn test_split_min_size() {
+        unsafe {
+            let mut heap = Heap::new();
+            heap.options.split_min_size = usize::max_value() / 2;
+            let block = heap.alloc(56).expect("unable to allocate block");
+            assert!(block.size() > 56);
+            assert_eq!(heap.stats().heap_size, block.block_size());
+            heap.dealloc(block);
+        }
+    }
+
+    #[test]
+    fn test_stats_free_list() {
+        unsafe {
+            let mut heap = Heap::new();
+            let mut block = heap.request(512).expect("unable to request block").0;
+            // Block2 imitates a used block. So it will not be added to list
+            let mut block2 = block.shrink(72).expect("unable to split block");
+            let block3 = block2.shrink(120).expect("unable to split block");
+            heap.list.insert(block).expect("unable to insert");
+            heap.list.insert(block3).expect("unable to insert");
+
+            let stats = heap.stats();
+            assert_eq!(stats.free_blocks, 2);
+            assert_eq!(stats.free_bytes, block.size() + block3.size());
+            assert_eq!(stats.largest_free_block, block3.size());
+            assert_eq!(stats.free_histogram[6], 1);
+            assert_eq!(stats.free_histogram.iter().sum::<usize>(), 2);
+            assert!(stats.external_fragmentation() > 0.0);
+            assert!(stats.metadata_overhead() > 0.0);
+        }
+    }
+
     #[test]
     fn test_request_block_split() {
         unsafe {
@@ -74,10 +733,43 @@ mod tests {
             let rem_block = heap
                 .request(256)
                 .expect("unable to request block")
-                .shrink(128)
+                .0
+                .shrink(120)
                 .expect("unable to split block");
-            let brk = rem_block.next_potential_block().as_ptr();
+            let brk = rem_block
+                .next_potential_block()
+                .as_ptr()
+                .add(BLOCK_META_SIZE);
             assert_eq!(brk.cast::<c_void>(), sbrk(0));
         }
     }
+
+    #[test]
+    fn test_reuse_slightly_larger_blocks() {
+        unsafe {
+            let mut heap = Heap::with_policy(MmapSource::new(1 << 20), FirstFit);
+            let mut blocks = [None; 64];
+            for (i, block) in blocks.iter_mut().enumerate() {
+                let b = heap.alloc(88).expect("unable to allocate block");
+                // Every other block stays allocated to prevent merging
+                if i % 2 == 0 {
+                    *block = Some(b);
+                }
+            }
+            for block in blocks.iter().flatten() {
+                heap.dealloc(*block);
+            }
+            let heap_size = heap.stats().heap_size;
+
+            // Blocks exceeding the requested size by less than the split threshold are reused,
+            // the slack stays part of the allocated block
+            for _ in 0..32 {
+                let block = heap.alloc(72).expect("unable to allocate block");
+                assert_eq!(block.size(), 88);
+            }
+            assert_eq!(heap.stats().heap_size, heap_size);
+            assert_eq!(heap.stats().free_blocks, 0);
+            heap.destroy();
+        }
+    }
 }
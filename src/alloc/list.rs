@@ -1,11 +1,25 @@
-use libc_print::libc_eprintln;
+use core::{cmp, intrinsics, mem};
 
-use crate::alloc::block::{BlockPtr, BLOCK_SPLIT_MIN_SIZE};
+use crate::alloc::block::{BlockPtr, BLOCK_META_SIZE};
+use crate::alloc::fit::FitPolicy;
+use crate::alloc::tree::{SizeTree, TREE_MIN_SIZE, TREE_NODE_SIZE};
+
+/// Offset of the poisoned part in the memory region of a free block,
+/// the space before holds the list links and the tree node.
+const POISON_OFFSET: usize = mem::size_of::<Option<BlockPtr>>() * 2 + TREE_NODE_SIZE;
+/// Number of corrupted bytes included in reports.
+const REPORT_BYTES: usize = 8;
 
 #[repr(C)]
 pub struct IntrusiveList {
     pub head: Option<BlockPtr>,
     pub tail: Option<BlockPtr>,
+    /// Index of all blocks with at least `TREE_MIN_SIZE` bytes for best fit lookups.
+    tree: SizeTree,
+    /// Byte filling the memory regions of all free blocks, 0 if disabled.
+    poison: u8,
+    /// Number of blocks with a detected write-after-free.
+    pub poison_errors: usize,
 }
 
 impl IntrusiveList {
@@ -13,66 +27,164 @@ impl IntrusiveList {
         Self {
             head: None,
             tail: None,
+            tree: SizeTree::new(),
+            poison: 0,
+            poison_errors: 0,
+        }
+    }
+
+    /// Changes the poison byte, all free blocks are filled with it again.
+    pub fn set_poison(&mut self, poison: u8) {
+        if poison == self.poison {
+            return;
+        }
+        self.poison = poison;
+        if poison != 0 {
+            self.iter().for_each(|block| self.fill_poison(block));
         }
     }
 
+    /// Returns the poison byte, 0 if disabled.
+    #[inline]
+    pub fn poison(&self) -> u8 {
+        self.poison
+    }
+
+    /// Fills the memory region of a free block with the poison byte if enabled,
+    /// the links are spared.
+    pub fn fill_poison(&self, block: BlockPtr) {
+        if self.poison == 0 {
+            return;
+        }
+        if let Some(len) = block.size().checked_sub(POISON_OFFSET) {
+            // SAFETY: the memory region spans `block.size()` bytes
+            unsafe {
+                let start = block.mem_region().as_ptr().add(POISON_OFFSET);
+                intrinsics::write_bytes(start, self.poison, len);
+            }
+        }
+    }
+
+    /// Verifies that the poison of a block is intact where it will be overwritten
+    /// to serve `size` bytes. Returns `false`, counts and reports the first corrupted byte otherwise.
+    pub fn check_poison(&mut self, block: BlockPtr, size: usize) -> bool {
+        if self.poison == 0 || self.verify_poison(block, size) {
+            return true;
+        }
+        self.poison_errors += 1;
+        false
+    }
+
+    fn verify_poison(&self, block: BlockPtr, size: usize) -> bool {
+        let end = cmp::min(block.size(), size + BLOCK_META_SIZE + POISON_OFFSET);
+        if end <= POISON_OFFSET {
+            return true;
+        }
+        // SAFETY: the memory region spans `block.size()` bytes
+        let region =
+            unsafe { core::slice::from_raw_parts(block.mem_region().as_ptr(), block.size()) };
+        let first = match region[POISON_OFFSET..end]
+            .iter()
+            .position(|b| *b != self.poison)
+        {
+            Some(i) => POISON_OFFSET + i,
+            None => return true,
+        };
+        let count = region[first..end]
+            .iter()
+            .filter(|b| **b != self.poison)
+            .count();
+        error!(
+            List,
+            "write after free detected at {:p} in {:?}, {} bytes corrupted: {:02x?}",
+            &region[first],
+            block,
+            count,
+            &region[first..cmp::min(end, first + REPORT_BYTES)]
+        );
+        false
+    }
+
     /// Inserts a `BlockPtr` to the existing list and
     /// returns `Err` on detected double-free.
     pub fn insert(&mut self, mut to_insert: BlockPtr) -> Result<(), ()> {
-        // Reset pointer locations since they were part as user allocatable data
-        to_insert.as_mut().unlink();
-
         // Add initial element
         if self.head.is_none() {
             debug_assert!(self.tail.is_none());
+            to_insert.as_mut().unlink();
             self.head = Some(to_insert);
             self.tail = Some(to_insert);
+            self.index(to_insert);
             return Ok(());
         }
 
         debug_assert!(self.head.is_some());
         debug_assert!(self.tail.is_some());
 
-        match self.find_higher_block(to_insert)? {
+        // Look up the position first, the links of a block which is already listed must be kept
+        let higher = self.find_higher_block(to_insert)?;
+        // Reset pointer locations since they were part as user allocatable data
+        to_insert.as_mut().unlink();
+        match higher {
             Some(block) => IntrusiveList::insert_before(block, to_insert),
             None => IntrusiveList::insert_after(self.tail.unwrap(), to_insert),
         }
-        let inserted = IntrusiveList::maybe_merge_adjacent(to_insert);
+        // Neighbours may grow or vanish by merging, they are indexed again afterwards
+        let (prev, next) = (to_insert.as_ref().prev, to_insert.as_ref().next);
+        prev.into_iter().chain(next).for_each(|b| self.unindex(b));
+        let inserted = self.maybe_merge_adjacent(to_insert);
+        self.index(inserted);
+        if let Some(prev) = prev.filter(|p| *p != inserted) {
+            self.index(prev);
+        }
+        if let Some(next) = next.filter(|n| inserted.as_ref().next == Some(*n)) {
+            self.index(next);
+        }
         self.update_ends(inserted);
         Ok(())
     }
 
-    /// Removes and returns the first suitable `BlockPtr`.
-    pub fn pop(&mut self, size: usize) -> Option<BlockPtr> {
-        for block in self.iter() {
-            if size == block.size() {
-                dprintln!(
-                    "[libcollam.so]: found perfect {} at {:p} for size {}",
-                    block.as_ref(),
-                    block,
-                    size
-                );
-                return Some(self.remove(block));
-            }
-            if size + BLOCK_SPLIT_MIN_SIZE <= block.size() {
-                dprintln!(
-                    "[libcollam.so]: found suitable {} at {:p} for size {}",
-                    block.as_ref(),
-                    block,
-                    size
-                );
-                return Some(self.remove(block));
-            }
+    /// Adds a block to the size index if it is large enough.
+    #[inline]
+    fn index(&mut self, block: BlockPtr) {
+        if block.size() >= TREE_MIN_SIZE {
+            self.tree.insert(block);
+        }
+    }
+
+    /// Removes a block from the size index if it has been added.
+    #[inline]
+    fn unindex(&mut self, block: BlockPtr) {
+        if block.size() >= TREE_MIN_SIZE {
+            self.tree.remove(block);
         }
-        None
+    }
+
+    /// Removes and returns the `BlockPtr` chosen by `policy` for the given size.
+    /// Best fit lookups for large sizes are served by the size index.
+    pub fn pop(&mut self, size: usize, policy: &mut impl FitPolicy) -> Option<BlockPtr> {
+        let block = if size >= TREE_MIN_SIZE && policy.best_fit() {
+            self.tree.find_best(size)?
+        } else {
+            policy.find(self.iter(), size)?
+        };
+        trace!(
+            List,
+            "found suitable {} at {:p} for size {}",
+            block.as_ref(),
+            block,
+            size
+        );
+        self.check_poison(block, size);
+        Some(self.remove(block))
     }
 
     /// Prints some debugging information about the heap structure.
     #[cfg(feature = "debug")]
     pub fn debug(&self) {
-        dprintln!("[debug]: === list debug start ===");
+        trace!(List, "=== list debug start ===");
         for (i, block) in self.iter().enumerate() {
-            dprintln!("[debug]: pos: {}\t{} at\t{:p}", i, block.as_ref(), block);
+            trace!(List, "pos: {}\t{} at\t{:p}", i, block.as_ref(), block);
             if !block.as_ref().verify() {
                 panic!("Unable to verify: {} at\t{:p}", block.as_ref(), block);
             }
@@ -104,7 +216,9 @@ impl IntrusiveList {
                 );
             }
         }
-        dprintln!("[debug]: === list debug end ===");
+        let indexed = self.iter().filter(|b| b.size() >= TREE_MIN_SIZE).count();
+        debug_assert_eq!(self.tree.verify(), indexed);
+        trace!(List, "=== list debug end ===");
     }
 
     /// Adds a `BlockPtr` to the list before the given anchor.
@@ -152,18 +266,34 @@ impl IntrusiveList {
 
     /// Takes a `BlockPtr` and tries to merge adjacent blocks if possible.
     /// Always returns a `BlockPtr`.
-    fn maybe_merge_adjacent(block: BlockPtr) -> BlockPtr {
+    fn maybe_merge_adjacent(&self, block: BlockPtr) -> BlockPtr {
         let block = match block.as_ref().prev {
-            Some(prev) => prev.maybe_merge_next().unwrap_or(block),
+            Some(prev) => self.maybe_merge_next(prev).unwrap_or(block),
             None => block,
         };
-        block.maybe_merge_next().unwrap_or(block)
+        self.maybe_merge_next(block).unwrap_or(block)
+    }
+
+    /// Merges a block with the next one if possible,
+    /// the header and links of the absorbed block are poisoned.
+    fn maybe_merge_next(&self, block: BlockPtr) -> Option<BlockPtr> {
+        let next = block.as_ref().next?;
+        let next_size = next.block_size();
+        let merged = block.maybe_merge_next()?;
+        if self.poison != 0 {
+            let len = cmp::min(next_size, BLOCK_META_SIZE + POISON_OFFSET);
+            // SAFETY: the absorbed block is part of the merged memory region
+            unsafe { intrinsics::write_bytes(next.cast::<u8>().as_ptr(), self.poison, len) };
+        }
+        Some(merged)
     }
 
     /// Returns first `BlockPtr` that has a higher memory address than the given `BlockPtr`
     /// or `None` if no block exists at a higher memory address.
     /// Returns `Err` if given `BlockPtr` is already in list.
-    /// TODO: implement with better algorithm
+    ///
+    /// The list is walked, so inserting takes linear time. The address order isn't indexed
+    /// since the smallest free blocks have no room for a tree node.
     fn find_higher_block(&self, to_insert: BlockPtr) -> Result<Option<BlockPtr>, ()> {
         for block in self.iter() {
             if block.as_ptr() > to_insert.as_ptr() {
@@ -178,8 +308,15 @@ impl IntrusiveList {
         Ok(None)
     }
 
+    /// Removes and returns the block at the highest address.
+    pub fn pop_tail(&mut self) -> Option<BlockPtr> {
+        let tail = self.tail?;
+        Some(self.remove(tail))
+    }
+
     /// Removes the given `BlockPtr` from list and returns it.
     fn remove(&mut self, mut elem: BlockPtr) -> BlockPtr {
+        self.unindex(elem);
         // Update head
         if let Some(head) = self.head {
             if elem == head {
@@ -229,7 +366,9 @@ impl Iterator for Iter {
 mod tests {
     use super::*;
     use crate::alloc::block::BLOCK_META_SIZE;
+    use crate::alloc::fit::{BestFit, FirstFit};
     use crate::alloc::heap::Heap;
+    use std::vec::Vec;
 
     #[test]
     fn test_list_new() {
@@ -241,10 +380,10 @@ mod tests {
     #[test]
     fn test_insert_after_no_merge() {
         let mut heap = Heap::new();
-        let mut block = unsafe { heap.request(256).expect("unable to request block") };
+        let mut block = unsafe { heap.request(256).expect("unable to request block").0 };
         // Block2 imitates a used block. So it will not be added to list
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
+        let mut block2 = block.shrink(56).expect("unable to split block");
+        let block3 = block2.shrink(56).expect("unable to split block");
 
         // Insert block1
         heap.list.insert(block).expect("unable to insert");
@@ -266,10 +405,10 @@ mod tests {
     #[test]
     fn test_insert_before_no_merge() {
         let mut heap = Heap::new();
-        let mut block = unsafe { heap.request(256).expect("unable to request block") };
+        let mut block = unsafe { heap.request(256).expect("unable to request block").0 };
         // Block2 imitates a used block. So it will not be added to list
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
+        let mut block2 = block.shrink(56).expect("unable to split block");
+        let block3 = block2.shrink(56).expect("unable to split block");
 
         // Insert block3
         heap.list.insert(block3).expect("unable to insert");
@@ -288,12 +427,41 @@ mod tests {
         assert_eq!(block3.as_ref().prev, Some(block));
     }
 
+    #[test]
+    fn test_insert_ends() {
+        let mut heap = Heap::new();
+        let mut blocks = Vec::new();
+        let mut block = unsafe { heap.request(4096).expect("unable to request block").0 };
+        // Every other block imitates a used one, so none of them merge
+        for _ in 0..16 {
+            let mut used = block.shrink(56).expect("unable to split block");
+            blocks.push(block);
+            block = used.shrink(56).expect("unable to split block");
+        }
+        for block in blocks[1..15].iter().step_by(2) {
+            heap.list.insert(*block).expect("unable to insert");
+        }
+        // Blocks beyond the tail or before the head become the new ends
+        heap.list.insert(blocks[15]).expect("unable to insert");
+        heap.list.insert(blocks[0]).expect("unable to insert");
+        assert_eq!(heap.list.head, Some(blocks[0]));
+        assert_eq!(heap.list.tail, Some(blocks[15]));
+        assert_eq!(heap.list.tail.unwrap().as_ref().next, None);
+        // Inserting the ends again is detected as double free
+        assert!(heap.list.insert(blocks[15]).is_err());
+        assert!(heap.list.insert(blocks[0]).is_err());
+        heap.list.insert(blocks[8]).expect("unable to insert");
+        let addrs: Vec<_> = heap.list.iter().map(|b| b.as_ptr()).collect();
+        assert!(addrs.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(addrs.len(), 10);
+    }
+
     #[test]
     fn test_insert_merge() {
         let mut heap = Heap::new();
-        let mut block = unsafe { heap.request(256).expect("unable to request block") };
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
+        let mut block = unsafe { heap.request(256).expect("unable to request block").0 };
+        let mut block2 = block.shrink(56).expect("unable to split block");
+        let block3 = block2.shrink(56).expect("unable to split block");
 
         // Insert block1
         heap.list.insert(block).expect("unable to insert");
@@ -301,7 +469,7 @@ mod tests {
         assert_eq!(heap.list.tail, Some(block));
         assert_eq!(block.as_ref().next, None);
         assert_eq!(block.as_ref().prev, None);
-        assert_eq!(block.size(), 64);
+        assert_eq!(block.size(), 56);
 
         // Insert block2
         heap.list.insert(block2).expect("unable to insert");
@@ -309,7 +477,7 @@ mod tests {
         assert_eq!(heap.list.tail, Some(block));
         assert_eq!(block.as_ref().next, None);
         assert_eq!(block.as_ref().prev, None);
-        assert_eq!(block.size(), 64 + BLOCK_META_SIZE + 64);
+        assert_eq!(block.size(), 56 + BLOCK_META_SIZE + 56);
 
         // Insert block3
         heap.list.insert(block3).expect("unable to insert");
@@ -317,55 +485,133 @@ mod tests {
         assert_eq!(heap.list.tail, Some(block));
         assert_eq!(block.as_ref().next, None);
         assert_eq!(block.as_ref().prev, None);
-        assert!(block.size() > 64 + BLOCK_META_SIZE + 64 + BLOCK_META_SIZE);
+        assert!(block.size() > 56 + BLOCK_META_SIZE + 56 + BLOCK_META_SIZE);
     }
 
     #[test]
     fn test_pop_exact_size() {
         let mut heap = Heap::new();
-        let mut block = unsafe { heap.request(512).expect("unable to request block") };
+        let mut block = unsafe { heap.request(512).expect("unable to request block").0 };
         // Block2 imitates a used block. So it will not be added to list
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
+        let mut block2 = block.shrink(56).expect("unable to split block");
+        let block3 = block2.shrink(56).expect("unable to split block");
 
         // Insert block1
         heap.list.insert(block).expect("unable to insert");
         // Insert block3
         heap.list.insert(block3).expect("unable to insert");
 
-        let result = heap.list.pop(64).expect("got no block");
+        let result = heap.list.pop(56, &mut FirstFit).expect("got no block");
         assert_eq!(result, block);
         assert_eq!(result.as_ref().next, None);
         assert_eq!(result.as_ref().prev, None);
-        assert_eq!(result.size(), 64);
+        assert_eq!(result.size(), 56);
     }
 
     #[test]
     fn test_pop_smaller_size() {
         let mut heap = Heap::new();
-        let mut block = unsafe { heap.request(512).expect("unable to request block") };
+        let mut block = unsafe { heap.request(512).expect("unable to request block").0 };
         // Block2 imitates a used block. So it will not be added to list
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
+        let mut block2 = block.shrink(56).expect("unable to split block");
+        let block3 = block2.shrink(56).expect("unable to split block");
 
         // Insert block1
         heap.list.insert(block).expect("unable to insert");
         // Insert block3
         heap.list.insert(block3).expect("unable to insert");
 
-        let result = heap.list.pop(16).expect("got no block");
+        let result = heap.list.pop(16, &mut FirstFit).expect("got no block");
         assert_eq!(result, block);
         assert_eq!(result.as_ref().next, None);
         assert_eq!(result.as_ref().prev, None);
-        assert_eq!(result.size(), 64);
+        assert_eq!(result.size(), 56);
+    }
+
+    #[test]
+    fn test_pop_poison() {
+        let mut heap = Heap::new();
+        let mut block = unsafe { heap.request(1016).expect("unable to request block").0 };
+        let mut block2 = block.shrink(504).expect("unable to split block");
+        let block3 = block2.shrink(248).expect("unable to split block");
+        heap.list.set_poison(0xA5);
+
+        // Merged blocks are poisoned across the absorbed header
+        for b in &[block, block2] {
+            heap.list.fill_poison(*b);
+            heap.list.insert(*b).expect("unable to insert");
+        }
+        assert_eq!(heap.list.head, Some(block));
+        let region = block.mem_region().as_ptr();
+        let poisoned = unsafe { core::slice::from_raw_parts(region, block.size()) };
+        assert!(poisoned[POISON_OFFSET..].iter().all(|b| *b == 0xA5));
+
+        let result = heap.list.pop(504, &mut FirstFit).expect("got no block");
+        assert_eq!(result, block);
+        assert_eq!(heap.list.poison_errors, 0);
+
+        // Write after free into the popped part
+        heap.list.fill_poison(block3);
+        heap.list.insert(block3).expect("unable to insert");
+        unsafe { *block3.mem_region().as_ptr().add(100) = 0 };
+        heap.list.pop(56, &mut FirstFit).expect("got no block");
+        assert_eq!(heap.list.poison_errors, 1);
+    }
+
+    #[test]
+    fn test_pop_slightly_larger_size() {
+        let mut heap = Heap::new();
+        let mut block = unsafe { heap.request(512).expect("unable to request block").0 };
+        // Block2 imitates a used block. So it will not be added to list
+        let mut block2 = block.shrink(72).expect("unable to split block");
+        block2.shrink(56).expect("unable to split block");
+
+        heap.list.insert(block).expect("unable to insert");
+        // The remainder of 16 bytes is too small to be split off, but the block is still used
+        let result = heap.list.pop(56, &mut FirstFit).expect("got no block");
+        assert_eq!(result, block);
+        assert_eq!(result.size(), 72);
+    }
+
+    #[test]
+    fn test_size_index() {
+        let mut heap = Heap::new();
+        let mut block = unsafe { heap.request(64 << 10).expect("unable to request block").0 };
+        let mut blocks = Vec::new();
+        for i in 0..48 {
+            let rem = block
+                .shrink(248 + (i * 37 % 16) * 64)
+                .expect("unable to split block");
+            blocks.push(block);
+            block = rem;
+        }
+        // Every other block imitates a used block
+        for block in blocks.iter().step_by(2) {
+            heap.list.insert(*block).expect("unable to insert");
+        }
+        let large =
+            |list: &IntrusiveList| list.iter().filter(|b| b.size() >= TREE_MIN_SIZE).count();
+        assert_eq!(heap.list.tree.verify(), large(&heap.list));
+
+        // Merging changes the size of indexed blocks
+        for block in blocks.iter().skip(1).step_by(4) {
+            heap.list.insert(*block).expect("unable to insert");
+        }
+        assert_eq!(heap.list.tree.verify(), large(&heap.list));
+
+        for size in [600, 1024, 1500, 2000, 4096].iter() {
+            let expected = BestFit.find(heap.list.iter(), *size);
+            assert_eq!(heap.list.pop(*size, &mut BestFit), expected);
+            assert_eq!(heap.list.tree.verify(), large(&heap.list));
+        }
     }
 
     #[test]
     fn test_iter() {
         let mut heap = Heap::new();
-        let mut block = unsafe { heap.request(256).expect("unable to request block") };
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
+        let mut block = unsafe { heap.request(256).expect("unable to request block").0 };
+        let mut block2 = block.shrink(56).expect("unable to split block");
+        let block3 = block2.shrink(56).expect("unable to split block");
 
         // Insert block1
         heap.list.insert(block).expect("unable to insert");
@@ -382,10 +628,10 @@ mod tests {
     #[test]
     fn test_debug() {
         let mut heap = Heap::new();
-        let mut block = unsafe { heap.request(256).expect("unable to request block") };
+        let mut block = unsafe { heap.request(256).expect("unable to request block").0 };
         // Block2 imitates a used block. So it will not be added to list
-        let mut block2 = block.shrink(64).expect("unable to split block");
-        let block3 = block2.shrink(64).expect("unable to split block");
+        let mut block2 = block.shrink(56).expect("unable to split block");
+        let block3 = block2.shrink(56).expect("unable to split block");
 
         // Insert block1
         heap.list.insert(block).expect("unable to insert");
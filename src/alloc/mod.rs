@@ -1,45 +1,483 @@
-use core::alloc::{GlobalAlloc, Layout};
-use core::{cmp, intrinsics, ptr::null_mut, ptr::Unique};
+use core::alloc::{
+    AllocErr, AllocInit, AllocRef, GlobalAlloc, Layout, MemoryBlock, ReallocPlacement,
+};
+use core::ptr::{null_mut, NonNull, Unique};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, Ordering};
+use core::{cmp, intrinsics, mem};
 
-use libc_print::libc_eprintln;
 use spin::Mutex;
 
-use crate::alloc::block::{BlockPtr, BLOCK_MIN_REGION_SIZE};
+use crate::alloc::block::BlockPtr;
+use crate::alloc::fit::{FirstFit, FitPolicy};
+use crate::alloc::guard::Guard;
 use crate::alloc::heap::Heap;
+use crate::alloc::options::{GuardMode, Options};
+use crate::alloc::redzone::Redzones;
+use crate::alloc::slab::{Slab, SLAB_MAX_SIZE};
+use crate::alloc::stats::HeapStats;
+use crate::profile::Profiler;
+use crate::sources::{DataSegment, MemorySource};
 use crate::util;
+use crate::MIN_ALIGN;
 
 pub mod block;
+mod buddy;
+pub mod bump;
+pub mod fit;
+mod guard;
 mod heap;
 mod list;
+pub mod options;
+mod quarantine;
+mod redzone;
+mod slab;
+pub mod stats;
+mod tree;
 
-pub struct Collam {
-    heap: Mutex<Heap>,
+/// Called with the requested size if the heap is out of memory.
+/// Returns `true` if memory has been freed and the allocation should be retried.
+/// The handler may allocate and free memory itself.
+pub type OomHandler = fn(usize) -> bool;
+
+/// Number of times an allocation is retried if the `OomHandler` asks for it.
+const OOM_MAX_RETRIES: usize = 8;
+
+/// General purpose allocator, by default backed by the process wide data segment.
+/// Instances using a private memory source like `MmapSource` are independent of each other
+/// and release all their memory when dropped.
+/// Free blocks are chosen by the placement policy `F`, first fit by default.
+pub struct Collam<S: MemorySource = DataSegment, F: FitPolicy = FirstFit> {
+    heap: Mutex<Heap<S, F>>,
+    /// Serves allocations of up to `SLAB_MAX_SIZE` bytes.
+    slab: Slab,
+    /// Set if `Options::slab` is enabled and no debugging option needs the heap,
+    /// avoids locking the heap for small allocations.
+    slab_enabled: AtomicBool,
+    /// Copy of `Options::poison`, new allocations are filled with its complement.
+    poison: AtomicU8,
+    /// Serves all allocations if `Options::guard` is enabled.
+    guard: Guard,
+    /// Lays out heap allocations with redzones if `Options::redzone` is set.
+    redzones: Redzones,
+    profiler: AtomicPtr<Profiler>,
+    /// `OomHandler` cast to a pointer, null if there is none.
+    oom_handler: AtomicPtr<()>,
 }
 
-impl Collam {
+impl Collam<DataSegment> {
     #[must_use]
     pub const fn new() -> Self {
         Self {
             heap: spin::Mutex::new(Heap::new()),
+            slab: Slab::new(),
+            slab_enabled: AtomicBool::new(true),
+            poison: AtomicU8::new(0),
+            guard: Guard::new(),
+            redzones: Redzones::new(),
+            profiler: AtomicPtr::new(null_mut()),
+            oom_handler: AtomicPtr::new(null_mut()),
+        }
+    }
+}
+
+impl<S: MemorySource> Collam<S> {
+    /// Creates an allocator which obtains its memory from the given memory source.
+    #[must_use]
+    pub const fn with_source(source: S) -> Self {
+        Self::with_policy(source, FirstFit)
+    }
+}
+
+impl<S: MemorySource, F: FitPolicy> Collam<S, F> {
+    /// Creates an allocator with the given memory source and placement policy.
+    #[must_use]
+    pub const fn with_policy(source: S, policy: F) -> Self {
+        Self {
+            heap: spin::Mutex::new(Heap::with_policy(source, policy)),
+            slab: Slab::new(),
+            slab_enabled: AtomicBool::new(true),
+            poison: AtomicU8::new(0),
+            guard: Guard::new(),
+            redzones: Redzones::new(),
+            profiler: AtomicPtr::new(null_mut()),
+            oom_handler: AtomicPtr::new(null_mut()),
+        }
+    }
+
+    /// Attaches a sampling heap profiler, `None` detaches the current one.
+    pub fn set_profiler(&self, profiler: Option<&'static Profiler>) {
+        let ptr = profiler.map_or(null_mut(), |p| p as *const Profiler as *mut Profiler);
+        self.profiler.store(ptr, Ordering::Release);
+    }
+
+    #[inline]
+    fn profiler(&self) -> Option<&'static Profiler> {
+        // SAFETY: only `&'static Profiler` references are stored
+        unsafe { self.profiler.load(Ordering::Acquire).as_ref() }
+    }
+
+    /// Registers a handler which is called before an allocation fails, `None` removes it.
+    pub fn set_oom_handler(&self, handler: Option<OomHandler>) {
+        let ptr = handler.map_or(null_mut(), |h| h as *mut ());
+        self.oom_handler.store(ptr, Ordering::Release);
+    }
+
+    #[inline]
+    fn oom_handler(&self) -> Option<OomHandler> {
+        let ptr = self.oom_handler.load(Ordering::Acquire);
+        if ptr.is_null() {
+            return None;
+        }
+        // SAFETY: only `OomHandler` pointers are stored
+        Some(unsafe { mem::transmute::<*mut (), OomHandler>(ptr) })
+    }
+
+    /// Called if the heap can't provide `size` bytes, no lock may be held.
+    /// Returns `true` if the `OomHandler` asks to retry or only the soft limit has been reached,
+    /// otherwise the current stats are logged.
+    #[cold]
+    fn on_oom(&self, size: usize, attempt: usize) -> bool {
+        let soft_limit_hit = self.heap.lock().take_soft_limit_hit();
+        if attempt < OOM_MAX_RETRIES {
+            if let Some(handler) = self.oom_handler() {
+                if handler(size) {
+                    trace!(Alloc, "retrying allocation of {} bytes", size);
+                    return true;
+                }
+            }
+        }
+        if soft_limit_hit {
+            return true;
+        }
+        let stats = self.stats();
+        warn!(
+            Alloc,
+            "out of memory: unable to allocate {} bytes (heap: {}, used: {} in {} blocks, free: {} in {} blocks, largest free: {})",
+            size,
+            stats.heap_size,
+            stats.used_bytes,
+            stats.used_blocks,
+            stats.free_bytes,
+            stats.free_blocks,
+            stats.largest_free_block
+        );
+        false
+    }
+
+    /// Charges `size` bytes committed by the slab or guard allocator against the limits,
+    /// the heap lock may not be held.
+    fn reserve(&self, size: usize) -> bool {
+        // SAFETY: we know it is thread safe, because we're locking the mutex
+        unsafe { self.heap.lock().reserve_external(size) }
+    }
+
+    /// Gives back `size` bytes charged by `reserve`.
+    fn release(&self, size: usize) {
+        self.heap.lock().release_external(size);
+    }
+
+    /// Calls `alloc` once more if it failed because the soft limit has been crossed,
+    /// after giving the `OomHandler` the chance to release memory.
+    fn retry_soft_limit(&self, size: usize, alloc: impl Fn() -> *mut u8) -> *mut u8 {
+        let ptr = alloc();
+        if ptr.is_null() && self.heap.lock().soft_limit_hit() && self.on_oom(size, 0) {
+            return alloc();
         }
+        ptr
     }
 
-    /// Requests and returns suitable empty `BlockPtr`.
+    /// Requests and returns a `BlockPtr` of at least the given size.
+    /// If `zeroed` is set, it is additionally checked whether its memory region is zeroed.
     #[inline]
-    fn request_block(&self, size: usize) -> Option<BlockPtr> {
+    fn alloc_block(&self, size: usize, zeroed: bool) -> Option<(BlockPtr, bool)> {
         // SAFETY: we know it is thread safe, because we're locking the mutex
-        unsafe { self.heap.lock().request(size) }
+        unsafe {
+            let mut heap = self.heap.lock();
+            if zeroed {
+                heap.alloc_fresh(size)
+            } else {
+                heap.alloc(size).map(|block| (block, false))
+            }
+        }
+    }
+
+    /// Allocates memory for `layout`, the first `layout.size()` bytes are cleared if `zeroed`
+    /// is set and the memory isn't known to be zeroed already.
+    ///
+    /// # Safety
+    ///
+    /// `layout` must have a non-zero size.
+    unsafe fn alloc_memory(&self, layout: Layout, zeroed: bool) -> *mut u8 {
+        let mode = self.guard.mode();
+        if mode != GuardMode::Disabled {
+            // Guarded pages are never reused and always zeroed
+            let ptr = self.retry_soft_limit(layout.size(), || {
+                self.guard
+                    .alloc(layout.size(), mode, |size| self.reserve(size))
+            });
+            if !ptr.is_null() {
+                if let Some(profiler) = self.profiler() {
+                    profiler.on_alloc(ptr, layout.size());
+                }
+                return ptr;
+            }
+        }
+
+        if layout.size() <= SLAB_MAX_SIZE && self.slab_enabled.load(Ordering::Relaxed) {
+            let ptr = self.retry_soft_limit(layout.size(), || {
+                self.slab.alloc(layout.size(), |size| self.reserve(size))
+            });
+            if !ptr.is_null() {
+                if zeroed {
+                    intrinsics::write_bytes(ptr, 0, layout.size());
+                }
+                if let Some(profiler) = self.profiler() {
+                    profiler.on_alloc(ptr, slab::object_size(layout.size()));
+                }
+                return ptr;
+            }
+        }
+
+        let redzone = self.redzones.size();
+        let padded = match redzone {
+            0 => Some(layout.size()),
+            _ => redzone::padded_size(layout.size(), redzone),
+        };
+        let size = match padded.and_then(block::region_size) {
+            Some(s) => s,
+            None => return null_mut(),
+        };
+        let mut attempt = 0;
+        let (block, fresh) = loop {
+            if let Some(b) = self.alloc_block(size, zeroed) {
+                break b;
+            }
+            if !self.on_oom(size, attempt) {
+                return null_mut();
+            }
+            attempt += 1;
+        };
+
+        debug_assert!(
+            block.size() >= size,
+            "requested_size={}, got_block={}",
+            size,
+            block.as_ref()
+        );
+        let ptr = match redzone {
+            0 => block.mem_region().as_ptr(),
+            _ => self.redzones.init(block, layout.size(), redzone),
+        };
+        if zeroed && !fresh {
+            intrinsics::write_bytes(ptr, 0, layout.size());
+        } else if !zeroed {
+            let poison = self.poison.load(Ordering::Relaxed);
+            if poison != 0 {
+                intrinsics::write_bytes(ptr, !poison, layout.size());
+            }
+        }
+        if let Some(profiler) = self.profiler() {
+            profiler.on_alloc(ptr, size);
+        }
+        ptr
     }
 
     /// Releases the given `BlockPtr` back to the allocator.
     #[inline]
-    fn release_block(&self, block: BlockPtr) {
+    fn dealloc_block(&self, block: BlockPtr) {
+        // SAFETY: we know it is thread safe, because we're locking the mutex
+        unsafe { self.heap.lock().dealloc(block) }
+    }
+
+    /// Replaces the runtime configuration.
+    pub fn configure(&self, options: Options) {
         // SAFETY: we know it is thread safe, because we're locking the mutex
-        unsafe { self.heap.lock().release(block) }
+        unsafe { self.heap.lock().configure(options) };
+        // Slab objects have no room for redzones and are neither poisoned nor quarantined
+        let slab = options.slab
+            && options.redzone == 0
+            && options.poison == 0
+            && options.quarantine_size == 0;
+        self.slab_enabled.store(slab, Ordering::Relaxed);
+        self.slab.set_randomize(options.randomize);
+        self.poison.store(options.poison, Ordering::Relaxed);
+        self.guard.set_mode(options.guard);
+        self.redzones.set_size(options.redzone);
+    }
+
+    /// Returns the current runtime configuration.
+    pub fn options(&self) -> Options {
+        self.heap.lock().options
+    }
+
+    /// Aborts the process if `abort_on_corruption` is enabled.
+    #[inline]
+    fn on_corruption(&self) {
+        if self.heap.lock().options.abort_on_corruption {
+            intrinsics::abort();
+        }
+    }
+
+    /// Returns the usable size of the memory region at `ptr` which has been allocated by this
+    /// instance, or 0 if it can't be verified.
+    pub fn usable_size(&self, ptr: *mut u8) -> usize {
+        if self.slab.contains(ptr) {
+            return self.slab.usable_size(ptr);
+        }
+        if self.guard.contains(ptr) {
+            return self.guard.usable_size(ptr);
+        }
+        if let Some(block) = self.redzones.lookup(ptr) {
+            // SAFETY: `lookup` only returns blocks with redzones
+            return unsafe { redzone::usable_size(block) };
+        }
+        let block = match Unique::new(ptr).and_then(BlockPtr::from_mem_region) {
+            Some(b) => b,
+            None => return 0,
+        };
+        if !block.as_ref().verify() {
+            error!(
+                Alloc,
+                "malloc_usable_size(): Unable to verify {} at {:p}",
+                block.as_ref(),
+                block
+            );
+            return 0;
+        }
+        block.size()
+    }
+
+    /// Returns the `MemoryBlock` for a memory region handed out by this instance.
+    #[inline]
+    fn memory_block(&self, ptr: NonNull<u8>) -> MemoryBlock {
+        MemoryBlock {
+            ptr,
+            size: self.usable_size(ptr.as_ptr()),
+        }
+    }
+
+    /// Moves an object out of the slab if it doesn't fit anymore.
+    unsafe fn realloc_slab(&self, ptr: *mut u8, new_size: usize) -> *mut u8 {
+        let old_size = self.slab.usable_size(ptr);
+        if old_size == 0 {
+            error!(Alloc, "realloc(): invalid slab pointer {:p}", ptr);
+            self.on_corruption();
+            return null_mut();
+        }
+        if new_size <= old_size {
+            return ptr;
+        }
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, MIN_ALIGN));
+        if new_ptr.is_null() {
+            return new_ptr;
+        }
+        intrinsics::copy_nonoverlapping(ptr, new_ptr, old_size);
+        if let Some(profiler) = self.profiler() {
+            profiler.on_dealloc(ptr);
+        }
+        self.slab.dealloc(ptr, |size| self.release(size));
+        new_ptr
+    }
+
+    /// Moves a guarded allocation to a new location, so stale pointers fault.
+    unsafe fn realloc_guarded(&self, ptr: *mut u8, new_size: usize) -> *mut u8 {
+        let old_size = self.guard.usable_size(ptr);
+        if old_size == 0 {
+            error!(Alloc, "realloc(): invalid guarded pointer {:p}", ptr);
+            self.on_corruption();
+            return null_mut();
+        }
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, MIN_ALIGN));
+        if new_ptr.is_null() {
+            return new_ptr;
+        }
+        intrinsics::copy_nonoverlapping(ptr, new_ptr, cmp::min(old_size, new_size));
+        if let Some(profiler) = self.profiler() {
+            profiler.on_dealloc(ptr);
+        }
+        self.guard.dealloc(ptr, |size| self.release(size));
+        new_ptr
+    }
+
+    /// Verifies redzones before resizing, the block moves if the back redzone doesn't fit.
+    unsafe fn realloc_redzone(&self, block: BlockPtr, new_size: usize) -> *mut u8 {
+        if !self.redzones.verify(block, "realloc()") {
+            self.on_corruption();
+            return null_mut();
+        }
+        let ptr = redzone::user_ptr(block);
+        if self.redzones.resize(block, new_size) {
+            return ptr;
+        }
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, MIN_ALIGN));
+        if new_ptr.is_null() {
+            return new_ptr;
+        }
+        let old_size = redzone::usable_size(block);
+        intrinsics::copy_nonoverlapping(ptr, new_ptr, cmp::min(old_size, new_size));
+        if let Some(profiler) = self.profiler() {
+            profiler.on_dealloc(ptr);
+        }
+        self.redzones.remove(block);
+        self.dealloc_block(block);
+        new_ptr
+    }
+
+    /// Verifies the redzones of all live allocations and the headers of all free blocks.
+    /// Returns the number of corrupted blocks, details are reported as errors.
+    pub fn check_heap(&self) -> usize {
+        let mut corrupted = self.redzones.check();
+        for block in self.heap.lock().list.iter() {
+            if !block.as_ref().verify() {
+                error!(Alloc, "heap check: unable to verify free {:?}", block);
+                corrupted += 1;
+            }
+        }
+        corrupted
+    }
+
+    /// Acquires all locks of this instance and its profiler, so no other thread holds one
+    /// while `fork()` copies the address space. Must be followed by `unlock_all`
+    /// in the parent and the child process.
+    pub fn lock_all(&self) {
+        self.slab.lock();
+        self.guard.lock();
+        self.redzones.lock();
+        if let Some(profiler) = self.profiler() {
+            profiler.lock();
+        }
+        // Taken last, the slab and guard allocators lock the heap to charge their memory
+        util::hold(&self.heap);
+    }
+
+    /// Releases the locks acquired by `lock_all`.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called after `lock_all`, the profiler may not have been changed in between.
+    pub unsafe fn unlock_all(&self) {
+        self.heap.force_unlock();
+        if let Some(profiler) = self.profiler() {
+            profiler.unlock();
+        }
+        self.redzones.unlock();
+        self.guard.unlock();
+        self.slab.unlock();
+    }
+
+    /// Returns a snapshot of the current heap state.
+    pub fn stats(&self) -> HeapStats {
+        let (slab_objects, slab_size) = self.slab.stats();
+        HeapStats {
+            slab_objects,
+            slab_size,
+            guard_allocations: self.guard.allocations(),
+            ..self.heap.lock().stats()
+        }
     }
 }
 
-unsafe impl GlobalAlloc for Collam {
+unsafe impl<S: MemorySource, F: FitPolicy> GlobalAlloc for Collam<S, F> {
     /// Allocate memory as described by the given `layout`.
     ///
     /// Returns a pointer to newly-allocated memory,
@@ -75,38 +513,16 @@ unsafe impl GlobalAlloc for Collam {
         if layout.size() == 0 {
             return null_mut();
         }
+        self.alloc_memory(layout, false)
+    }
 
-        let layout = match util::pad_min_align(layout.size()) {
-            Ok(l) => l,
-            Err(_) => return null_mut(),
-        };
-
-        let size = cmp::max(layout.size(), BLOCK_MIN_REGION_SIZE);
-        dprintln!("[libcollam.so]: alloc(size={})", size);
-        let mut block = match self.request_block(size) {
-            Some(b) => b,
-            None => {
-                dprintln!("[libcollam.so]: failed for size: {}\n", layout.size());
-                return null_mut();
-            }
-        };
-
-        if let Some(rem_block) = block.shrink(size) {
-            self.release_block(rem_block);
+    /// Behaves like `alloc`, but also ensures that the contents are set to zero before
+    /// being returned. Memory which is fresh from the memory source is not cleared again.
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if layout.size() == 0 {
+            return null_mut();
         }
-
-        dprintln!(
-            "[libcollam.so]: returning {} at {:p}\n",
-            block.as_ref(),
-            block
-        );
-        debug_assert!(
-            block.size() >= size,
-            "requested_size={}, got_block={}",
-            size,
-            block.as_ref()
-        );
-        block.mem_region().as_ptr()
+        self.alloc_memory(layout, true)
     }
 
     /// Deallocate the block of memory at the given `ptr` pointer with the given `layout`.
@@ -122,19 +538,59 @@ unsafe impl GlobalAlloc for Collam {
     /// * `layout` must be the same layout that was used
     ///   to allocate that block of memory,
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        if self.slab.contains(ptr) {
+            if let Some(profiler) = self.profiler() {
+                profiler.on_dealloc(ptr);
+            }
+            self.slab.dealloc(ptr, |size| self.release(size));
+            return;
+        }
+        if self.guard.contains(ptr) {
+            if let Some(profiler) = self.profiler() {
+                profiler.on_dealloc(ptr);
+            }
+            if !self.guard.dealloc(ptr, |size| self.release(size)) {
+                error!(
+                    Alloc,
+                    "free(): invalid guarded pointer or double free {:p}", ptr
+                );
+                self.on_corruption();
+            }
+            return;
+        }
+        if let Some(block) = self.redzones.lookup(ptr) {
+            let intact = self.redzones.verify(block, "free()");
+            self.redzones.remove(block);
+            if !intact {
+                self.on_corruption();
+                return;
+            }
+            if let Some(profiler) = self.profiler() {
+                profiler.on_dealloc(ptr);
+            }
+            self.dealloc_block(block);
+            return;
+        }
         if let Some(p) = Unique::new(ptr) {
-            dprintln!("[libcollam.so]: dealloc(ptr={:p})", ptr);
-
             let block = match BlockPtr::from_mem_region(p) {
                 Some(b) => b,
                 None => return,
             };
             if !block.as_ref().verify() {
-                eprintln!("free(): Unable to verify {} at {:p}", block.as_ref(), block);
+                error!(
+                    Alloc,
+                    "free(): Unable to verify {} at {:p}",
+                    block.as_ref(),
+                    block
+                );
+                self.on_corruption();
                 return;
             }
+            if let Some(profiler) = self.profiler() {
+                profiler.on_dealloc(ptr);
+            }
             // Add freed block back to heap structure.
-            self.release_block(block)
+            self.dealloc_block(block)
         }
     }
 
@@ -192,13 +648,20 @@ unsafe impl GlobalAlloc for Collam {
             Some(p) => p,
             None => return null_mut(),
         };
-
-        dprintln!("[libcollam.so]: realloc(ptr={:p}, size={})", ptr, new_size);
+        if self.slab.contains(ptr.as_ptr()) {
+            return self.realloc_slab(ptr.as_ptr(), new_size);
+        }
+        if self.guard.contains(ptr.as_ptr()) {
+            return self.realloc_guarded(ptr.as_ptr(), new_size);
+        }
+        if let Some(block) = self.redzones.lookup(ptr.as_ptr()) {
+            return self.realloc_redzone(block, new_size);
+        }
 
         // FIXME: Alignment  to old layout needed?
-        let new_layout = match util::pad_min_align(new_size) {
-            Ok(l) => l,
-            Err(_) => return null_mut(),
+        let size = match block::region_size(new_size) {
+            Some(s) => s,
+            None => return null_mut(),
         };
 
         let mut old_block = match BlockPtr::from_mem_region(ptr) {
@@ -207,44 +670,194 @@ unsafe impl GlobalAlloc for Collam {
         };
 
         if !old_block.as_ref().verify() {
-            eprintln!(
+            error!(
+                Alloc,
                 "realloc(): Unable to verify {} at {:p}",
                 old_block.as_ref(),
                 old_block
             );
+            self.on_corruption();
             return null_mut();
         }
 
-        match new_layout.size().cmp(&old_block.size()) {
+        match size.cmp(&old_block.size()) {
             cmp::Ordering::Equal => {
                 // Just return pointer if size didn't change.
                 ptr.as_ptr()
             }
             cmp::Ordering::Greater => {
                 // Allocate new region to fit size.
-                let new_ptr = self.alloc(new_layout);
-                let copy_size = cmp::min(new_layout.size(), old_block.size());
+                let new_ptr = self.alloc(Layout::from_size_align_unchecked(size, MIN_ALIGN));
+                if new_ptr.is_null() {
+                    return new_ptr;
+                }
+                let copy_size = cmp::min(size, old_block.size());
                 intrinsics::volatile_copy_nonoverlapping_memory(new_ptr, ptr.as_ptr(), copy_size);
+                if let Some(profiler) = self.profiler() {
+                    profiler.on_dealloc(ptr.as_ptr());
+                }
                 // Add old block back to heap structure.
-                self.release_block(old_block);
+                self.dealloc_block(old_block);
                 new_ptr
             }
             cmp::Ordering::Less => {
                 // Shrink allocated block if size is smaller.
-                let size = cmp::max(new_layout.size(), BLOCK_MIN_REGION_SIZE);
-                if let Some(rem_block) = old_block.shrink(size) {
-                    self.release_block(rem_block);
-                }
+                self.heap.lock().shrink(&mut old_block, size);
                 ptr.as_ptr()
             }
         }
     }
 }
 
+/// Allocator API implementation, allows to use separate `Collam` instances for collections.
+/// Only alignments up to `MIN_ALIGN` are supported.
+unsafe impl<S: MemorySource, F: FitPolicy> AllocRef for &Collam<S, F> {
+    fn alloc(&mut self, layout: Layout, init: AllocInit) -> Result<MemoryBlock, AllocErr> {
+        if layout.align() > MIN_ALIGN {
+            return Err(AllocErr);
+        }
+        if layout.size() == 0 {
+            return Ok(dangling(layout));
+        }
+        // SAFETY: layout has a non-zero size
+        let ptr = unsafe {
+            match init {
+                AllocInit::Uninitialized => GlobalAlloc::alloc(*self, layout),
+                AllocInit::Zeroed => GlobalAlloc::alloc_zeroed(*self, layout),
+            }
+        };
+        let memory = self.memory_block(NonNull::new(ptr).ok_or(AllocErr)?);
+        // SAFETY: memory denotes a valid block of memory, the requested part is already initialized
+        unsafe { init.init_offset(memory, layout.size()) };
+        Ok(memory)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            GlobalAlloc::dealloc(*self, ptr.as_ptr(), layout);
+        }
+    }
+
+    /// Grows the memory block in-place if its usable size is already sufficient,
+    /// otherwise moves it unless `placement` is `ReallocPlacement::InPlace`.
+    /// `AllocInit::Zeroed` only zeroes the memory beyond `layout.size()`.
+    unsafe fn grow(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+        placement: ReallocPlacement,
+        init: AllocInit,
+    ) -> Result<MemoryBlock, AllocErr> {
+        debug_assert!(new_size >= layout.size());
+        if layout.size() == 0 {
+            return match placement {
+                ReallocPlacement::MayMove => AllocRef::alloc(
+                    self,
+                    Layout::from_size_align_unchecked(new_size, layout.align()),
+                    init,
+                ),
+                ReallocPlacement::InPlace => Err(AllocErr),
+            };
+        }
+
+        let memory = self.memory_block(ptr);
+        if memory.size >= new_size {
+            init.init_offset(memory, layout.size());
+            return Ok(memory);
+        }
+        if placement == ReallocPlacement::InPlace {
+            return Err(AllocErr);
+        }
+        let new_ptr = GlobalAlloc::realloc(*self, ptr.as_ptr(), layout, new_size);
+        let memory = self.memory_block(NonNull::new(new_ptr).ok_or(AllocErr)?);
+        init.init_offset(memory, layout.size());
+        Ok(memory)
+    }
+
+    /// Shrinks the memory block in-place and releases the remaining space if possible.
+    /// Guarded allocations can only shrink by moving, which fails for
+    /// `ReallocPlacement::InPlace`.
+    unsafe fn shrink(
+        &mut self,
+        ptr: NonNull<u8>,
+        layout: Layout,
+        new_size: usize,
+        placement: ReallocPlacement,
+    ) -> Result<MemoryBlock, AllocErr> {
+        debug_assert!(new_size <= layout.size());
+        if new_size == 0 {
+            AllocRef::dealloc(self, ptr, layout);
+            return Ok(dangling(layout));
+        }
+        if self.slab.contains(ptr.as_ptr()) {
+            // Objects keep their size class
+            return Ok(self.memory_block(ptr));
+        }
+        if self.guard.contains(ptr.as_ptr()) {
+            if placement == ReallocPlacement::InPlace {
+                return Err(AllocErr);
+            }
+            let new_ptr = self.realloc_guarded(ptr.as_ptr(), new_size);
+            return Ok(self.memory_block(NonNull::new(new_ptr).ok_or(AllocErr)?));
+        }
+        if let Some(block) = self.redzones.lookup(ptr.as_ptr()) {
+            if !self.redzones.verify(block, "shrink()") {
+                self.on_corruption();
+                return Err(AllocErr);
+            }
+            // The back redzone always fits into a shrunk region
+            if !self.redzones.resize(block, new_size) {
+                return Err(AllocErr);
+            }
+            return Ok(self.memory_block(ptr));
+        }
+
+        let size = block::region_size(new_size).ok_or(AllocErr)?;
+        let mut block = Unique::new(ptr.as_ptr())
+            .and_then(BlockPtr::from_mem_region)
+            .ok_or(AllocErr)?;
+        if !block.as_ref().verify() {
+            error!(
+                Alloc,
+                "shrink(): Unable to verify {} at {:p}",
+                block.as_ref(),
+                block
+            );
+            self.on_corruption();
+            return Err(AllocErr);
+        }
+        if size < block.size() {
+            self.heap.lock().shrink(&mut block, size);
+        }
+        Ok(self.memory_block(ptr))
+    }
+}
+
+impl<S: MemorySource, F: FitPolicy> Drop for Collam<S, F> {
+    /// Releases all memory of the memory source, e.g. unmaps the region of a `MmapSource`.
+    fn drop(&mut self) {
+        // SAFETY: no memory handed out by this instance can be used after it has been dropped
+        unsafe { self.heap.lock().destroy() }
+    }
+}
+
+/// Returns an empty `MemoryBlock` for zero sized allocations.
+#[inline]
+fn dangling(layout: Layout) -> MemoryBlock {
+    MemoryBlock {
+        // SAFETY: alignment is never zero
+        ptr: unsafe { NonNull::new_unchecked(layout.align() as *mut u8) },
+        size: 0,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::alloc::block::BLOCK_META_SIZE;
+    use crate::alloc::options::Backend;
+    use crate::sources::{HugePages, MmapSource, HUGE_PAGE_SIZE, PAGE_SIZE, THP_ENABLED};
     use crate::util;
     use core::intrinsics::write_bytes;
 
@@ -312,6 +925,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_collam_profiler() {
+        static PROFILER: Profiler = Profiler::new();
+        PROFILER.set_interval(1);
+        unsafe {
+            let collam = Collam::new();
+            collam.set_profiler(Some(&PROFILER));
+            let layout = util::pad_min_align(64).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            assert!(!ptr.is_null());
+
+            let mut fds = [0; 2];
+            assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+            assert!(PROFILER.dump(fds[1]));
+            let mut buf = [0_u8; 4096];
+            let read = libc::read(fds[0], buf.as_mut_ptr().cast::<libc::c_void>(), buf.len());
+            assert!(read > 0);
+            assert!(buf[..read as usize].ends_with(b" 64\n"));
+
+            collam.dealloc(ptr, layout);
+            collam.set_profiler(None);
+            assert!(PROFILER.dump(fds[1]));
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
     #[test]
     fn test_collam_realloc_null() {
         unsafe {
@@ -335,7 +975,7 @@ mod tests {
     fn test_collam_realloc_memory_corruption() {
         unsafe {
             let collam = Collam::new();
-            let layout = util::pad_min_align(16).expect("unable to align layout");
+            let layout = util::pad_min_align(512).expect("unable to align layout");
             let ptr = collam.alloc(layout);
             assert!(!ptr.is_null());
 
@@ -358,7 +998,7 @@ mod tests {
     fn test_collam_dealloc_memory_corruption() {
         unsafe {
             let collam = Collam::new();
-            let layout = util::pad_min_align(32).expect("unable to align layout");
+            let layout = util::pad_min_align(512).expect("unable to align layout");
             let ptr = collam.alloc(layout);
             assert!(!ptr.is_null());
 
@@ -368,4 +1008,539 @@ mod tests {
             collam.dealloc(ptr, layout);
         }
     }
+
+    #[test]
+    fn test_alloc_ref_alloc_zeroed() {
+        unsafe {
+            let collam = Collam::new();
+            let mut a = &collam;
+            let layout = Layout::from_size_align(100, 8).expect("unable to create layout");
+            let memory =
+                AllocRef::alloc(&mut a, layout, AllocInit::Zeroed).expect("unable to allocate");
+            assert!(memory.size >= 100);
+            let slice = core::slice::from_raw_parts(memory.ptr.as_ptr(), memory.size);
+            assert!(slice.iter().all(|b| *b == 0));
+            AllocRef::dealloc(&mut a, memory.ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_alloc_ref_zero_size_and_alignment() {
+        let collam = Collam::new();
+        let mut a = &collam;
+        let layout = Layout::from_size_align(0, 8).expect("unable to create layout");
+        let memory =
+            AllocRef::alloc(&mut a, layout, AllocInit::Uninitialized).expect("unable to allocate");
+        assert_eq!(memory.size, 0);
+        unsafe { AllocRef::dealloc(&mut a, memory.ptr, layout) };
+
+        let layout = Layout::from_size_align(64, 4096).expect("unable to create layout");
+        assert!(AllocRef::alloc(&mut a, layout, AllocInit::Uninitialized).is_err());
+    }
+
+    #[test]
+    fn test_alloc_ref_grow() {
+        unsafe {
+            let collam = Collam::new();
+            let mut a = &collam;
+            let layout = Layout::from_size_align(32, 8).expect("unable to create layout");
+            let memory = AllocRef::alloc(&mut a, layout, AllocInit::Uninitialized)
+                .expect("unable to allocate");
+            write_bytes(memory.ptr.as_ptr(), 1, 32);
+
+            // Growing within the usable size keeps the block in place
+            let grown = a
+                .grow(
+                    memory.ptr,
+                    layout,
+                    memory.size,
+                    ReallocPlacement::InPlace,
+                    AllocInit::Zeroed,
+                )
+                .expect("unable to grow in place");
+            assert_eq!(grown.ptr, memory.ptr);
+            assert!(a
+                .grow(
+                    memory.ptr,
+                    layout,
+                    memory.size + 1,
+                    ReallocPlacement::InPlace,
+                    AllocInit::Zeroed,
+                )
+                .is_err());
+
+            let grown = a
+                .grow(
+                    memory.ptr,
+                    layout,
+                    4096,
+                    ReallocPlacement::MayMove,
+                    AllocInit::Zeroed,
+                )
+                .expect("unable to grow");
+            assert!(grown.size >= 4096);
+            let slice = core::slice::from_raw_parts(grown.ptr.as_ptr(), grown.size);
+            assert!(slice[..32].iter().all(|b| *b == 1));
+            assert!(slice[32..].iter().all(|b| *b == 0));
+
+            let layout = Layout::from_size_align(4096, 8).expect("unable to create layout");
+            AllocRef::dealloc(&mut a, grown.ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_alloc_ref_shrink() {
+        unsafe {
+            let collam = Collam::new();
+            let mut a = &collam;
+            let layout = Layout::from_size_align(1024, 8).expect("unable to create layout");
+            let memory = AllocRef::alloc(&mut a, layout, AllocInit::Uninitialized)
+                .expect("unable to allocate");
+
+            let shrunk = a
+                .shrink(memory.ptr, layout, 128, ReallocPlacement::InPlace)
+                .expect("unable to shrink");
+            assert_eq!(shrunk.ptr, memory.ptr);
+            assert_eq!(Some(shrunk.size), block::region_size(128));
+
+            let layout = Layout::from_size_align(128, 8).expect("unable to create layout");
+            let empty = a
+                .shrink(shrunk.ptr, layout, 0, ReallocPlacement::InPlace)
+                .expect("unable to shrink");
+            assert_eq!(empty.size, 0);
+        }
+    }
+
+    #[test]
+    fn test_alloc_ref_shrink_guarded() {
+        unsafe {
+            let collam = Collam::with_source(MmapSource::new(1 << 20));
+            let mut options = collam.options();
+            options.guard = GuardMode::Overflow;
+            collam.configure(options);
+            let mut a = &collam;
+            let layout = Layout::from_size_align(1024, 8).expect("unable to create layout");
+            let memory = AllocRef::alloc(&mut a, layout, AllocInit::Uninitialized)
+                .expect("unable to allocate");
+            assert!(collam.guard.contains(memory.ptr.as_ptr()));
+            write_bytes(memory.ptr.as_ptr(), 1, 1024);
+
+            // The allocation ends at the guard page and can't shrink in place
+            assert!(a
+                .shrink(memory.ptr, layout, 128, ReallocPlacement::InPlace)
+                .is_err());
+            assert_eq!(collam.usable_size(memory.ptr.as_ptr()), 1024);
+
+            let shrunk = a
+                .shrink(memory.ptr, layout, 128, ReallocPlacement::MayMove)
+                .expect("unable to shrink");
+            assert_ne!(shrunk.ptr, memory.ptr);
+            assert_eq!(shrunk.size, 128);
+            let slice = core::slice::from_raw_parts(shrunk.ptr.as_ptr(), 128);
+            assert!(slice.iter().all(|b| *b == 1));
+            let layout = Layout::from_size_align(128, 8).expect("unable to create layout");
+            AllocRef::dealloc(&mut a, shrunk.ptr, layout);
+            assert_eq!(collam.stats().guard_allocations, 0);
+        }
+    }
+
+    #[test]
+    fn test_collam_private_heaps() {
+        unsafe {
+            let heap1 = Collam::with_source(MmapSource::new(1 << 20));
+            let heap2 = Collam::with_source(MmapSource::new(1 << 20));
+            let layout = util::pad_min_align(512).expect("unable to align layout");
+            let ptr1 = heap1.alloc(layout);
+            let ptr2 = heap2.alloc(layout);
+            assert!(!ptr1.is_null() && !ptr2.is_null());
+            write_bytes(ptr1, 1, 512);
+            write_bytes(ptr2, 2, 512);
+            assert_eq!(heap1.stats().used_blocks, 1);
+
+            // Memory of another instance is rejected
+            heap2.dealloc(ptr1, layout);
+            assert_eq!(heap2.stats().used_blocks, 1);
+            assert_eq!(*ptr1, 1);
+
+            // Dropping one instance releases all of its memory and leaves the other one intact
+            drop(heap1);
+            assert_eq!(*ptr2.add(511), 2);
+            heap2.dealloc(ptr2, layout);
+            assert_eq!(heap2.stats().used_blocks, 0);
+        }
+    }
+
+    #[test]
+    fn test_collam_alloc_zeroed() {
+        unsafe {
+            let collam = Collam::with_source(MmapSource::new(1 << 20));
+            let zeroed = |ptr: *mut u8, size| {
+                core::slice::from_raw_parts(ptr, size)
+                    .iter()
+                    .all(|b| *b == 0)
+            };
+            for size in [64, 2000].iter() {
+                let layout = util::pad_min_align(*size).expect("unable to align layout");
+                // Fresh memory of the memory source
+                let ptr = collam.alloc_zeroed(layout);
+                assert!(zeroed(ptr, *size));
+                write_bytes(ptr, 0xFF, *size);
+                collam.dealloc(ptr, layout);
+
+                // Reused memory is cleared
+                let ptr = collam.alloc_zeroed(layout);
+                assert!(zeroed(ptr, *size));
+                collam.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_collam_private_heap_exhausted() {
+        unsafe {
+            let heap = Collam::with_source(MmapSource::new(1 << 16));
+            let layout = util::pad_min_align(1 << 17).expect("unable to align layout");
+            assert!(heap.alloc(layout).is_null());
+            let layout = util::pad_min_align(1 << 10).expect("unable to align layout");
+            assert!(!heap.alloc(layout).is_null());
+        }
+    }
+
+    #[test]
+    fn test_collam_huge_pages() {
+        unsafe {
+            let source = MmapSource::with_huge_pages(1 << 24, HugePages::Transparent);
+            let heap = Collam::with_source(source);
+            assert_eq!(heap.stats().huge_page_bytes, 0);
+            let layout = util::pad_min_align(1 << 10).expect("unable to align layout");
+            let ptr = heap.alloc(layout);
+            assert!(!ptr.is_null());
+            // The heap grows by a whole huge page and keeps the remainder
+            let stats = heap.stats();
+            let expected = if *THP_ENABLED { HUGE_PAGE_SIZE } else { 0 };
+            assert_eq!(stats.huge_page_bytes, expected);
+            assert_eq!(stats.heap_size, HUGE_PAGE_SIZE);
+            heap.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_guard_pages() {
+        unsafe {
+            let collam = Collam::with_source(MmapSource::new(1 << 20));
+            let mut options = collam.options();
+            options.guard = GuardMode::Overflow;
+            collam.configure(options);
+
+            let layout = util::pad_min_align(64).expect("unable to align layout");
+            let ptr = collam.alloc_zeroed(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(*ptr.add(63), 0);
+            write_bytes(ptr, 1, 64);
+            assert_eq!(collam.usable_size(ptr), 64);
+            let stats = collam.stats();
+            assert_eq!(stats.used_blocks, 0);
+            assert_eq!(stats.guard_allocations, 1);
+            // Guarded pages are counted, the guard page itself isn't committed
+            assert_eq!(stats.heap_size, *PAGE_SIZE);
+
+            // Reallocations always move
+            let new_ptr = collam.realloc(ptr, layout, 32);
+            assert_ne!(new_ptr, ptr);
+            assert_eq!(*new_ptr.add(31), 1);
+            assert_eq!(collam.usable_size(ptr), 0);
+
+            // Existing allocations stay valid when the mode changes
+            options.guard = GuardMode::Disabled;
+            collam.configure(options);
+            let ptr = collam.alloc(layout);
+            assert!(!collam.guard.contains(ptr));
+            collam.dealloc(new_ptr, layout);
+            collam.dealloc(ptr, layout);
+            assert_eq!(collam.stats().guard_allocations, 0);
+        }
+    }
+
+    #[test]
+    fn test_collam_oom_handler() {
+        use core::sync::atomic::AtomicUsize;
+        static COLLAM: Collam<MmapSource> = Collam::with_source(MmapSource::new(1 << 20));
+        static CACHE: AtomicPtr<u8> = AtomicPtr::new(null_mut());
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        /// Frees the cached allocation on the first call.
+        fn release_cache(_size: usize) -> bool {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            let ptr = CACHE.swap(null_mut(), Ordering::SeqCst);
+            if ptr.is_null() {
+                return false;
+            }
+            unsafe { COLLAM.dealloc(ptr, Layout::from_size_align_unchecked(1, MIN_ALIGN)) };
+            true
+        }
+
+        fn always_retry(_size: usize) -> bool {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            true
+        }
+
+        unsafe {
+            let layout = util::pad_min_align(600 << 10).expect("unable to align layout");
+            CACHE.store(COLLAM.alloc(layout), Ordering::SeqCst);
+            assert!(COLLAM.alloc(layout).is_null());
+
+            COLLAM.set_oom_handler(Some(release_cache));
+            let ptr = COLLAM.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+            // Nothing left to release
+            assert!(COLLAM.alloc(layout).is_null());
+            assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+            // Retries are limited
+            COLLAM.set_oom_handler(Some(always_retry));
+            assert!(COLLAM.alloc(layout).is_null());
+            assert_eq!(CALLS.load(Ordering::SeqCst), 2 + OOM_MAX_RETRIES);
+
+            COLLAM.set_oom_handler(None);
+            assert!(COLLAM.alloc(layout).is_null());
+            assert_eq!(CALLS.load(Ordering::SeqCst), 2 + OOM_MAX_RETRIES);
+            COLLAM.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_fork() {
+        use std::thread;
+        use std::vec::Vec;
+        static COLLAM: Collam<MmapSource> = Collam::with_source(MmapSource::new(16 << 20));
+
+        /// Allocates from the slab and the heap.
+        fn churn() -> bool {
+            [24, 4000].iter().all(|size| unsafe {
+                let layout = Layout::from_size_align_unchecked(*size, MIN_ALIGN);
+                let ptr = COLLAM.alloc(layout);
+                COLLAM.dealloc(ptr, layout);
+                !ptr.is_null()
+            })
+        }
+
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    for _ in 0..2000 {
+                        assert!(churn());
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..20 {
+            unsafe {
+                COLLAM.lock_all();
+                let pid = libc::fork();
+                COLLAM.unlock_all();
+                if pid == 0 {
+                    // Kill the child if it deadlocks
+                    libc::alarm(10);
+                    libc::_exit(if churn() { 0 } else { 1 });
+                }
+                assert!(pid > 0);
+                let mut status = 0;
+                assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+            }
+        }
+        for thread in threads {
+            thread.join().expect("allocating thread panicked");
+        }
+    }
+
+    #[test]
+    fn test_collam_limits() {
+        use core::sync::atomic::AtomicUsize;
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        fn count(_size: usize) -> bool {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            false
+        }
+
+        unsafe {
+            let collam = Collam::with_source(MmapSource::new(1 << 20));
+            let mut options = collam.options();
+            options.limit = 256 << 10;
+            options.soft_limit = 128 << 10;
+            collam.configure(options);
+            collam.set_oom_handler(Some(count));
+
+            let layout = util::pad_min_align(100 << 10).expect("unable to align layout");
+            let ptr1 = collam.alloc(layout);
+            assert!(!ptr1.is_null());
+            assert_eq!(CALLS.load(Ordering::SeqCst), 0);
+            // The handler runs once the soft limit is crossed, the allocation succeeds anyway
+            let ptr2 = collam.alloc(layout);
+            assert!(!ptr2.is_null());
+            assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+            // Exceeding the hard limit fails
+            assert!(collam.alloc(layout).is_null());
+            assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+            assert!(collam.stats().heap_size <= options.limit);
+            collam.dealloc(ptr1, layout);
+            collam.dealloc(ptr2, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_slab_limit() {
+        unsafe {
+            let collam = Collam::with_source(MmapSource::new(1 << 20));
+            let mut options = collam.options();
+            options.limit = 4 * slab::group_size();
+            collam.configure(options);
+
+            let layout = util::pad_min_align(SLAB_MAX_SIZE).expect("unable to align layout");
+            let mut ptrs = [null_mut(); 2048];
+            let mut count = 0;
+            for ptr in ptrs.iter_mut() {
+                *ptr = collam.alloc(layout);
+                if ptr.is_null() {
+                    break;
+                }
+                assert!(collam.slab.contains(*ptr));
+                count += 1;
+            }
+            // Slab pages are charged against the limit
+            assert_eq!(count, options.limit / SLAB_MAX_SIZE);
+            assert_eq!(collam.stats().heap_size, options.limit);
+            for ptr in ptrs[..count].iter() {
+                collam.dealloc(*ptr, layout);
+            }
+            // Released pages are given back, except for the last one of the class
+            assert_eq!(collam.stats().heap_size, slab::group_size());
+            assert!(!collam.alloc(layout).is_null());
+        }
+    }
+
+    #[test]
+    fn test_collam_poison() {
+        unsafe {
+            let collam = Collam::with_source(MmapSource::new(1 << 20));
+            let mut options = collam.options();
+            options.poison = 0xA5;
+            collam.configure(options);
+
+            let layout = util::pad_min_align(200).expect("unable to align layout");
+            let ptr = collam.alloc(layout);
+            let guard = collam.alloc(layout);
+            assert!(!collam.slab.contains(ptr));
+            assert_eq!(*ptr.add(199), 0x5A);
+            assert_eq!(*collam.alloc_zeroed(layout).add(199), 0);
+
+            collam.dealloc(ptr, layout);
+            assert_eq!(*ptr.add(199), 0xA5);
+            let ptr = collam.alloc(layout);
+            assert_eq!(collam.stats().write_after_free, 0);
+
+            collam.dealloc(ptr, layout);
+            *ptr.add(100) = 1;
+            assert_eq!(collam.alloc(layout), ptr);
+            assert_eq!(collam.stats().write_after_free, 1);
+            collam.dealloc(guard, layout);
+        }
+    }
+
+    #[test]
+    fn test_collam_redzones() {
+        unsafe {
+            let collam = Collam::with_source(MmapSource::new(1 << 20));
+            let mut options = collam.options();
+            options.redzone = 32;
+            collam.configure(options);
+
+            let layout = util::pad_min_align(40).expect("unable to align layout");
+            let ptr = collam.alloc_zeroed(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % MIN_ALIGN, 0);
+            assert_eq!(collam.usable_size(ptr), 48);
+            write_bytes(ptr, 1, 48);
+            assert_eq!(collam.check_heap(), 0);
+
+            // Shrinking stays in-place, growing beyond the block moves
+            assert_eq!(collam.realloc(ptr, layout, 20), ptr);
+            assert_eq!(collam.usable_size(ptr), 20);
+            let ptr = collam.realloc(ptr, layout, 4096);
+            assert_eq!(*ptr.add(19), 1);
+            assert_eq!(collam.usable_size(ptr), 4096);
+
+            // Blocks without redzones stay valid when the option changes
+            options.redzone = 0;
+            collam.configure(options);
+            let plain = collam.alloc(layout);
+            assert_eq!(collam.usable_size(plain), 48);
+            collam.dealloc(plain, layout);
+
+            *ptr.add(4096) = 0;
+            *ptr.sub(9) = 0;
+            assert_eq!(collam.check_heap(), 1);
+            collam.dealloc(ptr, layout);
+            assert_eq!(collam.check_heap(), 0);
+            assert_eq!(collam.stats().used_blocks, 1);
+        }
+    }
+
+    #[test]
+    fn test_collam_buddy_backend() {
+        unsafe {
+            let collam = Collam::with_source(MmapSource::new(16 << 20));
+            let layout = util::pad_min_align(3000).expect("unable to align layout");
+            let list_ptr = collam.alloc(layout);
+            assert!(!list_ptr.is_null());
+
+            collam.configure(Options {
+                backend: Backend::Buddy,
+                ..Options::new()
+            });
+            let ptr = collam.alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(collam.usable_size(ptr), 4096 - BLOCK_META_SIZE);
+            write_bytes(ptr, 1, 3000);
+
+            // Shrinking returns the upper halves to the buddy allocator
+            let ptr = collam.realloc(ptr, layout, 400);
+            assert_eq!(collam.usable_size(ptr), 512 - BLOCK_META_SIZE);
+            assert_eq!(*ptr.add(399), 1);
+
+            // Blocks are freed by the backend which handed them out
+            collam.dealloc(list_ptr, layout);
+            collam.dealloc(ptr, layout);
+            let stats = collam.stats();
+            assert_eq!(stats.used_blocks, 0);
+            assert_eq!(stats.used_bytes, 0);
+        }
+    }
+
+    /// Frees the first and third of four blocks and returns the address chosen for `size` bytes.
+    unsafe fn reused_block<F: FitPolicy>(policy: F, size: usize) -> (usize, [usize; 4]) {
+        let collam = Collam::with_policy(MmapSource::new(1 << 20), policy);
+        let layout = |size| util::pad_min_align(size).expect("unable to align layout");
+        let mut ptrs = [0; 4];
+        for (ptr, size) in ptrs.iter_mut().zip([2048, 512, 768, 512].iter()) {
+            *ptr = collam.alloc(layout(*size)) as usize;
+        }
+        collam.dealloc(ptrs[0] as *mut u8, layout(2048));
+        collam.dealloc(ptrs[2] as *mut u8, layout(768));
+        (collam.alloc(layout(size)) as usize, ptrs)
+    }
+
+    #[test]
+    fn test_collam_fit_policy() {
+        unsafe {
+            let (ptr, ptrs) = reused_block(FirstFit, 700);
+            assert_eq!(ptr, ptrs[0]);
+            let (ptr, ptrs) = reused_block(fit::BestFit, 700);
+            assert_eq!(ptr, ptrs[2]);
+            let (ptr, ptrs) = reused_block(fit::WorstFit, 700);
+            assert_eq!(ptr, ptrs[0]);
+        }
+    }
 }
@@ -0,0 +1,305 @@
+//! Runtime configuration of the allocator.
+//!
+//! Options are given as comma separated `key=value` pairs, e.g. `trim_threshold=128k,abort_on_corruption=1`.
+//! Parsing works on byte slices and never allocates, so it can run before the allocator is usable.
+use crate::alloc::block::BLOCK_SPLIT_MIN_SIZE;
+use crate::alloc::redzone::REDZONE_MAX_SIZE;
+use crate::util;
+
+/// Poison byte used if `poison` is given without a value.
+pub const DEFAULT_POISON: u8 = 0xA5;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OptionError {
+    /// The key is not known by this parser.
+    UnknownKey,
+    /// The value can't be parsed or is out of range.
+    InvalidValue,
+}
+
+/// Strategy used to manage blocks which are not served by the slab.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Address ordered free list with first fit placement (`list`).
+    List,
+    /// Binary buddy system, sizes are rounded up to powers of two (`buddy`).
+    Buddy,
+}
+
+impl Backend {
+    /// Parses a backend name as used in option strings.
+    pub fn from_bytes(name: &[u8]) -> Option<Self> {
+        match name {
+            b"list" => Some(Backend::List),
+            b"buddy" => Some(Backend::Buddy),
+            _ => None,
+        }
+    }
+}
+
+/// Placement of allocations relative to their guard page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuardMode {
+    /// Allocations are served by the regular heap (`off`).
+    Disabled,
+    /// The memory region ends right at the guard page to detect overflows (`overflow`).
+    /// Overflows by less than the alignment padding stay undetected.
+    Overflow,
+    /// The memory region starts right behind the guard page to detect underflows (`underflow`).
+    Underflow,
+}
+
+impl GuardMode {
+    /// Parses a guard mode as used in option strings.
+    pub fn from_bytes(name: &[u8]) -> Option<Self> {
+        match name {
+            b"off" | b"0" => Some(GuardMode::Disabled),
+            b"overflow" | b"1" => Some(GuardMode::Overflow),
+            b"underflow" => Some(GuardMode::Underflow),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// Minimum size of the remaining memory region when splitting a block (`split_min`).
+    /// Smaller remainders stay part of the allocated block.
+    pub split_min_size: usize,
+    /// Minimum size of a free block at the end of the heap
+    /// before it is returned to the memory source (`trim_threshold`).
+    pub trim_threshold: usize,
+    /// Aborts the process if heap corruption or a double free is detected (`abort_on_corruption`).
+    pub abort_on_corruption: bool,
+    /// Serves small allocations from size class slabs (`slab`).
+    pub slab: bool,
+    /// Backend for new allocations (`backend`), blocks are always freed by their owner.
+    pub backend: Backend,
+    /// Places every allocation on its own pages next to a guard page (`guard`).
+    pub guard: GuardMode,
+    /// Size of the redzones around every heap allocation in bytes (`redzone`),
+    /// rounded up to a multiple of `MIN_ALIGN`, 0 disables them.
+    pub redzone: usize,
+    /// Byte filling freed memory (`poison`), new allocations are filled with its complement.
+    /// Reused free blocks are checked for writes after free, 0 disables poisoning.
+    pub poison: u8,
+    /// Maximum usable bytes of freed blocks held back before they become reusable
+    /// (`quarantine`), 0 disables the quarantine.
+    pub quarantine_size: usize,
+    /// Maximum number of freed blocks in quarantine (`quarantine_blocks`).
+    pub quarantine_blocks: usize,
+    /// Randomizes the heap base, the chosen free blocks and slab slots (`randomize`).
+    pub randomize: bool,
+    /// Maximum bytes obtained from the memory source (`limit`), 0 disables the limit.
+    /// Allocations which would exceed it fail.
+    pub limit: usize,
+    /// Bytes obtained from the memory source before the heap is trimmed and the
+    /// out-of-memory handler is called (`soft_limit`), 0 disables the limit.
+    pub soft_limit: usize,
+}
+
+impl Options {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            split_min_size: BLOCK_SPLIT_MIN_SIZE,
+            trim_threshold: 0,
+            abort_on_corruption: false,
+            slab: true,
+            backend: Backend::List,
+            guard: GuardMode::Disabled,
+            redzone: 0,
+            poison: 0,
+            quarantine_size: 0,
+            quarantine_blocks: 4096,
+            randomize: false,
+            limit: 0,
+            soft_limit: 0,
+        }
+    }
+
+    /// Applies a single option.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<(), OptionError> {
+        match key {
+            b"split_min" => {
+                self.split_min_size = parse_size(value)
+                    .filter(|s| *s >= BLOCK_SPLIT_MIN_SIZE)
+                    .ok_or(OptionError::InvalidValue)?
+            }
+            b"trim_threshold" => {
+                self.trim_threshold = parse_size(value).ok_or(OptionError::InvalidValue)?
+            }
+            b"backend" => {
+                self.backend = Backend::from_bytes(value).ok_or(OptionError::InvalidValue)?
+            }
+            b"guard" => {
+                self.guard = GuardMode::from_bytes(value).ok_or(OptionError::InvalidValue)?
+            }
+            b"redzone" => {
+                self.redzone = parse_size(value)
+                    .filter(|s| *s <= REDZONE_MAX_SIZE)
+                    .map(util::min_align_unchecked)
+                    .ok_or(OptionError::InvalidValue)?
+            }
+            b"poison" if value.is_empty() => self.poison = DEFAULT_POISON,
+            b"poison" => {
+                self.poison = parse_size(value)
+                    .filter(|p| *p <= 0xFF)
+                    .ok_or(OptionError::InvalidValue)? as u8
+            }
+            b"quarantine" => {
+                self.quarantine_size = parse_size(value).ok_or(OptionError::InvalidValue)?
+            }
+            b"quarantine_blocks" => {
+                self.quarantine_blocks = parse_size(value).ok_or(OptionError::InvalidValue)?
+            }
+            b"limit" => self.limit = parse_size(value).ok_or(OptionError::InvalidValue)?,
+            b"soft_limit" => {
+                self.soft_limit = parse_size(value).ok_or(OptionError::InvalidValue)?
+            }
+            b"randomize" => self.randomize = parse_bool(value).ok_or(OptionError::InvalidValue)?,
+            b"slab" => self.slab = parse_bool(value).ok_or(OptionError::InvalidValue)?,
+            b"abort_on_corruption" => {
+                self.abort_on_corruption = parse_bool(value).ok_or(OptionError::InvalidValue)?
+            }
+            _ => return Err(OptionError::UnknownKey),
+        }
+        Ok(())
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns an iterator over the `(key, value)` pairs of an option string.
+/// Empty entries are skipped, entries without `=` have an empty value.
+pub fn pairs(spec: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    spec.split(|c| *c == b',')
+        .filter(|e| !e.is_empty())
+        .map(|entry| match entry.iter().position(|c| *c == b'=') {
+            Some(i) => (&entry[..i], &entry[i + 1..]),
+            None => (entry, &entry[entry.len()..]),
+        })
+}
+
+/// Parses a decimal number with an optional `k`, `m` or `g` suffix.
+/// Returns `None` on invalid input or overflow.
+pub fn parse_size(value: &[u8]) -> Option<usize> {
+    let (digits, shift) = match value.last()? {
+        b'k' | b'K' => (&value[..value.len() - 1], 10),
+        b'm' | b'M' => (&value[..value.len() - 1], 20),
+        b'g' | b'G' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let number = digits.iter().try_fold(0_usize, |acc, c| match c {
+        b'0'..=b'9' => acc.checked_mul(10)?.checked_add(usize::from(c - b'0')),
+        _ => None,
+    })?;
+    number.checked_mul(1 << shift)
+}
+
+/// Parses `1`, `true`, `on`, `0`, `false` and `off`, an empty value counts as `true`.
+pub fn parse_bool(value: &[u8]) -> Option<bool> {
+    match value {
+        b"" | b"1" | b"true" | b"on" => Some(true),
+        b"0" | b"false" | b"off" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_pairs() {
+        let pairs: Vec<_> = pairs(b"a=1,,b=,c,d=x=y").collect();
+        assert_eq!(
+            pairs,
+            [
+                (&b"a"[..], &b"1"[..]),
+                (b"b", b""),
+                (b"c", b""),
+                (b"d", b"x=y")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size(b"0"), Some(0));
+        assert_eq!(parse_size(b"4096"), Some(4096));
+        assert_eq!(parse_size(b"128k"), Some(128 << 10));
+        assert_eq!(parse_size(b"2M"), Some(2 << 20));
+        assert_eq!(parse_size(b"1g"), Some(1 << 30));
+        assert_eq!(parse_size(b""), None);
+        assert_eq!(parse_size(b"k"), None);
+        assert_eq!(parse_size(b"12x"), None);
+        assert_eq!(parse_size(b"99999999999999999999"), None);
+        assert_eq!(parse_size(b"99999999999999g"), None);
+    }
+
+    #[test]
+    fn test_options_set() {
+        let mut options = Options::new();
+        assert_eq!(options.set(b"trim_threshold", b"64k"), Ok(()));
+        assert_eq!(options.set(b"abort_on_corruption", b""), Ok(()));
+        assert_eq!(options.set(b"split_min", b"256"), Ok(()));
+        assert_eq!(options.set(b"backend", b"buddy"), Ok(()));
+        assert_eq!(options.set(b"guard", b"underflow"), Ok(()));
+        assert_eq!(options.set(b"redzone", b"20"), Ok(()));
+        assert_eq!(options.trim_threshold, 64 << 10);
+        assert_eq!(options.backend, Backend::Buddy);
+        assert_eq!(options.guard, GuardMode::Underflow);
+        assert_eq!(options.redzone, 32);
+        assert_eq!(options.set(b"poison", b""), Ok(()));
+        assert_eq!(options.poison, DEFAULT_POISON);
+        assert_eq!(options.set(b"poison", b"85"), Ok(()));
+        assert_eq!(options.poison, 85);
+        assert_eq!(options.set(b"quarantine", b"1m"), Ok(()));
+        assert_eq!(options.set(b"quarantine_blocks", b"100"), Ok(()));
+        assert_eq!(options.quarantine_size, 1 << 20);
+        assert_eq!(options.quarantine_blocks, 100);
+        assert_eq!(options.set(b"randomize", b""), Ok(()));
+        assert!(options.randomize);
+        assert_eq!(options.set(b"limit", b"1g"), Ok(()));
+        assert_eq!(options.set(b"soft_limit", b"512m"), Ok(()));
+        assert_eq!((options.limit, options.soft_limit), (1 << 30, 512 << 20));
+        assert!(options.abort_on_corruption);
+        assert_eq!(options.split_min_size, 256);
+
+        assert_eq!(
+            options.set(b"split_min", b"0"),
+            Err(OptionError::InvalidValue)
+        );
+        assert_eq!(
+            options.set(b"abort_on_corruption", b"maybe"),
+            Err(OptionError::InvalidValue)
+        );
+        assert_eq!(
+            options.set(b"backend", b"tree"),
+            Err(OptionError::InvalidValue)
+        );
+        assert_eq!(
+            options.set(b"guard", b"below"),
+            Err(OptionError::InvalidValue)
+        );
+        assert_eq!(
+            options.set(b"redzone", b"1m"),
+            Err(OptionError::InvalidValue)
+        );
+        assert_eq!(
+            options.set(b"poison", b"256"),
+            Err(OptionError::InvalidValue)
+        );
+        assert_eq!(options.set(b"unknown", b"1"), Err(OptionError::UnknownKey));
+        assert_eq!(options.split_min_size, 256);
+    }
+}
@@ -0,0 +1,81 @@
+//! FIFO of freed blocks which are held back before they become reusable.
+//!
+//! Delaying reuse keeps new allocations away from dangling pointers for a while.
+//! Combined with poisoning, writes to quarantined blocks are detected once they leave.
+//! Quarantined blocks are marked free but are not part of the free list, so they can't be
+//! merged and freeing them again is still detected as double free.
+use crate::alloc::block::BlockPtr;
+
+pub struct Quarantine {
+    /// Oldest block, evicted first.
+    head: Option<BlockPtr>,
+    tail: Option<BlockPtr>,
+    /// Number of blocks held back.
+    pub blocks: usize,
+    /// Usable bytes of all blocks held back.
+    pub bytes: usize,
+}
+
+impl Quarantine {
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            tail: None,
+            blocks: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Appends a freed block, it is linked through its `next` field.
+    pub fn push(&mut self, mut block: BlockPtr) {
+        block.as_mut().unlink();
+        match self.tail {
+            Some(mut tail) => tail.as_mut().next = Some(block),
+            None => self.head = Some(block),
+        }
+        self.tail = Some(block);
+        self.blocks += 1;
+        self.bytes += block.size();
+    }
+
+    /// Removes and returns the oldest block.
+    pub fn pop(&mut self) -> Option<BlockPtr> {
+        let mut block = self.head?;
+        self.head = block.as_ref().next;
+        if self.head.is_none() {
+            self.tail = None;
+        }
+        block.as_mut().unlink();
+        self.blocks -= 1;
+        self.bytes -= block.size();
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::heap::Heap;
+
+    #[test]
+    fn test_quarantine_fifo() {
+        let mut heap = Heap::new();
+        let mut block1 = unsafe { heap.request(256).expect("unable to request block").0 };
+        let mut block2 = block1.shrink(56).expect("unable to split block");
+        let block3 = block2.shrink(56).expect("unable to split block");
+
+        let mut quarantine = Quarantine::new();
+        assert_eq!(quarantine.pop(), None);
+        quarantine.push(block1);
+        quarantine.push(block2);
+        assert_eq!(quarantine.pop(), Some(block1));
+        quarantine.push(block3);
+        assert_eq!(quarantine.blocks, 2);
+        assert_eq!(quarantine.bytes, block2.size() + block3.size());
+
+        assert_eq!(quarantine.pop(), Some(block2));
+        assert_eq!(quarantine.pop(), Some(block3));
+        assert_eq!(quarantine.pop(), None);
+        assert_eq!(quarantine.bytes, 0);
+    }
+}
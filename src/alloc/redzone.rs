@@ -0,0 +1,428 @@
+//! Redzones around heap allocations, a lightweight alternative to guard pages.
+//!
+//! The memory region of a block with redzones is laid out as
+//! `[meta][front redzone ..., locator][user region][back redzone ...]`.
+//! The metadata holds the requested size and links all live allocations for the heap checker,
+//! the locator word right in front of the user region leads back to the block header.
+//! Both redzones are filled with `REDZONE_PATTERN`, the back redzone covers everything from
+//! the end of the requested size to the end of the block, so even small overflows are detected.
+use core::ptr::Unique;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{cmp, intrinsics, mem};
+
+use spin::Mutex;
+
+use crate::alloc::block::{BlockPtr, BLOCK_META_SIZE};
+use crate::util;
+use crate::MIN_ALIGN;
+
+/// Byte pattern filling the redzones.
+pub const REDZONE_PATTERN: u8 = 0xFD;
+/// Largest accepted redzone size.
+pub const REDZONE_MAX_SIZE: usize = 64 << 10;
+/// Size of `RedzoneMeta` at the start of the memory region.
+const REDZONE_META_SIZE: usize = mem::size_of::<RedzoneMeta>();
+const WORD_SIZE: usize = mem::size_of::<usize>();
+/// Tag stored in the most significant byte of the locator word.
+const LOCATOR_TAG: usize = 0x5A;
+const LOCATOR_TAG_SHIFT: u32 = usize::max_value().count_ones() - 8;
+/// Number of corrupted bytes included in reports.
+const REPORT_BYTES: usize = 8;
+
+#[repr(C)]
+struct RedzoneMeta {
+    /// Size requested by the user.
+    size: usize,
+    /// Offset of the user region from the start of the memory region.
+    front: usize,
+    next: Option<BlockPtr>,
+    prev: Option<BlockPtr>,
+}
+
+/// Returns the region size needed to hold `size` bytes surrounded by redzones of `redzone` bytes.
+#[inline]
+pub fn padded_size(size: usize, redzone: usize) -> Option<usize> {
+    size.checked_add(REDZONE_META_SIZE + 2 * redzone)
+}
+
+#[inline]
+fn meta(block: BlockPtr) -> *mut RedzoneMeta {
+    block.mem_region().as_ptr().cast::<RedzoneMeta>()
+}
+
+#[inline]
+const fn locator(front: usize) -> usize {
+    (LOCATOR_TAG << LOCATOR_TAG_SHIFT) | (front + BLOCK_META_SIZE)
+}
+
+/// Returns the pointer handed out to the user for a block with redzones.
+///
+/// # Safety
+///
+/// `block` must carry redzones.
+#[inline]
+pub unsafe fn user_ptr(block: BlockPtr) -> *mut u8 {
+    block.mem_region().as_ptr().add((*meta(block)).front)
+}
+
+/// Returns the size requested by the user for a block with redzones.
+///
+/// # Safety
+///
+/// `block` must carry redzones.
+#[inline]
+pub unsafe fn usable_size(block: BlockPtr) -> usize {
+    (*meta(block)).size
+}
+
+/// Returns the offset of the first byte in `[start, start + len)` which differs from
+/// `expected(i)`, together with the number of differing bytes.
+unsafe fn find_corruption(
+    start: *const u8,
+    len: usize,
+    expected: impl Fn(usize) -> u8,
+) -> Option<(usize, usize)> {
+    let mut first = None;
+    let mut count = 0;
+    for i in 0..len {
+        if *start.add(i) != expected(i) {
+            first = first.or(Some(i));
+            count += 1;
+        }
+    }
+    first.map(|f| (f, count))
+}
+
+/// Tracks live allocations with redzones.
+pub struct Redzones {
+    /// Copy of `Options::redzone`.
+    size: AtomicUsize,
+    /// Number of live allocations with redzones, lookups are skipped if there are none.
+    live: AtomicUsize,
+    head: Mutex<Option<BlockPtr>>,
+}
+
+impl Redzones {
+    pub const fn new() -> Self {
+        Self {
+            size: AtomicUsize::new(0),
+            live: AtomicUsize::new(0),
+            head: Mutex::new(None),
+        }
+    }
+
+    /// Returns the redzone size for new allocations, 0 if disabled.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Changes the redzone size for new allocations, existing allocations keep their layout.
+    pub fn set_size(&self, size: usize) {
+        self.size.store(size, Ordering::Relaxed);
+    }
+
+    /// Lays out an allocated block with redzones around `size` bytes and returns the user pointer.
+    ///
+    /// # Safety
+    ///
+    /// `block` must be in use and have a region size of at least `padded_size(size, redzone)`.
+    pub unsafe fn init(&self, mut block: BlockPtr, size: usize, redzone: usize) -> *mut u8 {
+        debug_assert!(padded_size(size, redzone).map_or(false, |s| s <= block.size()));
+        let meta = meta(block);
+        let front = REDZONE_META_SIZE + redzone;
+        (*meta).size = size;
+        (*meta).front = front;
+        let region = block.mem_region().as_ptr();
+        let ptr = region.add(front);
+        intrinsics::write_bytes(
+            region.add(REDZONE_META_SIZE),
+            REDZONE_PATTERN,
+            redzone - WORD_SIZE,
+        );
+        *ptr.sub(WORD_SIZE).cast::<usize>() = locator(front);
+        intrinsics::write_bytes(ptr.add(size), REDZONE_PATTERN, block.size() - front - size);
+        block.as_mut().set_redzone(true);
+
+        let mut head = self.head.lock();
+        (*meta).prev = None;
+        (*meta).next = *head;
+        if let Some(next) = *head {
+            (*self::meta(next)).prev = Some(block);
+        }
+        *head = Some(block);
+        self.live.fetch_add(1, Ordering::Relaxed);
+        ptr
+    }
+
+    /// Unlinks a block from the live allocations and drops its redzone flag.
+    ///
+    /// # Safety
+    ///
+    /// `block` must have been laid out by `init`.
+    pub unsafe fn remove(&self, mut block: BlockPtr) {
+        let meta = meta(block);
+        let mut head = self.head.lock();
+        match (*meta).prev {
+            Some(prev) => (*self::meta(prev)).next = (*meta).next,
+            None => *head = (*meta).next,
+        }
+        if let Some(next) = (*meta).next {
+            (*self::meta(next)).prev = (*meta).prev;
+        }
+        block.as_mut().set_redzone(false);
+        self.live.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Returns the block of a user pointer with redzones, `None` for all other pointers.
+    /// If the locator has been overwritten, the block is searched at the configured redzone size.
+    pub fn lookup(&self, ptr: *mut u8) -> Option<BlockPtr> {
+        if self.live.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+        // SAFETY: pointers handed out by the heap are preceded by a block header or locator
+        let word = unsafe { ptr.cast::<usize>().sub(1).read_unaligned() };
+        let located = Some(word & !(0xFF << LOCATOR_TAG_SHIFT)).filter(|offset| {
+            word >> LOCATOR_TAG_SHIFT == LOCATOR_TAG
+                && offset.checked_sub(BLOCK_META_SIZE).map_or(false, |front| {
+                    (REDZONE_META_SIZE + MIN_ALIGN..=REDZONE_META_SIZE + REDZONE_MAX_SIZE)
+                        .contains(&front)
+                        && front % MIN_ALIGN == 0
+                })
+        });
+        let offset = if let Some(offset) = located {
+            offset
+        } else {
+            let plain = BlockPtr::from_mem_region(Unique::new(ptr)?)?;
+            let redzone = self.size();
+            if redzone == 0 || (plain.as_ref().verify() && !plain.as_ref().has_redzone()) {
+                return None;
+            }
+            REDZONE_META_SIZE + redzone + BLOCK_META_SIZE
+        };
+        let region = (ptr as usize).checked_sub(offset.checked_sub(BLOCK_META_SIZE)?)?;
+        let block = BlockPtr::from_mem_region(Unique::new(region as *mut u8)?)?;
+        let header = block.as_ref();
+        if header.verify() && header.in_use() && header.has_redzone() {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    /// Shrinks or grows the user region in-place if the block is large enough
+    /// to keep the back redzone, returns `false` otherwise.
+    ///
+    /// # Safety
+    ///
+    /// `block` must have been laid out by `init`.
+    pub unsafe fn resize(&self, block: BlockPtr, size: usize) -> bool {
+        let meta = meta(block);
+        let front = (*meta).front;
+        let redzone = front - REDZONE_META_SIZE;
+        match size.checked_add(front + redzone) {
+            Some(s) if s <= block.size() => {}
+            _ => return false,
+        }
+        let ptr = block.mem_region().as_ptr().add(front);
+        (*meta).size = size;
+        intrinsics::write_bytes(ptr.add(size), REDZONE_PATTERN, block.size() - front - size);
+        true
+    }
+
+    /// Verifies both redzones of a block and logs the offset and content of corrupted bytes,
+    /// `op` names the operation in reports. Returns `true` if the redzones are intact.
+    ///
+    /// # Safety
+    ///
+    /// `block` must have been laid out by `init`.
+    pub unsafe fn verify(&self, block: BlockPtr, op: &str) -> bool {
+        let meta = meta(block);
+        let (size, front) = ((*meta).size, (*meta).front);
+        let redzone = match front.checked_sub(REDZONE_META_SIZE + WORD_SIZE) {
+            Some(r) if front.checked_add(size).map_or(false, |e| e <= block.size()) => r,
+            _ => {
+                error!(
+                    Alloc,
+                    "{}: corrupted redzone metadata in {:?} (size={}, front={})",
+                    op,
+                    block,
+                    size,
+                    front
+                );
+                return false;
+            }
+        };
+        let region = block.mem_region().as_ptr();
+        let ptr = region.add(front);
+
+        let mut intact = true;
+        let locator = locator(front).to_ne_bytes();
+        let start = region.add(REDZONE_META_SIZE);
+        let expected = |i| {
+            if i < redzone {
+                REDZONE_PATTERN
+            } else {
+                locator[i - redzone]
+            }
+        };
+        if let Some((first, count)) = find_corruption(start, redzone + WORD_SIZE, expected) {
+            report(op, "underflow", ptr, start.add(first), ptr, count, block);
+            intact = false;
+        }
+        let end = ptr.add(size);
+        let back = block.size() - front - size;
+        if let Some((first, count)) = find_corruption(end, back, |_| REDZONE_PATTERN) {
+            let block_end = region.add(block.size());
+            report(op, "overflow", ptr, end.add(first), block_end, count, block);
+            intact = false;
+        }
+        intact
+    }
+
+    /// Verifies the redzones of all live allocations, returns the number of corrupted blocks.
+    pub fn check(&self) -> usize {
+        let head = self.head.lock();
+        let mut corrupted = 0;
+        let mut current = *head;
+        while let Some(block) = current {
+            // SAFETY: all blocks in the list have been laid out by `init`
+            unsafe {
+                if !self.verify(block, "heap check") {
+                    corrupted += 1;
+                }
+                current = (*meta(block)).next;
+            }
+        }
+        corrupted
+    }
+
+    /// Holds the lock until `unlock` is called, e.g. while forking.
+    pub fn lock(&self) {
+        util::hold(&self.head);
+    }
+
+    /// Releases the lock acquired by `lock`.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called after `lock`.
+    pub unsafe fn unlock(&self) {
+        self.head.force_unlock();
+    }
+}
+
+/// Logs a corrupted redzone, `first` points to the first corrupted byte
+/// and `end` to the end of the redzone.
+unsafe fn report(
+    op: &str,
+    kind: &str,
+    ptr: *mut u8,
+    first: *mut u8,
+    end: *mut u8,
+    count: usize,
+    block: BlockPtr,
+) {
+    let offset = first as isize - ptr as isize;
+    let len = cmp::min(REPORT_BYTES, end as usize - first as usize);
+    let bytes = core::slice::from_raw_parts(first, len);
+    error!(
+        Alloc,
+        "{}: buffer {} of {:p} in {:?}, {} bytes corrupted starting at offset {}: {:02x?}",
+        op,
+        kind,
+        ptr,
+        block,
+        count,
+        offset,
+        bytes
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::block;
+    use std::vec::Vec;
+
+    /// Allocated block with the given region size at a properly aligned address.
+    fn block(buf: &mut Vec<u128>, size: usize) -> BlockPtr {
+        buf.resize(size / 16 + 2, 0);
+        let ptr = unsafe { buf.as_mut_ptr().cast::<u8>().add(BLOCK_META_SIZE) };
+        let mut block = BlockPtr::new(Unique::new(ptr).unwrap(), size);
+        block.as_mut().set_in_use(true);
+        block
+    }
+
+    #[test]
+    fn test_redzone_layout() {
+        unsafe {
+            let redzones = Redzones::new();
+            redzones.set_size(32);
+            let size = block::region_size(padded_size(100, 32).unwrap()).unwrap();
+            let mut buf = Vec::new();
+            let block = block(&mut buf, size);
+
+            let ptr = redzones.init(block, 100, 32);
+            assert_eq!(ptr as usize % MIN_ALIGN, 0);
+            assert_eq!(ptr, user_ptr(block));
+            assert_eq!(usable_size(block), 100);
+            assert_eq!(*ptr.sub(1), LOCATOR_TAG as u8);
+            assert_eq!(*ptr.add(100), REDZONE_PATTERN);
+            assert_eq!(redzones.lookup(ptr), Some(block));
+            assert!(redzones.verify(block, "test"));
+            assert_eq!(redzones.check(), 0);
+
+            // Growing in-place keeps the back redzone
+            assert!(redzones.resize(block, 104));
+            assert!(!redzones.resize(block, size));
+            assert!(redzones.verify(block, "test"));
+
+            redzones.remove(block);
+            assert!(!block.as_ref().has_redzone());
+            assert_eq!(redzones.lookup(ptr), None);
+        }
+    }
+
+    #[test]
+    fn test_redzone_corruption() {
+        unsafe {
+            let redzones = Redzones::new();
+            let size = block::region_size(padded_size(64, 16).unwrap()).unwrap();
+            let mut buf1 = Vec::new();
+            let mut buf2 = Vec::new();
+            let block1 = block(&mut buf1, size);
+            let block2 = block(&mut buf2, size);
+            let ptr1 = redzones.init(block1, 64, 16);
+            let ptr2 = redzones.init(block2, 60, 16);
+
+            // Overflow into the padding of an unaligned size
+            *ptr2.add(61) = 0;
+            assert!(redzones.verify(block1, "test"));
+            assert!(!redzones.verify(block2, "test"));
+            assert_eq!(redzones.check(), 1);
+
+            // Overwritten locator, the block is found at the configured size
+            *ptr1.sub(1) = 0;
+            assert_eq!(redzones.lookup(ptr1), None);
+            redzones.set_size(16);
+            assert_eq!(redzones.lookup(ptr1), Some(block1));
+            assert!(!redzones.verify(block1, "test"));
+            assert_eq!(redzones.check(), 2);
+
+            // Overwritten locator offset
+            redzones.set_size(0);
+            *ptr1.sub(4) = 0x7F;
+            assert_eq!(redzones.lookup(ptr1), None);
+            // Tagged locator with an offset smaller than the block header
+            ptr1.cast::<usize>()
+                .sub(1)
+                .write_unaligned(LOCATOR_TAG << LOCATOR_TAG_SHIFT | 1);
+            assert_eq!(redzones.lookup(ptr1), None);
+
+            redzones.remove(block1);
+            redzones.remove(block2);
+            assert_eq!(redzones.check(), 0);
+        }
+    }
+}
@@ -0,0 +1,502 @@
+//! Slab allocator for small objects.
+//!
+//! Objects of up to `SLAB_MAX_SIZE` bytes are served from pages dedicated to a single size class.
+//! Objects carry no header, the size class and the free slots of a page are stored out-of-band
+//! in a metadata table at the start of a reserved region, so ownership of a pointer is derived
+//! from its address alone.
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::{cmp, mem};
+
+use spin::Mutex;
+
+use crate::sources::PAGE_SIZE;
+use crate::util::{self, Rng};
+use crate::MIN_ALIGN;
+
+/// Largest object size served by the slab allocator.
+pub const SLAB_MAX_SIZE: usize = 256;
+/// Size of a slab page, larger system pages are shared by several slab pages.
+pub const SLAB_PAGE_SIZE: usize = 4096;
+/// Reserved address space for slab pages and their metadata.
+pub const SLAB_REGION_SIZE: usize = 64 << 20;
+
+/// Number of size classes, one per `MIN_ALIGN` step.
+const SLAB_CLASSES: usize = SLAB_MAX_SIZE / MIN_ALIGN;
+const SLAB_PAGES: usize = SLAB_REGION_SIZE / SLAB_PAGE_SIZE;
+/// Number of bitmap words required for the smallest size class.
+const BITMAP_WORDS: usize = SLAB_PAGE_SIZE / MIN_ALIGN / 64;
+/// Pages at the start of the region which hold the metadata table.
+const META_PAGES: usize =
+    (SLAB_PAGES * mem::size_of::<PageMeta>() + SLAB_PAGE_SIZE - 1) / SLAB_PAGE_SIZE;
+/// Marks the end of a page list, page 0 always holds metadata.
+const NONE: u32 = 0;
+
+/// Metadata of a single slab page, stored in the zero initialized metadata table.
+#[derive(Copy, Clone)]
+struct PageMeta {
+    /// Bit `i` is set if slot `i` is in use.
+    bitmap: [u64; BITMAP_WORDS],
+    prev: u32,
+    next: u32,
+    /// Size class + 1, 0 for unused pages.
+    class: u16,
+    used: u16,
+}
+
+/// Returns the size class for the given size.
+#[inline]
+fn class_of(size: usize) -> usize {
+    debug_assert!(size > 0 && size <= SLAB_MAX_SIZE);
+    (size + MIN_ALIGN - 1) / MIN_ALIGN - 1
+}
+
+/// Returns the object size of the given size class.
+#[inline]
+const fn class_size(class: usize) -> usize {
+    (class + 1) * MIN_ALIGN
+}
+
+/// Returns the number of objects per page of the given size class.
+#[inline]
+const fn class_capacity(class: usize) -> usize {
+    SLAB_PAGE_SIZE / class_size(class)
+}
+
+/// Returns the number of slab pages per system page.
+#[inline]
+fn group_pages() -> usize {
+    cmp::max(*PAGE_SIZE / SLAB_PAGE_SIZE, 1)
+}
+
+/// Returns the size in which memory is charged and given back to the system,
+/// which happens once all slab pages of a system page are unused.
+#[inline]
+pub fn group_size() -> usize {
+    group_pages() * SLAB_PAGE_SIZE
+}
+
+struct SlabState {
+    /// Start of the reserved region, 0 if not yet reserved.
+    base: usize,
+    /// Head of the list of pages with free slots for each size class.
+    partial: [u32; SLAB_CLASSES],
+    /// Head of the list of released pages.
+    free: u32,
+    /// Next page which has never been used.
+    next_page: u32,
+    /// Number of pages assigned to a size class.
+    pages: usize,
+    objects: usize,
+    /// Picks random slots if set.
+    rng: Option<Rng>,
+}
+
+impl SlabState {
+    #[inline]
+    fn meta(&mut self, page: u32) -> &mut PageMeta {
+        debug_assert!(page as usize >= META_PAGES && (page as usize) < SLAB_PAGES);
+        // SAFETY: the metadata table spans all pages of the reserved region
+        unsafe { &mut *(self.base as *mut PageMeta).add(page as usize) }
+    }
+
+    #[inline]
+    fn page_addr(&self, page: u32) -> usize {
+        self.base + page as usize * SLAB_PAGE_SIZE
+    }
+
+    /// Returns the first page of the system page containing `page`.
+    #[inline]
+    fn group_start(page: u32) -> u32 {
+        page - page % group_pages() as u32
+    }
+
+    /// Returns `true` if any other page sharing the system page of `page` is in use.
+    fn group_in_use(&mut self, page: u32) -> bool {
+        let start = Self::group_start(page);
+        (start..start + group_pages() as u32).any(|p| p != page && self.meta(p).class != 0)
+    }
+
+    unsafe fn reserve(&mut self) -> Option<usize> {
+        let ptr = libc::mmap(
+            null_mut(),
+            SLAB_REGION_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            error!(Heap, "unable to reserve slab region");
+            return None;
+        }
+        trace!(Heap, "reserved slab region at {:p}", ptr);
+        self.base = ptr as usize;
+        // Slab pages must not share a system page with the metadata table
+        self.next_page = Self::group_start(META_PAGES as u32 + group_pages() as u32 - 1);
+        Some(self.base)
+    }
+
+    /// Returns an empty page assigned to the given size class, the memory of its
+    /// system page is charged with `reserve` beforehand unless it is already in use.
+    fn take_page(&mut self, class: usize, reserve: impl FnOnce(usize) -> bool) -> Option<u32> {
+        let page = if self.free != NONE {
+            self.free
+        } else if (self.next_page as usize) < SLAB_PAGES {
+            self.next_page
+        } else {
+            return None;
+        };
+        if !self.group_in_use(page) && !reserve(group_size()) {
+            return None;
+        }
+        if page == self.free {
+            self.free = self.meta(page).next;
+        } else {
+            self.next_page += 1;
+        }
+        *self.meta(page) = PageMeta {
+            bitmap: [0; BITMAP_WORDS],
+            prev: NONE,
+            next: NONE,
+            class: class as u16 + 1,
+            used: 0,
+        };
+        self.pages += 1;
+        Some(page)
+    }
+
+    fn push_partial(&mut self, class: usize, page: u32) {
+        let head = self.partial[class];
+        if head != NONE {
+            self.meta(head).prev = page;
+        }
+        let meta = self.meta(page);
+        meta.prev = NONE;
+        meta.next = head;
+        self.partial[class] = page;
+    }
+
+    fn unlink_partial(&mut self, class: usize, page: u32) {
+        let (prev, next) = {
+            let meta = self.meta(page);
+            (meta.prev, meta.next)
+        };
+        if prev == NONE {
+            self.partial[class] = next;
+        } else {
+            self.meta(prev).next = next;
+        }
+        if next != NONE {
+            self.meta(next).prev = prev;
+        }
+    }
+
+    unsafe fn alloc(&mut self, size: usize, reserve: impl FnOnce(usize) -> bool) -> *mut u8 {
+        if self.base == 0 && self.reserve().is_none() {
+            return null_mut();
+        }
+        let class = class_of(size);
+        let mut page = self.partial[class];
+        if page == NONE {
+            page = match self.take_page(class, reserve) {
+                Some(p) => p,
+                None => return null_mut(),
+            };
+            self.push_partial(class, page);
+        }
+
+        let capacity = class_capacity(class);
+        let start = self.rng.as_mut().map(|rng| rng.below(capacity));
+        let meta = self.meta(page);
+        let slot = match start {
+            // Use the first free slot at or after a random one
+            Some(start) => (start..capacity)
+                .chain(0..start)
+                .find(|s| meta.bitmap[s / 64] & (1 << (s % 64)) == 0),
+            None => meta
+                .bitmap
+                .iter()
+                .enumerate()
+                .find(|(_, w)| **w != u64::max_value())
+                .map(|(i, w)| i * 64 + (!*w).trailing_zeros() as usize),
+        };
+        let slot = match slot {
+            Some(slot) => slot,
+            None => return null_mut(),
+        };
+        debug_assert!(slot < capacity);
+        meta.bitmap[slot / 64] |= 1 << (slot % 64);
+        meta.used += 1;
+        if meta.used as usize == capacity {
+            self.unlink_partial(class, page);
+        }
+        self.objects += 1;
+        (self.page_addr(page) + slot * class_size(class)) as *mut u8
+    }
+
+    /// Returns the page, size class and slot of an object, `None` for invalid pointers.
+    fn locate(&mut self, ptr: *mut u8) -> Option<(u32, usize, usize)> {
+        let offset = (ptr as usize).checked_sub(self.base)?;
+        let page = (offset / SLAB_PAGE_SIZE) as u32;
+        if (page as usize) < META_PAGES || page >= self.next_page {
+            return None;
+        }
+        let class = (self.meta(page).class as usize).checked_sub(1)?;
+        let offset = offset % SLAB_PAGE_SIZE;
+        if offset % class_size(class) != 0 {
+            return None;
+        }
+        Some((page, class, offset / class_size(class)))
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, release: impl FnOnce(usize)) {
+        let (page, class, slot) = match self.locate(ptr) {
+            Some(l) => l,
+            None => {
+                error!(Heap, "free(): invalid slab pointer {:p}", ptr);
+                return;
+            }
+        };
+        let capacity = class_capacity(class);
+        let meta = self.meta(page);
+        let mask = 1 << (slot % 64);
+        if meta.bitmap[slot / 64] & mask == 0 {
+            error!(Heap, "double free detected for ptr {:p}", ptr);
+            return;
+        }
+        meta.bitmap[slot / 64] &= !mask;
+        meta.used -= 1;
+        let (used, next) = (meta.used as usize, meta.next);
+        self.objects -= 1;
+
+        if used + 1 == capacity {
+            self.push_partial(class, page);
+        } else if used == 0 && (self.partial[class] != page || next != NONE) {
+            // Give back empty pages unless it is the last partial page of its class
+            self.unlink_partial(class, page);
+            self.meta(page).class = 0;
+            self.meta(page).next = self.free;
+            self.free = page;
+            self.pages -= 1;
+            // Other pages of the same system page may still hold objects
+            if !self.group_in_use(page) {
+                libc::madvise(
+                    self.page_addr(Self::group_start(page)) as *mut libc::c_void,
+                    group_size(),
+                    libc::MADV_DONTNEED,
+                );
+                release(group_size());
+            }
+        }
+    }
+}
+
+/// Size class allocator for objects of up to `SLAB_MAX_SIZE` bytes.
+pub struct Slab {
+    /// Copy of `SlabState::base`, allows to check ownership without locking.
+    base: AtomicUsize,
+    state: Mutex<SlabState>,
+}
+
+impl Slab {
+    pub const fn new() -> Self {
+        Self {
+            base: AtomicUsize::new(0),
+            state: Mutex::new(SlabState {
+                base: 0,
+                partial: [NONE; SLAB_CLASSES],
+                free: NONE,
+                next_page: 0,
+                pages: 0,
+                objects: 0,
+                rng: None,
+            }),
+        }
+    }
+
+    /// Enables or disables random slot selection, seeded by `getrandom(2)`.
+    pub fn set_randomize(&self, enabled: bool) {
+        let mut state = self.state.lock();
+        if !enabled {
+            state.rng = None;
+        } else if state.rng.is_none() {
+            state.rng = Some(Rng::from_entropy());
+        }
+    }
+
+    /// Returns `true` if `ptr` points into the slab region.
+    #[inline]
+    pub fn contains(&self, ptr: *mut u8) -> bool {
+        let base = self.base.load(Ordering::Acquire);
+        base != 0 && (ptr as usize).wrapping_sub(base) < SLAB_REGION_SIZE
+    }
+
+    /// Returns an object of at least `size` bytes or null if the region is exhausted.
+    /// New pages are charged with `reserve`, the allocation fails if it returns `false`.
+    pub fn alloc(&self, size: usize, reserve: impl FnOnce(usize) -> bool) -> *mut u8 {
+        let mut state = self.state.lock();
+        // SAFETY: we know it is thread safe, because we're locking the mutex
+        let ptr = unsafe { state.alloc(size, reserve) };
+        self.base.store(state.base, Ordering::Release);
+        ptr
+    }
+
+    /// Releases an object, `ptr` must be contained in the slab region.
+    /// Pages given back to the system are passed to `release`.
+    pub fn dealloc(&self, ptr: *mut u8, release: impl FnOnce(usize)) {
+        debug_assert!(self.contains(ptr));
+        // SAFETY: we know it is thread safe, because we're locking the mutex
+        unsafe { self.state.lock().dealloc(ptr, release) }
+    }
+
+    /// Returns the usable size of the object at `ptr`, 0 for invalid pointers.
+    pub fn usable_size(&self, ptr: *mut u8) -> usize {
+        self.state
+            .lock()
+            .locate(ptr)
+            .map_or(0, |(_, class, _)| class_size(class))
+    }
+
+    /// Returns the number of live objects and the bytes of pages assigned to size classes.
+    pub fn stats(&self) -> (usize, usize) {
+        let state = self.state.lock();
+        (state.objects, state.pages * SLAB_PAGE_SIZE)
+    }
+
+    /// Holds the lock until `unlock` is called, e.g. while forking.
+    pub fn lock(&self) {
+        util::hold(&self.state);
+    }
+
+    /// Releases the lock acquired by `lock`.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called after `lock`.
+    pub unsafe fn unlock(&self) {
+        self.state.force_unlock();
+    }
+}
+
+impl Drop for Slab {
+    fn drop(&mut self) {
+        let base = self.state.lock().base;
+        if base != 0 {
+            // SAFETY: objects can't be used after the owning allocator has been dropped
+            unsafe { libc::munmap(base as *mut libc::c_void, SLAB_REGION_SIZE) };
+        }
+    }
+}
+
+/// Returns the size of the objects which are used for allocations of the given size.
+#[inline]
+pub fn object_size(size: usize) -> usize {
+    class_size(class_of(cmp::max(size, 1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    /// Returns the index of the page containing `ptr`.
+    fn page_of(slab: &Slab, ptr: *mut u8) -> usize {
+        (ptr as usize - slab.base.load(Ordering::Relaxed)) / SLAB_PAGE_SIZE
+    }
+
+    #[test]
+    fn test_size_classes() {
+        assert_eq!(class_of(1), 0);
+        assert_eq!(class_of(MIN_ALIGN), 0);
+        assert_eq!(class_of(MIN_ALIGN + 1), 1);
+        assert_eq!(class_of(SLAB_MAX_SIZE), SLAB_CLASSES - 1);
+        assert_eq!(object_size(7 * MIN_ALIGN - 1), 7 * MIN_ALIGN);
+        assert_eq!(class_capacity(0), SLAB_PAGE_SIZE / MIN_ALIGN);
+        assert!(class_capacity(0) <= BITMAP_WORDS * 64);
+        assert_eq!(group_size() % *PAGE_SIZE, 0);
+    }
+
+    #[test]
+    fn test_slab_alloc_dealloc() {
+        let slab = Slab::new();
+        let ptr1 = slab.alloc(2 * MIN_ALIGN - 1, |_| true);
+        let ptr2 = slab.alloc(2 * MIN_ALIGN, |_| true);
+        assert!(!ptr1.is_null() && !ptr2.is_null());
+        assert!(slab.contains(ptr1));
+        assert!(!slab.contains(&slab as *const _ as *mut u8));
+        assert_eq!(ptr1 as usize % MIN_ALIGN, 0);
+        // Objects of the same class are packed without headers
+        assert_eq!(ptr2 as usize - ptr1 as usize, 2 * MIN_ALIGN);
+        assert_eq!(slab.usable_size(ptr1), 2 * MIN_ALIGN);
+        assert_eq!(slab.stats(), (2, SLAB_PAGE_SIZE));
+
+        slab.dealloc(ptr1, |_| ());
+        assert_eq!(slab.alloc(MIN_ALIGN + 1, |_| true), ptr1);
+        slab.dealloc(ptr1, |_| ());
+        slab.dealloc(ptr2, |_| ());
+        assert_eq!(slab.stats().0, 0);
+    }
+
+    #[test]
+    fn test_slab_invalid_free() {
+        let slab = Slab::new();
+        let ptr = slab.alloc(64, |_| true);
+        slab.dealloc(ptr, |_| ());
+        // Double free and misaligned pointers are detected and ignored
+        slab.dealloc(ptr, |_| ());
+        slab.dealloc(unsafe { ptr.add(MIN_ALIGN) }, |_| ());
+        assert_eq!(slab.stats().0, 0);
+    }
+
+    #[test]
+    fn test_slab_pages() {
+        let slab = Slab::new();
+        let capacity = class_capacity(class_of(256));
+        let charged = Cell::new(0);
+        let mut ptrs = [null_mut(); 64];
+        for ptr in ptrs.iter_mut() {
+            *ptr = slab.alloc(256, |size| {
+                charged.set(charged.get() + size);
+                true
+            });
+            assert!(!ptr.is_null());
+        }
+        assert_eq!(slab.stats().1, 64 / capacity * SLAB_PAGE_SIZE);
+        // Pages sharing a system page are charged once
+        let pages = 64 / capacity;
+        let groups = (pages + group_pages() - 1) / group_pages();
+        assert_eq!(charged.get(), groups * group_size());
+        // Empty pages are given back, except for the last one of a class
+        for ptr in ptrs.iter() {
+            slab.dealloc(*ptr, |size| charged.set(charged.get() - size));
+        }
+        assert_eq!(slab.stats(), (0, SLAB_PAGE_SIZE));
+        assert_eq!(charged.get(), group_size());
+        // Released pages are reused
+        let ptr = slab.alloc(16, |_| true);
+        assert!(ptrs
+            .iter()
+            .any(|p| page_of(&slab, *p) == page_of(&slab, ptr)));
+    }
+
+    #[test]
+    fn test_slab_randomize() {
+        let slab = Slab::new();
+        slab.set_randomize(true);
+        let mut ptrs = [null_mut(); 32];
+        for ptr in ptrs.iter_mut() {
+            *ptr = slab.alloc(16, |_| true);
+            assert!(!ptr.is_null());
+        }
+        let page = page_of(&slab, ptrs[0]);
+        assert!(ptrs.iter().all(|p| page_of(&slab, *p) == page));
+        // Slots are no longer handed out in address order
+        assert!(ptrs.windows(2).any(|w| w[1] as usize != w[0] as usize + 16));
+        for (i, ptr) in ptrs.iter().enumerate() {
+            assert!(!ptrs[i + 1..].contains(ptr));
+            slab.dealloc(*ptr, |_| ());
+        }
+        assert_eq!(slab.stats().0, 0);
+    }
+}
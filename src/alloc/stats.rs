@@ -0,0 +1,189 @@
+use core::{cmp, fmt, mem};
+
+use crate::alloc::block::BLOCK_META_SIZE;
+
+/// Number of power-of-two buckets in `HeapStats::free_histogram`.
+pub const FREE_HISTOGRAM_BUCKETS: usize = 32;
+
+/// Snapshot of the heap state.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes currently obtained from the memory source, including metadata,
+    /// slab pages and guarded pages.
+    pub heap_size: usize,
+    /// Highest value `heap_size` has reached.
+    pub peak_heap_size: usize,
+    /// Bytes of the memory source in use which are mapped from the huge page pool or
+    /// advised for transparent huge pages while they are enabled.
+    pub huge_page_bytes: usize,
+    /// Number of blocks handed out to the user.
+    pub used_blocks: usize,
+    /// Usable bytes of all blocks handed out to the user.
+    pub used_bytes: usize,
+    /// Number of blocks in the free list.
+    pub free_blocks: usize,
+    /// Usable bytes in the free list, excluding metadata.
+    pub free_bytes: usize,
+    /// Usable size of the largest block in the free list.
+    pub largest_free_block: usize,
+    /// Number of live objects in the slab allocator.
+    pub slab_objects: usize,
+    /// Bytes of pages assigned to slab size classes.
+    pub slab_size: usize,
+    /// Number of live allocations served by the guard page allocator.
+    pub guard_allocations: usize,
+    /// Number of reused free blocks whose poison has been overwritten.
+    pub write_after_free: usize,
+    /// Number of freed blocks held back in quarantine.
+    pub quarantine_blocks: usize,
+    /// Usable bytes of all blocks in quarantine.
+    pub quarantine_bytes: usize,
+    /// Number of free blocks per size class, bucket `i` counts blocks of `[2^i, 2^(i+1))` bytes.
+    /// The last bucket holds all blocks of `2^31` bytes and more.
+    pub free_histogram: [usize; FREE_HISTOGRAM_BUCKETS],
+}
+
+impl HeapStats {
+    /// Returns the fraction of the heap which is held in the free list.
+    #[must_use]
+    pub fn free_ratio(&self) -> f64 {
+        ratio(self.free_bytes, self.heap_size)
+    }
+
+    /// Returns the external fragmentation of the free list as `1 - largest_free_block / free_bytes`.
+    /// 0 means all free memory is available in one block, values close to 1 indicate
+    /// that free memory is scattered over many small blocks.
+    #[must_use]
+    pub fn external_fragmentation(&self) -> f64 {
+        if self.free_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - ratio(self.largest_free_block, self.free_bytes)
+    }
+
+    /// Returns the fraction of the heap which is occupied by block metadata.
+    #[must_use]
+    pub fn metadata_overhead(&self) -> f64 {
+        ratio(
+            (self.used_blocks + self.free_blocks) * BLOCK_META_SIZE,
+            self.heap_size,
+        )
+    }
+}
+
+impl Default for HeapStats {
+    fn default() -> Self {
+        Self {
+            heap_size: 0,
+            peak_heap_size: 0,
+            huge_page_bytes: 0,
+            used_blocks: 0,
+            used_bytes: 0,
+            free_blocks: 0,
+            free_bytes: 0,
+            largest_free_block: 0,
+            slab_objects: 0,
+            slab_size: 0,
+            guard_allocations: 0,
+            write_after_free: 0,
+            quarantine_blocks: 0,
+            quarantine_bytes: 0,
+            free_histogram: [0; FREE_HISTOGRAM_BUCKETS],
+        }
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "heap size:              {}", self.heap_size)?;
+        writeln!(f, "peak heap size:         {}", self.peak_heap_size)?;
+        writeln!(f, "huge page bytes:        {}", self.huge_page_bytes)?;
+        writeln!(f, "used blocks:            {}", self.used_blocks)?;
+        writeln!(f, "used bytes:             {}", self.used_bytes)?;
+        writeln!(f, "free blocks:            {}", self.free_blocks)?;
+        writeln!(f, "free bytes:             {}", self.free_bytes)?;
+        writeln!(f, "largest free block:     {}", self.largest_free_block)?;
+        writeln!(f, "slab objects:           {}", self.slab_objects)?;
+        writeln!(f, "slab size:              {}", self.slab_size)?;
+        writeln!(f, "guard allocations:      {}", self.guard_allocations)?;
+        writeln!(f, "write after free:       {}", self.write_after_free)?;
+        writeln!(f, "quarantine blocks:      {}", self.quarantine_blocks)?;
+        writeln!(f, "quarantine bytes:       {}", self.quarantine_bytes)?;
+        writeln!(
+            f,
+            "external fragmentation: {:.4}",
+            self.external_fragmentation()
+        )?;
+        writeln!(f, "metadata overhead:      {:.4}", self.metadata_overhead())?;
+        writeln!(f, "free block histogram:")?;
+        for (i, count) in self.free_histogram.iter().enumerate() {
+            if *count > 0 {
+                writeln!(f, "  2^{}: {}", i, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the histogram bucket for the given block size.
+#[inline]
+pub fn histogram_bucket(size: usize) -> usize {
+    debug_assert!(size > 0);
+    let log2 = mem::size_of::<usize>() * 8 - 1 - size.leading_zeros() as usize;
+    cmp::min(log2, FREE_HISTOGRAM_BUCKETS - 1)
+}
+
+#[inline]
+fn ratio(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        return 0.0;
+    }
+    part as f64 / total as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_free_ratio() {
+        let mut stats = HeapStats::default();
+        assert!(stats.free_ratio().abs() < f64::EPSILON);
+        stats.heap_size = 4096;
+        stats.free_bytes = 1024;
+        assert!((stats.free_ratio() - 0.25).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_external_fragmentation() {
+        let mut stats = HeapStats::default();
+        assert!(stats.external_fragmentation().abs() < f64::EPSILON);
+        stats.free_bytes = 1024;
+        stats.largest_free_block = 256;
+        assert!((stats.external_fragmentation() - 0.75).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_metadata_overhead() {
+        let stats = HeapStats {
+            heap_size: BLOCK_META_SIZE * 16,
+            used_blocks: 3,
+            free_blocks: 1,
+            ..HeapStats::default()
+        };
+        assert!((stats.metadata_overhead() - 0.25).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_histogram_bucket() {
+        assert_eq!(histogram_bucket(1), 0);
+        assert_eq!(histogram_bucket(16), 4);
+        assert_eq!(histogram_bucket(31), 4);
+        assert_eq!(histogram_bucket(32), 5);
+        assert_eq!(histogram_bucket(1 << 31), FREE_HISTOGRAM_BUCKETS - 1);
+        assert_eq!(
+            histogram_bucket(usize::max_value()),
+            FREE_HISTOGRAM_BUCKETS - 1
+        );
+    }
+}
@@ -0,0 +1,287 @@
+//! AVL tree index of large free blocks, keyed by size and address.
+//!
+//! Nodes are stored in the memory region of the free blocks right after the list links,
+//! so the index doesn't need any memory on its own.
+use core::cmp;
+
+use crate::alloc::block::{Block, BlockPtr};
+
+/// Minimum size of a free block to be indexed.
+pub const TREE_MIN_SIZE: usize = 512;
+/// Bytes occupied by the node in the memory region of an indexed block.
+pub const TREE_NODE_SIZE: usize = core::mem::size_of::<Node>();
+
+#[repr(C)]
+struct Node {
+    left: Option<BlockPtr>,
+    right: Option<BlockPtr>,
+    height: usize,
+}
+
+#[inline]
+fn node<'a>(mut block: BlockPtr) -> &'a mut Node {
+    debug_assert!(block.size() >= TREE_MIN_SIZE);
+    // SAFETY: indexed blocks are large enough to hold the node behind the `Block` struct
+    unsafe { &mut *(block.as_mut() as *mut Block).add(1).cast::<Node>() }
+}
+
+#[inline]
+fn key(block: BlockPtr) -> (usize, usize) {
+    (block.size(), block.as_ptr() as usize)
+}
+
+#[inline]
+fn height(block: Option<BlockPtr>) -> usize {
+    block.map_or(0, |b| node(b).height)
+}
+
+pub struct SizeTree {
+    root: Option<BlockPtr>,
+}
+
+impl SizeTree {
+    pub const fn new() -> Self {
+        Self { root: None }
+    }
+
+    /// Adds a free block of at least `TREE_MIN_SIZE` bytes.
+    pub fn insert(&mut self, block: BlockPtr) {
+        self.root = Some(Self::insert_at(self.root, block));
+    }
+
+    /// Removes a block which has been added with its current size.
+    pub fn remove(&mut self, block: BlockPtr) {
+        self.root = Self::remove_at(self.root, block);
+    }
+
+    /// Returns the smallest block which can hold `size` bytes, the lowest address wins ties.
+    pub fn find_best(&self, size: usize) -> Option<BlockPtr> {
+        let mut best = None;
+        let mut current = self.root;
+        while let Some(block) = current {
+            if block.size() >= size {
+                best = Some(block);
+                current = node(block).left;
+            } else {
+                current = node(block).right;
+            }
+        }
+        best
+    }
+
+    fn insert_at(root: Option<BlockPtr>, block: BlockPtr) -> BlockPtr {
+        let root = match root {
+            Some(r) => r,
+            None => {
+                *node(block) = Node {
+                    left: None,
+                    right: None,
+                    height: 1,
+                };
+                return block;
+            }
+        };
+        let n = node(root);
+        if key(block) < key(root) {
+            n.left = Some(Self::insert_at(n.left, block));
+        } else {
+            n.right = Some(Self::insert_at(n.right, block));
+        }
+        Self::rebalance(root)
+    }
+
+    fn remove_at(root: Option<BlockPtr>, block: BlockPtr) -> Option<BlockPtr> {
+        let root = root?;
+        let n = node(root);
+        if root == block {
+            return match (n.left, n.right) {
+                (None, right) => right,
+                (left, None) => left,
+                (left, Some(right)) => {
+                    let (right, min) = Self::remove_min(right);
+                    let m = node(min);
+                    m.left = left;
+                    m.right = right;
+                    Some(Self::rebalance(min))
+                }
+            };
+        }
+        if key(block) < key(root) {
+            n.left = Self::remove_at(n.left, block);
+        } else {
+            n.right = Self::remove_at(n.right, block);
+        }
+        Some(Self::rebalance(root))
+    }
+
+    /// Detaches the smallest block of a subtree, returns the new subtree and the block.
+    fn remove_min(root: BlockPtr) -> (Option<BlockPtr>, BlockPtr) {
+        let n = node(root);
+        match n.left {
+            None => (n.right, root),
+            Some(left) => {
+                let (left, min) = Self::remove_min(left);
+                n.left = left;
+                (Some(Self::rebalance(root)), min)
+            }
+        }
+    }
+
+    /// Restores the height invariant of a subtree and returns its new root.
+    fn rebalance(root: BlockPtr) -> BlockPtr {
+        let n = node(root);
+        let (left, right) = (height(n.left), height(n.right));
+        if left > right + 1 {
+            let l = n.left.unwrap();
+            if height(node(l).left) < height(node(l).right) {
+                n.left = Some(Self::rotate_left(l));
+            }
+            return Self::rotate_right(root);
+        }
+        if right > left + 1 {
+            let r = n.right.unwrap();
+            if height(node(r).right) < height(node(r).left) {
+                n.right = Some(Self::rotate_right(r));
+            }
+            return Self::rotate_left(root);
+        }
+        Self::update_height(root);
+        root
+    }
+
+    fn rotate_left(root: BlockPtr) -> BlockPtr {
+        let pivot = node(root).right.unwrap();
+        node(root).right = node(pivot).left;
+        node(pivot).left = Some(root);
+        Self::update_height(root);
+        Self::update_height(pivot);
+        pivot
+    }
+
+    fn rotate_right(root: BlockPtr) -> BlockPtr {
+        let pivot = node(root).left.unwrap();
+        node(root).left = node(pivot).right;
+        node(pivot).right = Some(root);
+        Self::update_height(root);
+        Self::update_height(pivot);
+        pivot
+    }
+
+    #[inline]
+    fn update_height(block: BlockPtr) {
+        let n = node(block);
+        n.height = cmp::max(height(n.left), height(n.right)) + 1;
+    }
+
+    /// Verifies order and balance of the tree and returns the number of blocks.
+    #[cfg(any(test, feature = "debug"))]
+    pub fn verify(&self) -> usize {
+        fn check(root: Option<BlockPtr>, min: (usize, usize), max: (usize, usize)) -> usize {
+            let root = match root {
+                Some(r) => r,
+                None => return 0,
+            };
+            let n = node(root);
+            assert!(key(root) >= min && key(root) <= max, "tree order violated");
+            assert!(
+                (height(n.left) as isize - height(n.right) as isize).abs() <= 1,
+                "tree is unbalanced"
+            );
+            assert_eq!(n.height, cmp::max(height(n.left), height(n.right)) + 1);
+            check(n.left, min, key(root)) + 1 + check(n.right, key(root), max)
+        }
+        check(self.root, (0, 0), (usize::max_value(), usize::max_value()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alloc::block::BLOCK_META_SIZE;
+    use core::mem;
+    use core::ptr::Unique;
+    use std::vec::Vec;
+
+    /// Creates blocks with the given sizes, each at its own offset of 4 KiB.
+    fn blocks(buf: &mut [u128], sizes: &[usize]) -> Vec<BlockPtr> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| {
+                let ptr = unsafe {
+                    buf.as_mut_ptr()
+                        .cast::<u8>()
+                        .add(i * 4096 + BLOCK_META_SIZE)
+                };
+                BlockPtr::new(Unique::new(ptr).unwrap(), *size)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_node_fits() {
+        // The node has to fit behind the list links of the smallest indexed block
+        assert!(
+            mem::size_of::<Block>() + mem::size_of::<Node>() <= BLOCK_META_SIZE + TREE_MIN_SIZE
+        );
+    }
+
+    #[test]
+    fn test_tree_find_best() {
+        let mut buf = std::vec![0_u128; 16 * 256];
+        let sizes = [
+            2040,
+            520,
+            1016,
+            3064,
+            1016,
+            760,
+            4000 - BLOCK_META_SIZE,
+            520,
+        ];
+        let blocks = blocks(&mut buf, &sizes);
+        let mut tree = SizeTree::new();
+        for block in blocks.iter() {
+            tree.insert(*block);
+        }
+        assert_eq!(tree.verify(), sizes.len());
+
+        assert_eq!(tree.find_best(100), Some(blocks[1]));
+        assert_eq!(tree.find_best(600), Some(blocks[5]));
+        assert_eq!(tree.find_best(1000), Some(blocks[2]));
+        assert_eq!(tree.find_best(3064), Some(blocks[3]));
+        assert_eq!(tree.find_best(4000), None);
+
+        tree.remove(blocks[2]);
+        assert_eq!(tree.find_best(1000), Some(blocks[4]));
+        tree.remove(blocks[1]);
+        tree.remove(blocks[3]);
+        assert_eq!(tree.verify(), sizes.len() - 3);
+        assert_eq!(tree.find_best(2049), Some(blocks[6]));
+    }
+
+    #[test]
+    fn test_tree_balance() {
+        let mut buf = std::vec![0_u128; 256 * 256 + 64];
+        let sizes: Vec<usize> = (0..256).map(|i| 520 + (i * 7919 % 256) * 16).collect();
+        let blocks = blocks(&mut buf, &sizes);
+        let mut tree = SizeTree::new();
+        for block in blocks.iter() {
+            tree.insert(*block);
+        }
+        assert_eq!(tree.verify(), 256);
+        // A balanced tree with 256 nodes is at most 1.44 * log2(256) high
+        assert!(height(tree.root) <= 11);
+
+        for block in blocks.iter().step_by(2) {
+            tree.remove(*block);
+        }
+        assert_eq!(tree.verify(), 128);
+        for block in blocks.iter().skip(1).step_by(2) {
+            assert_eq!(
+                tree.find_best(block.size()).map(|b| b.size()),
+                Some(block.size())
+            );
+        }
+    }
+}
@@ -0,0 +1,258 @@
+//! Replays a recorded allocation trace against collam or the system allocator.
+//!
+//! Usage:
+//!     replay <trace-file> [--system] [--fit first|next|best|good|worst]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::{cmp, env, fs, process};
+
+use collam::alloc::fit::{BestFit, FirstFit, FitPolicy, GoodFit, NextFit, WorstFit};
+use collam::alloc::Collam;
+use collam::sources::DataSegment;
+use collam::trace::{TraceOp, TraceReader, TraceRecord};
+use collam::MIN_ALIGN;
+
+#[derive(Debug, Default)]
+struct Report {
+    ops: usize,
+    /// Records referring to pointers which have not been seen,
+    /// usually because the ring wrapped around.
+    skipped: usize,
+    live_bytes: usize,
+    peak_live_bytes: usize,
+    peak_heap_size: Option<usize>,
+    elapsed: Duration,
+}
+
+/// Re-executes the traced calls against the given allocator.
+struct Replayer<'a, A: GlobalAlloc> {
+    alloc: &'a A,
+    /// Maps traced pointers to the pointers returned during replay.
+    live: HashMap<u64, (*mut u8, Layout)>,
+    report: Report,
+}
+
+impl<'a, A: GlobalAlloc> Replayer<'a, A> {
+    fn new(alloc: &'a A) -> Self {
+        Self {
+            alloc,
+            live: HashMap::new(),
+            report: Report::default(),
+        }
+    }
+
+    /// Replays all records, `heap_size` is sampled after each call to determine the peak heap size.
+    fn run<I, F>(mut self, records: I, heap_size: F) -> Report
+    where
+        I: Iterator<Item = TraceRecord>,
+        F: Fn() -> Option<usize>,
+    {
+        let start = Instant::now();
+        for record in records {
+            unsafe { self.step(&record) };
+            if let Some(size) = heap_size() {
+                let peak = self.report.peak_heap_size.unwrap_or(0);
+                self.report.peak_heap_size = Some(cmp::max(peak, size));
+            }
+        }
+        self.report.elapsed = start.elapsed();
+        self.report
+    }
+
+    unsafe fn step(&mut self, record: &TraceRecord) {
+        self.report.ops += 1;
+        match record.op {
+            TraceOp::Malloc => self.malloc(record, false),
+            TraceOp::Calloc => self.malloc(record, true),
+            TraceOp::Free => self.free(record.ptr),
+            TraceOp::Realloc if record.old_ptr == 0 => self.malloc(record, false),
+            TraceOp::Realloc if record.size == 0 => self.free(record.old_ptr),
+            TraceOp::Realloc => self.realloc(record),
+        }
+    }
+
+    unsafe fn malloc(&mut self, record: &TraceRecord, zeroed: bool) {
+        if record.ptr == 0 || record.size == 0 {
+            // Failed or zero sized allocations don't own memory.
+            return;
+        }
+        let layout = Layout::from_size_align_unchecked(record.size as usize, MIN_ALIGN);
+        let ptr = if zeroed {
+            self.alloc.alloc_zeroed(layout)
+        } else {
+            self.alloc.alloc(layout)
+        };
+        self.insert(record.ptr, ptr, layout);
+    }
+
+    unsafe fn realloc(&mut self, record: &TraceRecord) {
+        if record.ptr == 0 {
+            // Failed realloc leaves the old pointer untouched.
+            return;
+        }
+        let (ptr, layout) = match self.remove(record.old_ptr) {
+            Some(entry) => entry,
+            None => return self.malloc(record, false),
+        };
+        let new_size = record.size as usize;
+        let new_ptr = self.alloc.realloc(ptr, layout, new_size);
+        let new_layout = Layout::from_size_align_unchecked(new_size, MIN_ALIGN);
+        self.insert(record.ptr, new_ptr, new_layout);
+    }
+
+    unsafe fn free(&mut self, traced: u64) {
+        if let Some((ptr, layout)) = self.remove(traced) {
+            self.alloc.dealloc(ptr, layout);
+        }
+    }
+
+    fn insert(&mut self, traced: u64, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            eprintln!("replay: allocation of {} bytes failed", layout.size());
+            return;
+        }
+        self.report.live_bytes += layout.size();
+        self.report.peak_live_bytes = cmp::max(self.report.peak_live_bytes, self.report.live_bytes);
+        self.live.insert(traced, (ptr, layout));
+    }
+
+    fn remove(&mut self, traced: u64) -> Option<(*mut u8, Layout)> {
+        match self.live.remove(&traced) {
+            Some((ptr, layout)) => {
+                self.report.live_bytes -= layout.size();
+                Some((ptr, layout))
+            }
+            None => {
+                self.report.skipped += 1;
+                None
+            }
+        }
+    }
+}
+
+const USAGE: &str = "usage: replay <trace-file> [--system] [--fit first|next|best|good|worst]";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let fit = match args.iter().position(|a| a == "--fit") {
+        Some(i) => args.get(i + 1).map(String::as_str).unwrap_or_else(|| {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }),
+        None => "first",
+    };
+    let path = match args
+        .iter()
+        .enumerate()
+        .find(|(i, a)| !a.starts_with("--") && (*i == 0 || args[i - 1] != "--fit"))
+    {
+        Some((_, p)) => p,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let system = args.iter().any(|a| a == "--system");
+
+    let data = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("replay: unable to read {}: {}", path, e);
+        process::exit(1);
+    });
+    let reader = TraceReader::new(&data).unwrap_or_else(|e| {
+        eprintln!("replay: invalid trace file {}: {:?}", path, e);
+        process::exit(1);
+    });
+
+    if system {
+        let report = Replayer::new(&System).run(reader.records(), || None);
+        print_report("system", &report);
+    } else {
+        let records = reader.records();
+        match fit {
+            "first" => replay_collam(records, FirstFit),
+            "next" => replay_collam(records, NextFit::new()),
+            "best" => replay_collam(records, BestFit),
+            "good" => replay_collam(records, GoodFit::default()),
+            "worst" => replay_collam(records, WorstFit),
+            _ => {
+                eprintln!("replay: unknown fit policy {}", fit);
+                process::exit(2);
+            }
+        }
+    }
+}
+
+fn replay_collam<I, F>(records: I, policy: F)
+where
+    I: Iterator<Item = TraceRecord>,
+    F: FitPolicy,
+{
+    let collam = Collam::with_policy(DataSegment::new(), policy);
+    let report = Replayer::new(&collam).run(records, || {
+        let stats = collam.stats();
+        Some(stats.heap_size + stats.slab_size)
+    });
+    print_report("collam", &report);
+    println!("peak overhead:    {:.4}", overhead(&report));
+    print!("{}", collam.stats());
+}
+
+/// Returns the ratio of heap size to live bytes at their respective peaks.
+fn overhead(report: &Report) -> f64 {
+    match report.peak_heap_size {
+        Some(heap) if report.peak_live_bytes > 0 => heap as f64 / report.peak_live_bytes as f64,
+        _ => 0.0,
+    }
+}
+
+fn print_report(name: &str, report: &Report) {
+    println!("allocator:        {}", name);
+    println!("operations:       {}", report.ops);
+    println!("skipped:          {}", report.skipped);
+    println!("time:             {:?}", report.elapsed);
+    println!("peak live bytes:  {}", report.peak_live_bytes);
+    match report.peak_heap_size {
+        Some(size) => println!("peak heap size:   {}", size),
+        None => println!("peak heap size:   n/a"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(seq: u64, op: TraceOp, size: u64, ptr: u64, old_ptr: u64) -> TraceRecord {
+        TraceRecord {
+            seq,
+            timestamp: seq,
+            thread_id: 1,
+            op,
+            size,
+            ptr,
+            old_ptr,
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let records = vec![
+            record(1, TraceOp::Malloc, 128, 0x10, 0),
+            record(2, TraceOp::Calloc, 64, 0x20, 0),
+            record(3, TraceOp::Realloc, 1024, 0x30, 0x10),
+            record(4, TraceOp::Free, 0, 0x20, 0),
+            record(5, TraceOp::Free, 0, 0x40, 0),
+            record(6, TraceOp::Realloc, 0, 0, 0x30),
+        ];
+        let collam = Collam::new();
+        let report = Replayer::new(&collam).run(records.into_iter(), || {
+            let stats = collam.stats();
+            Some(stats.heap_size + stats.slab_size)
+        });
+        assert_eq!(report.ops, 6);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.live_bytes, 0);
+        assert_eq!(report.peak_live_bytes, 1024 + 64);
+        assert!(report.peak_heap_size.unwrap() >= report.peak_live_bytes);
+    }
+}
@@ -1,3 +1,6 @@
+#![feature(allocator_api)]
+#![feature(asm)]
+#![feature(const_fn)]
 #![feature(core_intrinsics)]
 #![feature(ptr_internals)]
 #![no_std]
@@ -7,20 +10,19 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate libc;
-extern crate libc_print;
 extern crate spin;
 
 #[cfg(test)]
 #[macro_use]
 extern crate std;
 
-#[allow(unused_imports)]
-use libc_print::libc_eprintln;
-
 mod macros;
 
 pub mod alloc;
-mod sources;
+pub mod log;
+pub mod profile;
+pub mod sources;
+pub mod trace;
 mod util;
 
 #[cfg(all(any(
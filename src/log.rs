@@ -0,0 +1,244 @@
+//! Allocation free logging.
+//!
+//! Every message belongs to a `Target` and is written if its `Level` is enabled for that target.
+//! Levels and the output file descriptor can be changed at runtime,
+//! the `debug` feature enables all levels by default.
+//! Messages are formatted on the stack and written with a single `write` call
+//! unless they exceed the buffer of `FdWriter`.
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicI32, AtomicU8, Ordering};
+
+use crate::util::FdWriter;
+
+/// Number of available log targets.
+pub const LOG_TARGETS: usize = 4;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Trace = 4,
+}
+
+impl Level {
+    #[inline]
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            _ => Level::Trace,
+        }
+    }
+
+    /// Parses a level name as used in filter specifications.
+    pub fn from_bytes(name: &[u8]) -> Option<Self> {
+        match name {
+            b"off" => Some(Level::Off),
+            b"error" => Some(Level::Error),
+            b"warn" => Some(Level::Warn),
+            b"info" => Some(Level::Info),
+            b"trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Off => "off",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+/// Subsystem which emitted a message.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// Public allocator interfaces.
+    Alloc = 0,
+    /// Heap and block management.
+    Heap = 1,
+    /// Free list operations.
+    List = 2,
+    /// Memory sources.
+    Source = 3,
+}
+
+impl Target {
+    const ALL: [Target; LOG_TARGETS] = [Target::Alloc, Target::Heap, Target::List, Target::Source];
+
+    /// Parses a target name as used in filter specifications.
+    pub fn from_bytes(name: &[u8]) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|t| t.name().as_bytes() == name)
+    }
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Alloc => "alloc",
+            Target::Heap => "heap",
+            Target::List => "list",
+            Target::Source => "source",
+        }
+    }
+}
+
+/// Maximum enabled level for each target.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    levels: [Level; LOG_TARGETS],
+}
+
+impl Filter {
+    /// Returns a filter which enables `level` for all targets.
+    #[must_use]
+    pub const fn new(level: Level) -> Self {
+        Self {
+            levels: [level; LOG_TARGETS],
+        }
+    }
+
+    /// Parses a comma separated list of directives and applies them in order.
+    /// A directive is either a level, which applies to all targets,
+    /// or `target=level`, e.g. `warn,heap=trace`.
+    /// Returns `None` if a directive is invalid.
+    pub fn parse(mut self, spec: &[u8]) -> Option<Self> {
+        for directive in spec.split(|c| *c == b',').filter(|d| !d.is_empty()) {
+            match directive.iter().position(|c| *c == b'=') {
+                Some(i) => {
+                    let target = Target::from_bytes(&directive[..i])?;
+                    self.levels[target as usize] = Level::from_bytes(&directive[i + 1..])?;
+                }
+                None => self.levels = [Level::from_bytes(directive)?; LOG_TARGETS],
+            }
+        }
+        Some(self)
+    }
+
+    /// Sets the maximum enabled level for a single target.
+    pub fn set(&mut self, target: Target, level: Level) {
+        self.levels[target as usize] = level;
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn level(&self, target: Target) -> Level {
+        self.levels[target as usize]
+    }
+}
+
+#[cfg(feature = "debug")]
+const DEFAULT_LEVEL: u8 = Level::Trace as u8;
+#[cfg(not(feature = "debug"))]
+const DEFAULT_LEVEL: u8 = Level::Warn as u8;
+
+static LEVELS: [AtomicU8; LOG_TARGETS] = [
+    AtomicU8::new(DEFAULT_LEVEL),
+    AtomicU8::new(DEFAULT_LEVEL),
+    AtomicU8::new(DEFAULT_LEVEL),
+    AtomicU8::new(DEFAULT_LEVEL),
+];
+static OUTPUT: AtomicI32 = AtomicI32::new(libc::STDERR_FILENO);
+
+/// Returns the currently active filter.
+pub fn filter() -> Filter {
+    let mut filter = Filter::new(Level::Off);
+    for target in Target::ALL.iter() {
+        filter.levels[*target as usize] =
+            Level::from_u8(LEVELS[*target as usize].load(Ordering::Relaxed));
+    }
+    filter
+}
+
+/// Replaces the active filter.
+pub fn set_filter(filter: &Filter) {
+    for target in Target::ALL.iter() {
+        LEVELS[*target as usize].store(filter.level(*target) as u8, Ordering::Relaxed);
+    }
+}
+
+/// Sets the file descriptor all messages are written to, defaults to stderr.
+pub fn set_output(fd: libc::c_int) {
+    OUTPUT.store(fd, Ordering::Relaxed);
+}
+
+/// Returns `true` if messages with the given level and target are written.
+#[inline]
+pub fn enabled(target: Target, level: Level) -> bool {
+    level as u8 <= LEVELS[target as usize].load(Ordering::Relaxed)
+}
+
+/// Writes a message regardless of the active filter.
+/// Use the `error!`, `warn!`, `info!` and `trace!` macros for filtered output.
+pub fn write(target: Target, level: Level, args: fmt::Arguments) {
+    let mut writer = FdWriter::new(OUTPUT.load(Ordering::Relaxed));
+    let _ = write_message(&mut writer, target, level, args);
+}
+
+fn write_message(
+    w: &mut impl Write,
+    target: Target,
+    level: Level,
+    args: fmt::Arguments,
+) -> fmt::Result {
+    writeln!(w, "[collam] {} {}: {}", level.name(), target.name(), args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    #[test]
+    fn test_filter_parse() {
+        let filter = Filter::new(Level::Warn)
+            .parse(b"error,heap=trace,,list=off")
+            .expect("unable to parse filter");
+        assert_eq!(filter.level(Target::Alloc), Level::Error);
+        assert_eq!(filter.level(Target::Heap), Level::Trace);
+        assert_eq!(filter.level(Target::List), Level::Off);
+        assert_eq!(filter.level(Target::Source), Level::Error);
+        assert_eq!(
+            Filter::new(Level::Warn).parse(b""),
+            Some(Filter::new(Level::Warn))
+        );
+    }
+
+    #[test]
+    fn test_filter_parse_invalid() {
+        assert_eq!(Filter::new(Level::Warn).parse(b"loud"), None);
+        assert_eq!(Filter::new(Level::Warn).parse(b"disk=info"), None);
+        assert_eq!(Filter::new(Level::Warn).parse(b"heap=loud"), None);
+    }
+
+    #[test]
+    fn test_enabled() {
+        let old = filter();
+        set_filter(&Filter::new(Level::Warn).parse(b"list=trace").unwrap());
+        assert!(enabled(Target::Heap, Level::Error));
+        assert!(enabled(Target::Heap, Level::Warn));
+        assert!(!enabled(Target::Heap, Level::Info));
+        assert!(enabled(Target::List, Level::Trace));
+        set_filter(&old);
+    }
+
+    #[test]
+    fn test_write_message() {
+        let mut out = String::new();
+        write_message(&mut out, Target::Heap, Level::Error, format_args!("{}", 42))
+            .expect("unable to write message");
+        assert_eq!(out, "[collam] error heap: 42\n");
+    }
+}
@@ -15,25 +15,37 @@ macro_rules! debug_assert_ne {
     ($($arg:tt)*) => (if cfg!(feature = "debug") { assert_ne!($($arg)*); })
 }
 
+/// Writes a message if `$level` is enabled for `$target`.
+/// The arguments are only evaluated if the message is written.
 #[macro_export]
-macro_rules! println {
-    ($($arg:tt)*) => {
-        libc_eprintln!($($arg)*)
+macro_rules! log {
+    ($target:ident, $level:ident, $($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Target::$target, $crate::log::Level::$level) {
+            $crate::log::write(
+                $crate::log::Target::$target,
+                $crate::log::Level::$level,
+                format_args!($($arg)*),
+            )
+        }
     };
 }
 
 #[macro_export]
-macro_rules! dprintln {
-    ($($arg:tt)*) => {
-        if cfg!(feature = "debug") {
-            println!($($arg)*)
-        }
-    };
+macro_rules! error {
+    ($target:ident, $($arg:tt)*) => ($crate::log!($target, Error, $($arg)*));
 }
 
 #[macro_export]
-macro_rules! eprintln {
-    ($($arg:tt)*) => {
-        println!($($arg)*)
-    };
+macro_rules! warn {
+    ($target:ident, $($arg:tt)*) => ($crate::log!($target, Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($target:ident, $($arg:tt)*) => ($crate::log!($target, Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($target:ident, $($arg:tt)*) => ($crate::log!($target, Trace, $($arg)*));
 }
@@ -0,0 +1,407 @@
+//! Sampling heap profiler.
+//!
+//! Roughly every `interval` allocated bytes the call stack of the allocation is captured
+//! by walking the frame pointer chain. Sampled allocations are tracked until they are freed
+//! and can be dumped in the collapsed stack format understood by `flamegraph.pl` and `inferno`.
+//! Stacks are only meaningful if the profiled program keeps frame pointers
+//! (`-fno-omit-frame-pointer` or `-C force-frame-pointers=yes`), including collam itself.
+//! Frames are read through process_vm_readv(2), so a garbage frame pointer ends the walk
+//! instead of faulting.
+use core::fmt::{self, Write};
+use core::intrinsics::{likely, unlikely};
+use core::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use core::{cmp, mem};
+
+use spin::Mutex;
+
+use crate::util::{self, FdWriter};
+
+/// Maximum number of live sampled allocations, must be a power of two.
+pub const PROFILE_MAX_SAMPLES: usize = 1024;
+/// Maximum number of captured frames per sample.
+pub const PROFILE_MAX_FRAMES: usize = 24;
+
+#[derive(Copy, Clone)]
+struct Sample {
+    /// Address of the sampled allocation, 0 for empty slots.
+    ptr: usize,
+    size: usize,
+    depth: usize,
+    frames: [usize; PROFILE_MAX_FRAMES],
+}
+
+impl Sample {
+    const EMPTY: Sample = Sample {
+        ptr: 0,
+        size: 0,
+        depth: 0,
+        frames: [0; PROFILE_MAX_FRAMES],
+    };
+}
+
+/// Open addressing hash table with linear probing keyed by the allocation address.
+struct SampleTable {
+    slots: [Sample; PROFILE_MAX_SAMPLES],
+    len: usize,
+    /// Number of samples which have been discarded because the table was full.
+    dropped: usize,
+}
+
+impl SampleTable {
+    const fn new() -> Self {
+        Self {
+            slots: [Sample::EMPTY; PROFILE_MAX_SAMPLES],
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    #[inline]
+    fn index(ptr: usize) -> usize {
+        (ptr >> 4).wrapping_mul(0x9E37_79B9_7F4A_7C15) & (PROFILE_MAX_SAMPLES - 1)
+    }
+
+    fn insert(&mut self, sample: Sample) {
+        // Keep at least one empty slot so lookups always terminate.
+        if self.len + 1 >= PROFILE_MAX_SAMPLES {
+            self.dropped += 1;
+            return;
+        }
+        let mut i = Self::index(sample.ptr);
+        while self.slots[i].ptr != 0 && self.slots[i].ptr != sample.ptr {
+            i = (i + 1) & (PROFILE_MAX_SAMPLES - 1);
+        }
+        if self.slots[i].ptr == 0 {
+            self.len += 1;
+        }
+        self.slots[i] = sample;
+    }
+
+    /// Removes the sample for the given address, returns `false` if there was none.
+    fn remove(&mut self, ptr: usize) -> bool {
+        let mask = PROFILE_MAX_SAMPLES - 1;
+        let mut i = Self::index(ptr);
+        loop {
+            match self.slots[i].ptr {
+                0 => return false,
+                p if p == ptr => break,
+                _ => i = (i + 1) & mask,
+            }
+        }
+        // Backward shift deletion keeps probe sequences intact without tombstones.
+        let mut hole = i;
+        let mut j = i;
+        loop {
+            j = (j + 1) & mask;
+            if self.slots[j].ptr == 0 {
+                break;
+            }
+            let home = Self::index(self.slots[j].ptr);
+            if (j.wrapping_sub(home) & mask) >= (j.wrapping_sub(hole) & mask) {
+                self.slots[hole] = self.slots[j];
+                hole = j;
+            }
+        }
+        self.slots[hole] = Sample::EMPTY;
+        self.len -= 1;
+        true
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Sample> {
+        self.slots.iter().filter(|s| s.ptr != 0)
+    }
+}
+
+pub struct Profiler {
+    /// Average number of bytes between two samples, 0 disables sampling.
+    interval: AtomicUsize,
+    /// Bytes left until the next sample is taken.
+    countdown: AtomicIsize,
+    /// Number of live samples, allows `on_dealloc` to skip locking.
+    live: AtomicUsize,
+    samples: Mutex<SampleTable>,
+}
+
+impl Profiler {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            interval: AtomicUsize::new(0),
+            countdown: AtomicIsize::new(0),
+            live: AtomicUsize::new(0),
+            samples: Mutex::new(SampleTable::new()),
+        }
+    }
+
+    /// Returns the sampling interval in bytes, 0 if sampling is disabled.
+    #[inline]
+    pub fn interval(&self) -> usize {
+        self.interval.load(Ordering::Relaxed)
+    }
+
+    /// Sets the sampling interval in bytes, 0 disables sampling.
+    pub fn set_interval(&self, bytes: usize) {
+        self.interval.store(bytes, Ordering::Relaxed);
+        self.countdown.store(bytes as isize, Ordering::Relaxed);
+    }
+
+    /// Accounts an allocation and records its call stack if a sample is due.
+    #[inline]
+    pub fn on_alloc(&self, ptr: *mut u8, size: usize) {
+        let interval = self.interval.load(Ordering::Relaxed);
+        if likely(interval == 0) || ptr.is_null() {
+            return;
+        }
+        let remaining = self.countdown.fetch_sub(size as isize, Ordering::Relaxed);
+        if likely(remaining > size as isize) {
+            return;
+        }
+        self.countdown.store(interval as isize, Ordering::Relaxed);
+        self.sample(ptr as usize, size);
+    }
+
+    /// Forgets the sample for the given allocation if there is one.
+    #[inline]
+    pub fn on_dealloc(&self, ptr: *mut u8) {
+        if likely(self.live.load(Ordering::Relaxed) == 0) {
+            return;
+        }
+        if self.samples.lock().remove(ptr as usize) {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline(never)]
+    fn sample(&self, ptr: usize, size: usize) {
+        let mut sample = Sample {
+            ptr,
+            size,
+            depth: 0,
+            frames: [0; PROFILE_MAX_FRAMES],
+        };
+        sample.depth = backtrace(&mut sample.frames);
+
+        let mut samples = self.samples.lock();
+        let len = samples.len;
+        samples.insert(sample);
+        if samples.len > len {
+            self.live.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes all live samples in collapsed stack format to the given file descriptor.
+    /// The weight of each stack is the estimated number of bytes it represents.
+    /// Returns `false` if the sample table is currently locked, e.g. when called from
+    /// a signal handler which interrupted an allocation.
+    pub fn dump(&self, fd: libc::c_int) -> bool {
+        let samples = match self.samples.try_lock() {
+            Some(s) => s,
+            None => return false,
+        };
+        let interval = self.interval.load(Ordering::Relaxed);
+        let mut writer = FdWriter::new(fd);
+        for sample in samples.iter() {
+            if write_sample(&mut writer, sample, interval).is_err() {
+                return false;
+            }
+        }
+        writer.flush().is_ok()
+    }
+
+    /// Returns the number of samples which have been discarded because the table was full.
+    pub fn dropped(&self) -> usize {
+        self.samples.lock().dropped
+    }
+
+    /// Holds the lock until `unlock` is called, e.g. while forking.
+    pub fn lock(&self) {
+        util::hold(&self.samples);
+    }
+
+    /// Releases the lock acquired by `lock`.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called after `lock`.
+    pub unsafe fn unlock(&self) {
+        self.samples.force_unlock();
+    }
+}
+
+/// Writes a single collapsed stack line, the outermost frame comes first.
+fn write_sample(w: &mut impl Write, sample: &Sample, interval: usize) -> fmt::Result {
+    for (i, frame) in sample.frames[..sample.depth].iter().rev().enumerate() {
+        if i > 0 {
+            w.write_char(';')?;
+        }
+        write!(w, "{:#x}", frame)?;
+    }
+    if sample.depth == 0 {
+        w.write_str("[unknown]")?;
+    }
+    // Every sample stands for at least `interval` allocated bytes
+    writeln!(w, " {}", cmp::max(sample.size, interval))
+}
+
+/// Walks the frame pointer chain and stores the return addresses in `frames`.
+/// Returns the number of captured frames.
+///
+/// Code compiled without frame pointers uses `rbp` as general purpose register
+/// and the stack end of the current thread isn't known, so frames are read with
+/// `read_frame`, which fails for memory that isn't readable.
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+fn backtrace(frames: &mut [usize]) -> usize {
+    let (mut fp, sp): (usize, usize);
+    unsafe {
+        asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack));
+        asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack));
+    }
+    // SAFETY: getpid(2) always succeeds
+    let pid = unsafe { libc::getpid() };
+
+    let mut depth = 0;
+    while depth < frames.len() {
+        if fp < sp || fp % mem::align_of::<usize>() != 0 {
+            break;
+        }
+        let (next, ret) = match read_frame(pid, fp) {
+            Some(frame) => frame,
+            None => break,
+        };
+        if ret == 0 {
+            break;
+        }
+        frames[depth] = ret;
+        depth += 1;
+        // The stack grows downwards, so callers are always at higher addresses
+        if unlikely(next <= fp) {
+            break;
+        }
+        fp = next;
+    }
+    depth
+}
+
+/// Reads the saved frame pointer and return address at `fp` through process_vm_readv(2),
+/// returns `None` if they aren't readable.
+#[cfg(target_arch = "x86_64")]
+fn read_frame(pid: libc::pid_t, fp: usize) -> Option<(usize, usize)> {
+    let mut frame = [0usize; 2];
+    let len = mem::size_of_val(&frame);
+    let local = libc::iovec {
+        iov_base: frame.as_mut_ptr().cast(),
+        iov_len: len,
+    };
+    let remote = libc::iovec {
+        iov_base: fp as *mut libc::c_void,
+        iov_len: len,
+    };
+    // SAFETY: the kernel validates the remote range and only writes to `frame`
+    let read = unsafe { libc::process_vm_readv(pid, &local, 1, &remote, 1, 0) };
+    if read != len as isize {
+        return None;
+    }
+    Some((frame[0], frame[1]))
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+#[inline(always)]
+fn backtrace(_frames: &mut [usize]) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    static PROFILER: Profiler = Profiler::new();
+
+    #[test]
+    fn test_table_insert_remove() {
+        let mut table = SampleTable::new();
+        // Provoke collisions by using addresses which map to the same slot
+        let ptrs = [0x10, 0x10 + (PROFILE_MAX_SAMPLES << 4), 0x20, 0x30];
+        for &ptr in ptrs.iter() {
+            table.insert(Sample {
+                ptr,
+                ..Sample::EMPTY
+            });
+        }
+        assert_eq!(table.len, 4);
+        assert!(table.remove(0x10));
+        assert!(!table.remove(0x10));
+        for &ptr in ptrs[1..].iter() {
+            assert!(table.remove(ptr), "lost {:#x}", ptr);
+        }
+        assert_eq!(table.len, 0);
+        assert_eq!(table.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_table_full() {
+        let mut table = SampleTable::new();
+        for i in 1..=PROFILE_MAX_SAMPLES {
+            table.insert(Sample {
+                ptr: i << 4,
+                ..Sample::EMPTY
+            });
+        }
+        assert_eq!(table.len, PROFILE_MAX_SAMPLES - 1);
+        assert_eq!(table.dropped, 1);
+    }
+
+    #[test]
+    fn test_profiler_sampling() {
+        PROFILER.set_interval(1024);
+        // Allocations below the interval are not sampled
+        PROFILER.on_alloc(0x1000 as *mut u8, 512);
+        assert_eq!(PROFILER.live.load(Ordering::Relaxed), 0);
+        PROFILER.on_alloc(0x2000 as *mut u8, 512);
+        assert_eq!(PROFILER.live.load(Ordering::Relaxed), 1);
+
+        let mut out = String::new();
+        for sample in PROFILER.samples.lock().iter() {
+            write_sample(&mut out, sample, 1024).expect("unable to write sample");
+        }
+        assert!(out.ends_with(" 1024\n"), "unexpected output: {}", out);
+
+        PROFILER.on_dealloc(0x1000 as *mut u8);
+        assert_eq!(PROFILER.live.load(Ordering::Relaxed), 1);
+        PROFILER.on_dealloc(0x2000 as *mut u8);
+        assert_eq!(PROFILER.live.load(Ordering::Relaxed), 0);
+        PROFILER.set_interval(0);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut frames = [0; PROFILE_MAX_FRAMES];
+        let depth = backtrace(&mut frames);
+        assert!(depth <= PROFILE_MAX_FRAMES);
+        assert!(frames[..depth].iter().all(|&f| f != 0));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_read_frame() {
+        unsafe {
+            let pid = libc::getpid();
+            let frame = [0x10usize, 0x20];
+            assert_eq!(read_frame(pid, frame.as_ptr() as usize), Some((0x10, 0x20)));
+            // Inaccessible memory is reported instead of faulting
+            let page = libc::mmap(
+                core::ptr::null_mut(),
+                4096,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(page, libc::MAP_FAILED);
+            assert_eq!(read_frame(pid, page as usize), None);
+            assert_eq!(read_frame(pid, 0x10), None);
+            libc::munmap(page, 4096);
+        }
+    }
+}
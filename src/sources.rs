@@ -1,29 +1,102 @@
 use core::convert::TryFrom;
-use core::ptr::Unique;
+use core::ptr::{null_mut, Unique};
+use core::{cmp, intrinsics, mem};
 
-use crate::alloc::block::{BlockPtr, BLOCK_META_SIZE};
+use crate::alloc::block::{Block, BlockPtr, BLOCK_META_SIZE};
 use crate::util;
 
-use libc_print::libc_eprintln;
-
 lazy_static! {
-    static ref PAGE_SIZE: usize =
+    pub(crate) static ref PAGE_SIZE: usize =
         usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).unwrap();
+    /// Set if the kernel may back regions advised with `MADV_HUGEPAGE` by transparent huge pages.
+    pub(crate) static ref THP_ENABLED: bool = unsafe { thp_enabled() };
+}
+
+/// Reads the transparent huge page mode, which is `never` or missing if they are unavailable.
+unsafe fn thp_enabled() -> bool {
+    let path = b"/sys/kernel/mm/transparent_hugepage/enabled\0";
+    let fd = libc::open(
+        path.as_ptr().cast::<libc::c_char>(),
+        libc::O_RDONLY | libc::O_CLOEXEC,
+    );
+    if fd < 0 {
+        return false;
+    }
+    let mut buf = [0_u8; 64];
+    let len = libc::read(fd, buf.as_mut_ptr().cast::<libc::c_void>(), buf.len());
+    libc::close(fd);
+    let mode = match usize::try_from(len) {
+        Ok(len) if len > 0 => &buf[..len],
+        _ => return false,
+    };
+    !mode.windows(7).any(|w| w == b"[never]")
 }
 
+/// Size of a huge page as used by `MmapSource`.
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Provides the memory for a heap.
+///
+/// # Safety
+///
+/// Implementations may not be thread safe, callers have to synchronize access.
 pub trait MemorySource {
     /// Requests memory for the minimum specified size from the memory source
-    unsafe fn request(&self, size: usize) -> Option<BlockPtr>;
+    ///
+    /// # Safety
+    ///
+    /// Function may not be thread safe.
+    unsafe fn request(&mut self, size: usize) -> Option<BlockPtr>;
     /// Releases given `BlockPtr` back to the memory source.
     /// Returns `true` if block has been released, `false` otherwise.
+    ///
+    /// # Safety
+    ///
+    /// `block` must have been handed out by `request` and must not be used afterwards.
     unsafe fn release(&mut self, block: BlockPtr) -> bool;
+    /// Returns `true` if the given `BlockPtr` may have been handed out by this memory source.
+    fn contains(&self, _block: BlockPtr) -> bool {
+        true
+    }
+    /// Returns `true` if the memory region of every requested block is zeroed.
+    fn zeroed(&self) -> bool {
+        false
+    }
+    /// Returns the number of bytes handed out which are backed by huge pages.
+    fn huge_page_bytes(&self) -> usize {
+        0
+    }
+    /// Returns the unit to which the size of requested blocks is rounded up.
+    fn granularity(&self) -> usize {
+        *PAGE_SIZE
+    }
+    /// Leaves a gap of about `offset` bytes in front of the first block,
+    /// which randomizes the heap base if `offset` is random.
+    /// Has no effect once memory has been requested.
+    fn set_base_offset(&mut self, _offset: usize) {}
+    /// Releases all memory at once.
+    ///
+    /// # Safety
+    ///
+    /// Every `BlockPtr` handed out becomes invalid.
+    unsafe fn destroy(&mut self) {}
 }
 
 /// Defines data segment as memory source.
 /// Makes use of brk(2).
-pub struct DataSegment;
+pub struct DataSegment {
+    /// Program break after the last request or release, 0 if there is none.
+    end: usize,
+    /// Gap in front of the first block.
+    offset: usize,
+}
 
 impl DataSegment {
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { end: 0, offset: 0 }
+    }
+
     /// Wrapper for the kernel sbrk call.
     ///
     /// # Safety
@@ -41,43 +114,427 @@ impl DataSegment {
 }
 
 impl MemorySource for DataSegment {
+    /// Blocks are followed by a fencepost. If nobody else moved the program break
+    /// since the last call, the new block replaces the previous fencepost.
+    ///
     /// # Safety
     ///
     /// Function is not thread safe.
-    unsafe fn request(&self, size: usize) -> Option<BlockPtr> {
-        let size = util::pad_to_align(BLOCK_META_SIZE + size, *PAGE_SIZE)
+    unsafe fn request(&mut self, size: usize) -> Option<BlockPtr> {
+        let block_size = util::pad_to_align(BLOCK_META_SIZE + size, *PAGE_SIZE)
             .ok()?
             .size();
-        debug_assert!(size > BLOCK_META_SIZE);
-        let offset = isize::try_from(size).expect("cannot calculate sbrk offset");
-        Some(BlockPtr::new(Self::sbrk(offset)?, size - BLOCK_META_SIZE))
+        debug_assert!(block_size > BLOCK_META_SIZE);
+        let brk = Self::sbrk(0)?.as_ptr() as usize;
+        let (start, prev_in_use) = if brk == self.end {
+            let fencepost = brk - BLOCK_META_SIZE;
+            (fencepost, (*(fencepost as *const Block)).prev_in_use())
+        } else {
+            (block_start(brk + mem::take(&mut self.offset)), true)
+        };
+        let increment = (start + block_size + BLOCK_META_SIZE).checked_sub(brk)?;
+        let offset = isize::try_from(increment).ok()?;
+        trace!(Source, "requesting {} bytes from process", increment);
+        Self::sbrk(offset)?;
+        self.end = brk + increment;
+        // Only the page containing the previous break may hold stale data
+        let clean = util::pad_to_align(brk, *PAGE_SIZE).map_or(self.end, |l| l.size());
+        intrinsics::write_bytes(brk as *mut u8, 0, cmp::min(clean, self.end) - brk);
+
+        let mut block = BlockPtr::new(Unique::new(start as *mut u8)?, block_size - BLOCK_META_SIZE);
+        block.as_mut().set_prev_in_use(prev_in_use);
+        BlockPtr::fencepost(block.next_potential_block(), false);
+        Some(block)
     }
 
     /// # Safety
     ///
     /// Function is not thread safe.
     unsafe fn release(&mut self, block: BlockPtr) -> bool {
-        let brk = Self::sbrk(0).expect("sbrk(0) failed!").as_ptr();
-        if block.next_potential_block().as_ptr() != brk {
+        let brk = Self::sbrk(0).expect("sbrk(0) failed!").as_ptr() as usize;
+        let end = block.next_potential_block().as_ptr() as usize + BLOCK_META_SIZE;
+        if end != brk || brk != self.end {
             return false;
         }
 
         let offset = isize::try_from(block.block_size()).expect("cannot calculate sbrk offset");
-        dprintln!(
-            "[DataSegment]: freeing {} bytes from process (break={:?})",
+        trace!(
+            Source,
+            "freeing {} bytes from process (break={:#x})",
             offset,
-            Self::sbrk(0).expect("sbrk(0) failed!").as_ptr()
+            brk
         );
+        let prev_in_use = block.as_ref().prev_in_use();
         // TODO: remove expect
         Self::sbrk(-offset).expect("sbrk failed");
+        self.end = brk - block.block_size();
+        BlockPtr::fencepost(block.cast(), prev_in_use);
+        true
+    }
+
+    fn zeroed(&self) -> bool {
+        true
+    }
+
+    fn set_base_offset(&mut self, offset: usize) {
+        if self.end == 0 {
+            self.offset = offset;
+        }
+    }
+}
+
+/// Returns the lowest block address at or above `addr`, so the memory region is aligned.
+#[inline]
+fn block_start(addr: usize) -> usize {
+    util::min_align_unchecked(addr + BLOCK_META_SIZE) - BLOCK_META_SIZE
+}
+
+/// Huge page backing of an `MmapSource`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HugePages {
+    /// Only regular pages are used.
+    Disabled,
+    /// The region is aligned to `HUGE_PAGE_SIZE` and advised with `MADV_HUGEPAGE`,
+    /// the kernel backs it with transparent huge pages where possible.
+    Transparent,
+    /// The region is mapped with `MAP_HUGETLB` from the reserved huge page pool.
+    /// Falls back to `Transparent` if the pool is too small.
+    HugeTlb,
+}
+
+/// Defines a private memory region as memory source.
+/// The region is reserved with mmap(2) on first use and used like a private program break,
+/// so independent instances never share memory.
+pub struct MmapSource {
+    /// Start of the reserved region, 0 if not yet reserved.
+    base: usize,
+    capacity: usize,
+    /// Address of the fencepost behind the last block handed out.
+    top: usize,
+    huge: HugePages,
+    /// Set if the region is mapped from the huge page pool or the kernel accepted
+    /// `MADV_HUGEPAGE` for it while transparent huge pages are enabled.
+    huge_backed: bool,
+    /// Gap in front of the first block.
+    offset: usize,
+}
+
+impl MmapSource {
+    /// Creates a memory source which reserves `capacity` bytes of address space on first use.
+    /// Physical memory is only committed when touched.
+    #[must_use]
+    pub const fn new(capacity: usize) -> Self {
+        Self::with_huge_pages(capacity, HugePages::Disabled)
+    }
+
+    /// Creates a memory source backed by huge pages, which grows in units of `HUGE_PAGE_SIZE`.
+    #[must_use]
+    pub const fn with_huge_pages(capacity: usize, huge: HugePages) -> Self {
+        Self {
+            base: 0,
+            capacity,
+            top: 0,
+            huge,
+            huge_backed: false,
+            offset: 0,
+        }
+    }
+
+    /// Returns the number of reserved bytes.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the huge page backing, which is only final after the region has been reserved.
+    #[inline]
+    pub fn huge_pages(&self) -> HugePages {
+        self.huge
+    }
+
+    /// Returns the unit in which the region grows and shrinks.
+    #[inline]
+    fn page_size(&self) -> usize {
+        match self.huge {
+            HugePages::Disabled => *PAGE_SIZE,
+            HugePages::Transparent | HugePages::HugeTlb => HUGE_PAGE_SIZE,
+        }
+    }
+
+    unsafe fn reserve(&mut self) -> Option<()> {
+        let capacity = util::pad_to_align(self.capacity, self.page_size())
+            .ok()?
+            .size();
+        let (base, huge_backed) = match self.huge {
+            HugePages::Disabled => (Self::map(capacity, libc::MAP_NORESERVE)?, false),
+            HugePages::Transparent => Self::map_aligned(capacity)?,
+            HugePages::HugeTlb => match Self::map(capacity, libc::MAP_HUGETLB | libc::MAP_HUGE_2MB)
+            {
+                Some(base) => (base, true),
+                None => {
+                    warn!(Source, "unable to map {} bytes of huge pages", capacity);
+                    self.huge = HugePages::Transparent;
+                    Self::map_aligned(capacity)?
+                }
+            },
+        };
+        self.huge_backed = huge_backed;
+        trace!(Source, "reserved {} bytes at {:#x}", capacity, base);
+        self.base = base;
+        // Keep most of the region usable
+        self.offset = cmp::min(self.offset, capacity / 4);
+        self.top = block_start(self.base + self.offset);
+        self.capacity = capacity;
+        BlockPtr::fencepost(Unique::new(self.top as *mut u8)?, true);
+        Some(())
+    }
+
+    /// Maps `size` bytes of private anonymous memory with the given additional flags.
+    unsafe fn map(size: usize, flags: libc::c_int) -> Option<usize> {
+        let ptr = libc::mmap(
+            null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+            -1,
+            0,
+        );
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        Some(ptr as usize)
+    }
+
+    /// Maps `size` bytes aligned to `HUGE_PAGE_SIZE` and advises them for transparent huge pages.
+    /// Additionally returns `true` if they may be backed by transparent huge pages.
+    unsafe fn map_aligned(size: usize) -> Option<(usize, bool)> {
+        let len = size.checked_add(HUGE_PAGE_SIZE)?;
+        let ptr = Self::map(len, libc::MAP_NORESERVE)?;
+        let base = util::pad_to_align(ptr, HUGE_PAGE_SIZE).ok()?.size();
+        // Unmap the excess at both ends
+        if base > ptr {
+            libc::munmap(ptr as *mut libc::c_void, base - ptr);
+        }
+        libc::munmap((base + size) as *mut libc::c_void, ptr + len - base - size);
+        let advised = *THP_ENABLED
+            && libc::madvise(base as *mut libc::c_void, size, libc::MADV_HUGEPAGE) == 0;
+        if !advised {
+            warn!(Source, "transparent huge pages are not available");
+        }
+        Some((base, advised))
+    }
+}
+
+impl MemorySource for MmapSource {
+    unsafe fn request(&mut self, size: usize) -> Option<BlockPtr> {
+        if self.base == 0 {
+            self.reserve()?;
+        }
+        let block_size = util::pad_to_align(BLOCK_META_SIZE + size, self.page_size())
+            .ok()?
+            .size();
+        if block_size + BLOCK_META_SIZE > self.base + self.capacity - self.top {
+            return None;
+        }
+        let ptr = Unique::new(self.top as *mut u8)?;
+        let prev_in_use = ptr.cast::<Block>().as_ref().prev_in_use();
+        self.top += block_size;
+        let mut block = BlockPtr::new(ptr, block_size - BLOCK_META_SIZE);
+        block.as_mut().set_prev_in_use(prev_in_use);
+        BlockPtr::fencepost(block.next_potential_block(), false);
+        Some(block)
+    }
+
+    /// Huge page backed sources only take back blocks spanning at least one huge page.
+    unsafe fn release(&mut self, block: BlockPtr) -> bool {
+        let end = block.next_potential_block().as_ptr() as usize;
+        if end != self.top {
+            return false;
+        }
+        if self.huge != HugePages::Disabled && block.block_size() < HUGE_PAGE_SIZE {
+            return false;
+        }
+        self.top -= block.block_size();
+        BlockPtr::fencepost(block.cast(), block.as_ref().prev_in_use());
+        // Purge everything behind the new fencepost so it is zeroed when handed out again,
+        // whole pages are given back and the partial ones at both ends are cleared
+        let start = self.top + BLOCK_META_SIZE;
+        let end = end + BLOCK_META_SIZE;
+        let first = util::pad_to_align(start, *PAGE_SIZE).map_or(end, |l| cmp::min(l.size(), end));
+        let last = cmp::max(end & !(*PAGE_SIZE - 1), first);
+        intrinsics::write_bytes(start as *mut u8, 0, first - start);
+        if first < last {
+            trace!(Source, "releasing {} bytes at {:#x}", last - first, first);
+            libc::madvise(
+                first as *mut libc::c_void,
+                last - first,
+                libc::MADV_DONTNEED,
+            );
+        }
+        intrinsics::write_bytes(last as *mut u8, 0, end - last);
+        true
+    }
+
+    fn contains(&self, block: BlockPtr) -> bool {
+        let addr = block.as_ptr() as usize;
+        addr >= self.base && addr < self.top
+    }
+
+    fn zeroed(&self) -> bool {
         true
     }
+
+    fn granularity(&self) -> usize {
+        self.page_size()
+    }
+
+    /// Transparent huge pages are counted if the kernel accepted them for the region,
+    /// it may still back parts of it with regular pages.
+    fn huge_page_bytes(&self) -> usize {
+        if !self.huge_backed {
+            return 0;
+        }
+        self.top
+            .saturating_sub(block_start(self.base + self.offset))
+    }
+
+    unsafe fn destroy(&mut self) {
+        if self.base == 0 {
+            return;
+        }
+        trace!(
+            Source,
+            "unmapping {} bytes at {:#x}",
+            self.capacity,
+            self.base
+        );
+        libc::munmap(self.base as *mut libc::c_void, self.capacity);
+        self.base = 0;
+        self.top = 0;
+    }
+
+    fn set_base_offset(&mut self, offset: usize) {
+        if self.base == 0 {
+            self.offset = offset;
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_mmap_source() {
+        unsafe {
+            let mut source = MmapSource::new(1 << 20);
+            let block = source.request(100).expect("unable to request block");
+            assert_eq!(block.block_size(), *PAGE_SIZE);
+            assert!(source.contains(block));
+            block.mem_region().as_ptr().write_bytes(1, block.size());
+
+            let block2 = source.request(*PAGE_SIZE).expect("unable to request block");
+            assert_eq!(
+                block2.as_ptr() as usize,
+                block.next_potential_block().as_ptr() as usize
+            );
+            // Only the last block can be released
+            assert!(!source.release(block));
+            assert!(source.release(block2));
+            assert!(!source.contains(block2));
+            assert!(source.release(block));
+            assert_eq!(source.top, block.as_ptr() as usize);
+            assert_eq!(source.top, source.base + BLOCK_META_SIZE);
+            source.destroy();
+        }
+    }
+
+    #[test]
+    fn test_mmap_source_zeroed() {
+        unsafe {
+            let mut source = MmapSource::new(1 << 20);
+            assert!(source.zeroed());
+            let block = source
+                .request(3 * *PAGE_SIZE)
+                .expect("unable to request block");
+            block.mem_region().as_ptr().write_bytes(0xFF, block.size());
+            assert!(source.release(block));
+
+            let block = source.request(*PAGE_SIZE).expect("unable to request block");
+            let region = core::slice::from_raw_parts(block.mem_region().as_ptr(), block.size());
+            assert!(region.iter().all(|b| *b == 0));
+            source.destroy();
+        }
+    }
+
+    #[test]
+    fn test_mmap_source_huge_pages() {
+        for huge in [HugePages::Transparent, HugePages::HugeTlb].iter() {
+            unsafe {
+                let mut source = MmapSource::with_huge_pages(4 * HUGE_PAGE_SIZE, *huge);
+                let block = source.request(100).expect("unable to request block");
+                // The pool may be empty, but the region is always huge page aligned
+                assert_ne!(source.huge_pages(), HugePages::Disabled);
+                assert_eq!(source.base % HUGE_PAGE_SIZE, 0);
+                assert_eq!(block.block_size(), HUGE_PAGE_SIZE);
+                // Only counted if the kernel may back the region with huge pages
+                let huge_backed = source.huge_pages() == HugePages::HugeTlb || *THP_ENABLED;
+                assert_eq!(source.huge_backed, huge_backed);
+                let expected = if huge_backed { HUGE_PAGE_SIZE } else { 0 };
+                assert_eq!(source.huge_page_bytes(), expected);
+                block.mem_region().as_ptr().write_bytes(1, block.size());
+
+                let block2 = source
+                    .request(HUGE_PAGE_SIZE)
+                    .expect("unable to request block");
+                assert_eq!(block2.block_size(), 2 * HUGE_PAGE_SIZE);
+                assert!(source.request(100).is_none());
+                assert!(source.release(block2));
+                assert!(source.release(block));
+                assert_eq!(source.huge_page_bytes(), 0);
+                source.destroy();
+            }
+        }
+    }
+
+    #[test]
+    fn test_mmap_source_exhausted() {
+        unsafe {
+            // Each block needs a fencepost behind it
+            let mut source = MmapSource::new(3 * *PAGE_SIZE);
+            assert!(source.request(2 * *PAGE_SIZE).is_none());
+            assert!(source.request(*PAGE_SIZE).is_some());
+            assert!(source.request(*PAGE_SIZE).is_none());
+            source.destroy();
+        }
+    }
+
+    #[test]
+    fn test_mmap_source_base_offset() {
+        unsafe {
+            let mut source = MmapSource::new(1 << 20);
+            source.set_base_offset(4000);
+            let block = source.request(100).expect("unable to request block");
+            assert_eq!(block.as_ptr() as usize, block_start(source.base + 4000));
+            assert_eq!(block.mem_region().as_ptr() as usize % crate::MIN_ALIGN, 0);
+            // Ignored once the region has been reserved
+            source.set_base_offset(0);
+            assert!(source.release(block));
+            assert_eq!(source.top, block.as_ptr() as usize);
+            source.destroy();
+
+            // The gap is limited to a quarter of the region
+            let mut source = MmapSource::new(1 << 20);
+            source.set_base_offset(1 << 30);
+            let block = source.request(100).expect("unable to request block");
+            assert_eq!(
+                block.as_ptr() as usize,
+                block_start(source.base + (1 << 18))
+            );
+            source.destroy();
+        }
+    }
+
     #[test]
     fn test_sbrk_ok() {
         unsafe { assert!(DataSegment::sbrk(0).is_some()) };
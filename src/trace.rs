@@ -0,0 +1,341 @@
+//! Binary allocation trace format.
+//!
+//! A trace file starts with a `TraceHeader` followed by a ring of fixed size records.
+//! The record with sequence number `seq` is stored in slot `(seq - 1) % capacity`,
+//! so the writer never has to allocate or seek back and the file never grows
+//! beyond `TRACE_HEADER_SIZE + capacity * TRACE_RECORD_SIZE` bytes.
+//! All integers are stored in little endian byte order.
+use core::convert::TryInto;
+
+/// Magic bytes at the start of every trace file.
+pub const TRACE_MAGIC: [u8; 8] = *b"CLMTRACE";
+/// Version of the on-disk format.
+pub const TRACE_VERSION: u32 = 1;
+/// Size of the encoded `TraceHeader` in bytes.
+pub const TRACE_HEADER_SIZE: usize = 32;
+/// Size of an encoded `TraceRecord` in bytes.
+pub const TRACE_RECORD_SIZE: usize = 48;
+
+/// Describes the allocator entry point which produced a record.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceOp {
+    Malloc = 1,
+    Calloc = 2,
+    Realloc = 3,
+    Free = 4,
+}
+
+impl TraceOp {
+    #[inline]
+    fn from_u8(op: u8) -> Option<Self> {
+        match op {
+            1 => Some(TraceOp::Malloc),
+            2 => Some(TraceOp::Calloc),
+            3 => Some(TraceOp::Realloc),
+            4 => Some(TraceOp::Free),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TraceError {
+    /// Input is too short to contain a header.
+    Truncated,
+    /// Input doesn't start with `TRACE_MAGIC`.
+    InvalidMagic,
+    /// Input has been written with an incompatible format.
+    UnsupportedVersion(u32),
+    /// Input has been written with records of a different size.
+    InvalidRecordSize(u32),
+}
+
+/// Leading metadata of a trace file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceHeader {
+    /// Number of record slots in the ring.
+    pub capacity: u64,
+}
+
+impl TraceHeader {
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; TRACE_HEADER_SIZE] {
+        let mut buf = [0; TRACE_HEADER_SIZE];
+        buf[0..8].copy_from_slice(&TRACE_MAGIC);
+        buf[8..12].copy_from_slice(&TRACE_VERSION.to_le_bytes());
+        buf[12..16].copy_from_slice(&(TRACE_RECORD_SIZE as u32).to_le_bytes());
+        buf[16..24].copy_from_slice(&self.capacity.to_le_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, TraceError> {
+        if buf.len() < TRACE_HEADER_SIZE {
+            return Err(TraceError::Truncated);
+        }
+        if buf[0..8] != TRACE_MAGIC {
+            return Err(TraceError::InvalidMagic);
+        }
+        let version = read_u32(&buf[8..12]);
+        let record_size = read_u32(&buf[12..16]);
+        if version != TRACE_VERSION {
+            return Err(TraceError::UnsupportedVersion(version));
+        }
+        if record_size as usize != TRACE_RECORD_SIZE {
+            return Err(TraceError::InvalidRecordSize(record_size));
+        }
+        Ok(Self {
+            capacity: read_u64(&buf[16..24]),
+        })
+    }
+}
+
+/// A single traced allocator call.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Global sequence number, starting at 1.
+    pub seq: u64,
+    /// Monotonic timestamp in nanoseconds.
+    pub timestamp: u64,
+    /// Kernel thread id of the caller.
+    pub thread_id: u32,
+    pub op: TraceOp,
+    /// Requested size in bytes, 0 for `Free`.
+    pub size: u64,
+    /// Returned pointer or the freed pointer for `Free`.
+    pub ptr: u64,
+    /// Pointer passed to `Realloc`, 0 otherwise.
+    pub old_ptr: u64,
+}
+
+impl TraceRecord {
+    /// Returns the byte offset of this record within a trace file of the given capacity.
+    #[inline]
+    #[must_use]
+    pub fn file_offset(&self, capacity: u64) -> u64 {
+        debug_assert!(self.seq > 0 && capacity > 0);
+        TRACE_HEADER_SIZE as u64 + ((self.seq - 1) % capacity) * TRACE_RECORD_SIZE as u64
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> [u8; TRACE_RECORD_SIZE] {
+        let mut buf = [0; TRACE_RECORD_SIZE];
+        buf[0..8].copy_from_slice(&self.seq.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.thread_id.to_le_bytes());
+        buf[20] = self.op as u8;
+        buf[24..32].copy_from_slice(&self.size.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.ptr.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.old_ptr.to_le_bytes());
+        buf
+    }
+
+    /// Decodes a record, returns `None` for empty slots or invalid data.
+    #[must_use]
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        if buf.len() < TRACE_RECORD_SIZE {
+            return None;
+        }
+        let seq = read_u64(&buf[0..8]);
+        if seq == 0 {
+            return None;
+        }
+        Some(Self {
+            seq,
+            timestamp: read_u64(&buf[8..16]),
+            thread_id: read_u32(&buf[16..20]),
+            op: TraceOp::from_u8(buf[20])?,
+            size: read_u64(&buf[24..32]),
+            ptr: read_u64(&buf[32..40]),
+            old_ptr: read_u64(&buf[40..48]),
+        })
+    }
+}
+
+/// Decodes a trace file which has been loaded into memory.
+pub struct TraceReader<'a> {
+    header: TraceHeader,
+    slots: &'a [u8],
+}
+
+impl<'a> TraceReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, TraceError> {
+        let header = TraceHeader::from_bytes(data)?;
+        let body = &data[TRACE_HEADER_SIZE..];
+        // The file may be shorter than the full ring if it never wrapped around.
+        let len = body.len() - body.len() % TRACE_RECORD_SIZE;
+        Ok(Self {
+            header,
+            slots: &body[..len],
+        })
+    }
+
+    #[inline]
+    pub fn header(&self) -> TraceHeader {
+        self.header
+    }
+
+    /// Returns an iterator over all intact records ordered by their sequence number.
+    /// If the ring wrapped around only the most recent `capacity` records are available.
+    pub fn records(&self) -> Records<'a> {
+        let capacity = self.header.capacity;
+        let count = self.slots.len() / TRACE_RECORD_SIZE;
+        let newest = match capacity {
+            0 => 0,
+            _ => (0..count)
+                .filter_map(|i| self.slot(i))
+                .map(|r| r.seq)
+                .max()
+                .unwrap_or(0),
+        };
+        let first = if newest <= capacity {
+            1
+        } else {
+            newest - capacity + 1
+        };
+        Records {
+            slots: self.slots,
+            capacity,
+            next: first,
+            last: newest,
+        }
+    }
+
+    #[inline]
+    fn slot(&self, index: usize) -> Option<TraceRecord> {
+        let start = index * TRACE_RECORD_SIZE;
+        TraceRecord::from_bytes(&self.slots[start..start + TRACE_RECORD_SIZE])
+    }
+}
+
+pub struct Records<'a> {
+    slots: &'a [u8],
+    capacity: u64,
+    next: u64,
+    last: u64,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = TraceRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next <= self.last {
+            let seq = self.next;
+            self.next += 1;
+            let start = (((seq - 1) % self.capacity) as usize) * TRACE_RECORD_SIZE;
+            let record = match self.slots.get(start..start + TRACE_RECORD_SIZE) {
+                Some(buf) => TraceRecord::from_bytes(buf),
+                None => None,
+            };
+            // Skip slots which have been torn or overwritten by a concurrent writer
+            if let Some(r) = record {
+                if r.seq == seq {
+                    return Some(r);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[inline]
+fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(buf.try_into().unwrap())
+}
+
+#[inline]
+fn read_u64(buf: &[u8]) -> u64 {
+    u64::from_le_bytes(buf.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn record(seq: u64) -> TraceRecord {
+        TraceRecord {
+            seq,
+            timestamp: 1000 + seq,
+            thread_id: 42,
+            op: TraceOp::Realloc,
+            size: 128 * seq,
+            ptr: 0x1000 * seq,
+            old_ptr: 0x10,
+        }
+    }
+
+    fn trace(capacity: u64, records: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&TraceHeader { capacity }.to_bytes());
+        for seq in 1..=records {
+            let r = record(seq);
+            let offset = r.file_offset(capacity) as usize;
+            if data.len() < offset + TRACE_RECORD_SIZE {
+                data.resize(offset + TRACE_RECORD_SIZE, 0);
+            }
+            data[offset..offset + TRACE_RECORD_SIZE].copy_from_slice(&r.to_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let r = record(7);
+        assert_eq!(TraceRecord::from_bytes(&r.to_bytes()), Some(r));
+    }
+
+    #[test]
+    fn test_record_empty_slot() {
+        assert_eq!(TraceRecord::from_bytes(&[0; TRACE_RECORD_SIZE]), None);
+    }
+
+    #[test]
+    fn test_reader_invalid_magic() {
+        let mut data = trace(4, 1);
+        data[0] = b'X';
+        assert_eq!(
+            TraceReader::new(&data).err(),
+            Some(TraceError::InvalidMagic)
+        );
+        assert_eq!(
+            TraceReader::new(&data[..8]).err(),
+            Some(TraceError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_reader_incompatible_format() {
+        let mut data = trace(4, 1);
+        data[12..16].copy_from_slice(&32_u32.to_le_bytes());
+        assert_eq!(
+            TraceReader::new(&data).err(),
+            Some(TraceError::InvalidRecordSize(32))
+        );
+        data[8..12].copy_from_slice(&2_u32.to_le_bytes());
+        assert_eq!(
+            TraceReader::new(&data).err(),
+            Some(TraceError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn test_reader_no_wrap() {
+        let data = trace(16, 5);
+        let reader = TraceReader::new(&data).expect("unable to read trace");
+        assert_eq!(reader.header().capacity, 16);
+        let seqs: Vec<u64> = reader.records().map(|r| r.seq).collect();
+        assert_eq!(seqs, [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_reader_wrap() {
+        let data = trace(4, 10);
+        assert_eq!(data.len(), TRACE_HEADER_SIZE + 4 * TRACE_RECORD_SIZE);
+        let reader = TraceReader::new(&data).expect("unable to read trace");
+        let records: Vec<TraceRecord> = reader.records().collect();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0], record(7));
+        assert_eq!(records[3], record(10));
+    }
+}
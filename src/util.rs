@@ -1,5 +1,8 @@
 use crate::MIN_ALIGN;
 use core::alloc::{Layout, LayoutErr};
+use core::fmt;
+
+use spin::Mutex;
 
 /// Aligns passed value to be at lest the size of `MIN_ALIGN` and returns it.
 /// NOTE: not checked for overflows!
@@ -20,6 +23,134 @@ pub fn pad_to_align(size: usize, align: usize) -> Result<Layout, LayoutErr> {
     Ok(Layout::from_size_align(size, align)?.pad_to_align())
 }
 
+/// Acquires `mutex` and keeps it locked until it is released with `force_unlock`.
+/// Yields the CPU while waiting, since the holder may have been preempted.
+pub fn hold<T>(mutex: &Mutex<T>) {
+    loop {
+        if let Some(guard) = mutex.try_lock() {
+            core::mem::forget(guard);
+            return;
+        }
+        unsafe { libc::sched_yield() };
+    }
+}
+
+/// Xorshift64* generator used to randomize the heap layout.
+/// Fast and small, but not suitable for cryptographic purposes.
+#[derive(Copy, Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Creates a generator from `seed`, the state must never be zero.
+    #[must_use]
+    pub const fn new(seed: u64) -> Self {
+        Self { state: seed | 1 }
+    }
+
+    /// Creates a generator seeded by `getrandom(2)`.
+    /// Falls back to the clock and the stack address if no entropy is available.
+    #[must_use]
+    pub fn from_entropy() -> Self {
+        let mut seed = 0_u64;
+        let len = unsafe {
+            libc::getrandom(
+                (&mut seed as *mut u64).cast::<libc::c_void>(),
+                core::mem::size_of::<u64>(),
+                libc::GRND_NONBLOCK,
+            )
+        };
+        if len != core::mem::size_of::<u64>() as isize {
+            let mut ts = libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            };
+            unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+            seed = (ts.tv_sec as u64).rotate_left(32)
+                ^ ts.tv_nsec as u64
+                ^ (&seed as *const u64 as u64).rotate_left(16);
+        }
+        Self::new(seed)
+    }
+
+    /// Returns the next pseudo random number.
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns a pseudo random number in `0..n`, `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        debug_assert!(n > 0);
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+/// Buffered `fmt::Write` implementation for raw file descriptors.
+/// Doesn't allocate and flushes the buffer on drop.
+pub struct FdWriter {
+    fd: libc::c_int,
+    buf: [u8; 512],
+    len: usize,
+}
+
+impl FdWriter {
+    #[must_use]
+    pub const fn new(fd: libc::c_int) -> Self {
+        Self {
+            fd,
+            buf: [0; 512],
+            len: 0,
+        }
+    }
+
+    /// Writes all buffered data to the file descriptor.
+    pub fn flush(&mut self) -> fmt::Result {
+        let mut start = 0;
+        while start < self.len {
+            let remaining = &self.buf[start..self.len];
+            let written = unsafe {
+                libc::write(
+                    self.fd,
+                    remaining.as_ptr().cast::<libc::c_void>(),
+                    remaining.len(),
+                )
+            };
+            if written <= 0 {
+                self.len = 0;
+                return Err(fmt::Error);
+            }
+            start += written as usize;
+        }
+        self.len = 0;
+        Ok(())
+    }
+}
+
+impl fmt::Write for FdWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for chunk in s.as_bytes().chunks(self.buf.len()) {
+            if self.len + chunk.len() > self.buf.len() {
+                self.flush()?;
+            }
+            self.buf[self.len..self.len + chunk.len()].copy_from_slice(chunk);
+            self.len += chunk.len();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FdWriter {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -57,4 +188,40 @@ mod tests {
     fn test_pad_min_align_err() {
         assert!(pad_min_align(usize::max_value() - 14).is_err());
     }
+
+    #[test]
+    fn test_fd_writer() {
+        use core::fmt::Write;
+        let mut fds = [0; 2];
+        unsafe { assert_eq!(libc::pipe(fds.as_mut_ptr()), 0) };
+
+        let mut writer = FdWriter::new(fds[1]);
+        for i in 0..100 {
+            write!(writer, "{:08}", i).expect("unable to write");
+        }
+        drop(writer);
+
+        let mut buf = [0_u8; 1024];
+        let read =
+            unsafe { libc::read(fds[0], buf.as_mut_ptr().cast::<libc::c_void>(), buf.len()) };
+        assert_eq!(read, 800);
+        assert_eq!(&buf[792..800], b"00000099");
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+
+    #[test]
+    fn test_rng() {
+        let mut rng = Rng::new(0);
+        let first = rng.next_u64();
+        assert_ne!(first, rng.next_u64());
+        assert_eq!(Rng::new(0).next_u64(), first);
+        for n in 1..100 {
+            assert!(rng.below(n) < n);
+        }
+        let mut rng = Rng::from_entropy();
+        assert_ne!(rng.next_u64(), rng.next_u64());
+    }
 }